@@ -0,0 +1,125 @@
+// Compares the borrowing RawIrcMessage parser against the old parser, which allocated a String
+// for every block. Run with `cargo bench`.
+//
+// This is a plain harness (no external bench framework): each case runs a warmup pass and then
+// a fixed number of timed iterations over the whole corpus.
+
+use std::hint::black_box;
+use std::time::Instant;
+
+use tuisen::irc::{RawIrcMessage, TwitchIrcMessage};
+
+const WARMUP_ITERATIONS: u32 = 2_000;
+const ITERATIONS: u32 = 20_000;
+
+// Lines as Twitch sends them with the tags and commands capabilities on
+const CORPUS: [&str; 8] = [
+    "@badge-info=subscriber/8;badges=subscriber/6,premium/1;client-nonce=2cc8bb73f5d946b22ec2905c8ccdee7a;color=#1E90FF;display-name=Flipdoo;emotes=;first-msg=0;flags=;id=0c6d6b3e-8e6b-4a8f-9b4d-9d1a2bda0f7f;mod=0;returning-chatter=0;room-id=22484632;subscriber=1;tmi-sent-ts=1710000000000;turbo=0;user-id=123456789;user-type= :flipdoo!flipdoo@flipdoo.tmi.twitch.tv PRIVMSG #forsen :forsenE forsenE forsenE",
+    "@badge-info=;badges=moderator/1;color=#FF0000;display-name=SomeMod;emotes=25:0-4,12-16/1902:6-10;id=b34ccfc7-4977-403a-8a94-33c6bac34fb8;mod=1;room-id=1337;subscriber=0;tmi-sent-ts=1507246572675;turbo=0;user-id=1337;user-type=mod :somemod!somemod@somemod.tmi.twitch.tv PRIVMSG #forsen :Kappa Keepo Kappa",
+    "@badge-info=subscriber/24;badges=subscriber/24;color=;display-name=Chatter;emotes=;id=db25007f-7a18-43eb-9379-80131e44d633;login=chatter;mod=0;msg-id=resub;msg-param-cumulative-months=24;msg-param-should-share-streak=0;msg-param-sub-plan=1000;room-id=22484632;subscriber=1;system-msg=Chatter\\ssubscribed\\sat\\sTier\\s1.\\sThey've\\ssubscribed\\sfor\\s24\\smonths!;tmi-sent-ts=1507246572675;user-id=87654321;user-type= :tmi.twitch.tv USERNOTICE #forsen :Great stream -- keep it up!",
+    "@emote-only=0;followers-only=-1;r9k=0;room-id=22484632;slow=0;subs-only=0 :tmi.twitch.tv ROOMSTATE #forsen",
+    "@ban-duration=600;room-id=22484632;target-user-id=87654321;tmi-sent-ts=1642719320727 :tmi.twitch.tv CLEARCHAT #forsen :spammer",
+    ":tmi.twitch.tv 001 justinfan1337 :Welcome, GLHF!",
+    ":flipdoo!flipdoo@flipdoo.tmi.twitch.tv JOIN #forsen",
+    "PING :tmi.twitch.tv",
+];
+
+// The parser as it was before RawIrcMessage borrowed from its input
+mod legacy {
+    #[allow(dead_code)]
+    pub struct RawIrcMessage {
+        pub raw_tags: Option<String>,
+        pub raw_origin: Option<String>,
+        pub raw_command: String,
+        pub params: Vec<String>,
+    }
+
+    pub fn parse(value: String) -> Option<RawIrcMessage> {
+        let mut blocks = value.split(' ').map(|s| s.to_owned()).peekable();
+
+        let raw_tags = blocks
+            .next_if(|b| b.starts_with('@'))
+            .and_then(|b| b.strip_prefix('@').map(|b| b.to_owned()));
+        let raw_origin = blocks
+            .next_if(|b| b.starts_with(':'))
+            .and_then(|b| b.strip_prefix(':').map(|b| b.to_owned()));
+        let raw_command = blocks.next_if(|b| !b.is_empty())?;
+
+        let mut params: Vec<String> = Vec::new();
+        while let Some(param) = blocks.next_if(|b| !b.is_empty()) {
+            if let Some(head) = param.strip_prefix(':') {
+                let mut trailing = head.to_owned();
+                if blocks.peek().is_some() {
+                    trailing.push(' ');
+                    trailing.push_str(&blocks.collect::<Vec<String>>().as_slice().join(" "));
+                }
+                params.push(trailing);
+                break;
+            } else {
+                params.push(param.to_owned());
+            }
+        }
+
+        Some(RawIrcMessage {
+            raw_tags,
+            raw_origin,
+            raw_command,
+            params,
+        })
+    }
+}
+
+fn bench(name: &str, mut run: impl FnMut()) {
+    for _ in 0..WARMUP_ITERATIONS {
+        run();
+    }
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        run();
+    }
+    let elapsed = start.elapsed();
+    let per_line = elapsed / (ITERATIONS * CORPUS.len() as u32);
+    println!(
+        "{:<40} {:>10.2?} total, {:>8} ns/line",
+        name,
+        elapsed,
+        per_line.as_nanos()
+    );
+}
+
+fn main() {
+    println!("{} iterations over {} lines\n", ITERATIONS, CORPUS.len());
+
+    // The old client cloned every line before parsing it, so that is part of its cost
+    bench("legacy raw parse (owned blocks)", || {
+        for line in CORPUS {
+            black_box(legacy::parse(black_box(line.to_owned())));
+        }
+    });
+
+    bench("raw parse (borrowed)", || {
+        for line in CORPUS {
+            black_box(RawIrcMessage::try_from(black_box(line)).ok());
+        }
+    });
+
+    bench("raw parse (borrowed) + into_owned", || {
+        for line in CORPUS {
+            black_box(
+                RawIrcMessage::try_from(black_box(line))
+                    .ok()
+                    .map(|raw| raw.into_owned()),
+            );
+        }
+    });
+
+    bench("full parse to TwitchIrcMessage", || {
+        for line in CORPUS {
+            black_box(
+                RawIrcMessage::try_from(black_box(line))
+                    .ok()
+                    .and_then(|raw| TwitchIrcMessage::try_from(raw).ok()),
+            );
+        }
+    });
+}
@@ -0,0 +1,9 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use tuisen::irc::RawIrcMessage;
+
+fuzz_target!(|line: &str| {
+    let _ = RawIrcMessage::try_from(line);
+});
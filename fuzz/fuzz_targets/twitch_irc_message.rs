@@ -0,0 +1,22 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use tuisen::irc::{RawIrcMessage, TwitchIrcMessage};
+
+fuzz_target!(|line: &str| {
+    let Ok(raw) = RawIrcMessage::try_from(line) else {
+        return;
+    };
+    let Ok(message) = TwitchIrcMessage::try_from(raw) else {
+        return;
+    };
+
+    // Whatever we can parse and serialize must parse back to the same message
+    if let Ok(serialized) = message.serialize() {
+        assert!(!serialized.contains(['\r', '\n']));
+        let raw = RawIrcMessage::try_from(serialized.as_str()).expect("serialized line is valid");
+        let reparsed = TwitchIrcMessage::try_from(raw).expect("serialized message is valid");
+        assert_eq!(reparsed, message);
+    }
+});
@@ -1,14 +1,174 @@
+//! What the app and the client say to each other. The app sends [`TwitchAction`]s to the client,
+//! and the client answers with [`TerminalAction`]s.
+
+use std::time::Duration;
+
+#[derive(Debug)]
+#[non_exhaustive]
 pub enum TwitchAction {
-    Connect,
-    SendPrivmsg { message: String },
+    Join {
+        channel: String,
+    },
+    Part {
+        channel: String,
+    },
+    SendPrivmsg {
+        channel: String,
+        message: String,
+    },
+    /// A line for the server, sent as is
+    SendRaw(String),
 }
 
+#[derive(Debug)]
+#[non_exhaustive]
 pub enum TerminalAction {
     PrintPrivmsg {
         channel: String,
         username: String,
         message: String,
+        // Emote codes used in the message, for completion
+        emotes: Vec<String>,
+    },
+    /// The server confirmed that we joined a channel, under this username
+    Joined {
+        channel: String,
+        username: String,
+    },
+    /// The server confirmed that we left a channel
+    Parted {
+        channel: String,
+        username: String,
+    },
+    /// Users who joined a channel, or were already there when we joined (with the membership
+    /// capability). Only used for completion.
+    AddChatters {
+        channel: String,
+        usernames: Vec<String>,
+    },
+    RemoveChatter {
+        channel: String,
+        username: String,
+    },
+    /// A message we sent ourselves. Its state gets updated through SetOutgoingState.
+    PrintOutgoing {
+        id: u64,
+        channel: String,
+        username: String,
+        message: String,
+        state: OutgoingState,
+    },
+    SetOutgoingState {
+        id: u64,
+        state: OutgoingState,
     },
-    PrintPing(String),
+    /// A line from the client itself, for the system tab
     PrintDebug(String),
+    /// A notice from the server, about a channel or, without one, about the connection
+    PrintNotice {
+        channel: Option<String>,
+        message: String,
+    },
+    /// A sub, raid, announcement and the like. The kind comes from Twitch (e.g. "resub"), the
+    /// system message describes the event, and the message is what the user attached to it.
+    PrintUserNotice {
+        channel: String,
+        kind: Option<String>,
+        username: Option<String>,
+        system_message: Option<String>,
+        message: Option<String>,
+    },
+    /// A moderator cleared the whole chat, or with a username, banned that user, or timed them
+    /// out if there is a ban duration (in seconds)
+    ClearChat {
+        channel: String,
+        username: Option<String>,
+        ban_duration: Option<u32>,
+    },
+    /// A moderator deleted a single message
+    ClearMessage {
+        channel: String,
+        username: Option<String>,
+        message: String,
+    },
+    PrintWhisper {
+        username: String,
+        message: String,
+    },
+    /// The channel started hosting another one, or stopped if the target is None
+    SetHostTarget {
+        channel: String,
+        target: Option<String>,
+    },
+    SetConnectionStatus(ConnectionStatus),
+    /// How long the server took to answer our last ping
+    SetLatency(Duration),
+    /// The room modes of a channel that changed
+    UpdateRoomModes {
+        channel: String,
+        modes: RoomModes,
+    },
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[non_exhaustive]
+pub enum OutgoingState {
+    /// Waiting for the rate limiter
+    Queued,
+    Sent,
+    /// Never sent, either because too many messages were queued or because the message was bad
+    Dropped,
+}
+
+/// Restrictions on who can chat in a channel, and how. Twitch sends all of them when we join, but
+/// only the ones that changed after that, so a None leaves a mode as it was.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct RoomModes {
+    pub emote_only: Option<bool>,
+    /// -1 when off, or else the required follow age in minutes
+    pub followers_only: Option<i32>,
+    pub r9k: Option<bool>,
+    /// The delay between messages in seconds, 0 when off
+    pub slow: Option<u32>,
+    pub subs_only: Option<bool>,
+}
+
+impl RoomModes {
+    pub fn update(&mut self, changes: &RoomModes) {
+        self.emote_only = changes.emote_only.or(self.emote_only);
+        self.followers_only = changes.followers_only.or(self.followers_only);
+        self.r9k = changes.r9k.or(self.r9k);
+        self.slow = changes.slow.or(self.slow);
+        self.subs_only = changes.subs_only.or(self.subs_only);
+    }
+}
+
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub enum ConnectionStatus {
+    Connecting,
+    Authenticating {
+        username: String,
+    },
+    Connected {
+        channels: Vec<String>,
+    },
+    Disconnected(DisconnectReason),
+    /// A zero delay means we are reconnecting right away, because the server asked us to
+    Reconnecting {
+        attempt: u32,
+        delay: Duration,
+    },
+}
+
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub enum DisconnectReason {
+    ConnectionClosed,
+    ServerRequested,
+    AuthFailed,
+    AuthTimedOut,
+    /// The server stopped answering
+    TimedOut,
+    Error(String),
 }
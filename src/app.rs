@@ -1,21 +1,39 @@
-use std::sync::mpsc::{Receiver, Sender};
+use std::collections::{HashSet, VecDeque};
+use std::io;
+use std::sync::mpsc::Sender;
+use std::time::{Duration, Instant};
 
 use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
 use textwrap::wrap;
 
-use crate::actions::{TerminalAction, TwitchAction};
+use tuisen::actions::{
+    ConnectionStatus, DisconnectReason, OutgoingState, RoomModes, TerminalAction, TwitchAction,
+};
+
+use crate::commands::{command_info, parse_command, Command, CommandError, COMMANDS};
+use crate::completion::{candidates, Completion, RecentNames};
+use crate::editor::LineEditor;
+use crate::history::{HistorySearch, InputHistory};
+use crate::layout::{PaneLayout, SplitDirection};
+use crate::scrollback::{ScrollbackConfig, SpillFile, PAGE_SIZE};
+use crate::search::ScrollbackSearch;
+use crate::tui_config::{save_layout, PaneLayoutConfig};
 
 pub const INSERT_LEN_WARN: usize = 500;
 
 pub enum ScrollState {
     Bottom,
-    Offset(usize),
+    // The top of the view is at this line of this item (see ChatBuffer about item numbers), so
+    // that it stays put as new items come in
+    Anchored { item: usize, line: usize },
     Top,
 }
 
 pub enum InputMode {
     Normal,
     Insert,
+    // Typing a scrollback search query
+    Search,
 }
 
 impl InputMode {
@@ -23,196 +41,1399 @@ impl InputMode {
         match self {
             InputMode::Normal => "[ normal ]".to_owned(),
             InputMode::Insert => "[ insert ]".to_owned(),
+            InputMode::Search => "[ search ]".to_owned(),
         }
     }
 }
 
 pub enum ChatItem {
     Privmsg {
-        channel: String,
         username: String,
         message: String,
     },
-    Debug {
-        content: String,
+    // One of our own messages, which might still be waiting on the rate limiter
+    Outgoing {
+        id: u64,
+        username: String,
+        message: String,
+        state: OutgoingState,
     },
-    Ping {
+    Debug {
         content: String,
     },
 }
 
 impl ChatItem {
-    fn wrapped_lines(&self, width: usize) -> Vec<String> {
-        let unwrapped = match self {
+    pub fn username(&self) -> Option<&str> {
+        match self {
+            ChatItem::Privmsg { username, .. } | ChatItem::Outgoing { username, .. } => {
+                Some(username)
+            }
+            ChatItem::Debug { .. } => None,
+        }
+    }
+
+    // What searches look through
+    pub fn text(&self) -> &str {
+        match self {
+            ChatItem::Privmsg { message, .. } | ChatItem::Outgoing { message, .. } => message,
+            ChatItem::Debug { content } => content,
+        }
+    }
+
+    // The item as one line, before wrapping
+    pub fn unwrapped(&self) -> String {
+        match self {
             ChatItem::Debug { content } => content.clone(),
-            ChatItem::Ping { content } => format!("[ping {}]", &content),
-            ChatItem::Privmsg {
-                channel,
+            ChatItem::Privmsg { username, message } => format_privmsg(username, message),
+            ChatItem::Outgoing {
                 username,
                 message,
-            } => format!("[#{}] {}: {}", channel, username, message),
-        };
-        wrap(&unwrapped, width)
+                state,
+                ..
+            } => match state {
+                OutgoingState::Queued => format!("{} [queued]", format_privmsg(username, message)),
+                OutgoingState::Dropped => {
+                    format!("{} [not sent]", format_privmsg(username, message))
+                }
+                // Sent, or a state newer than this app
+                _ => format_privmsg(username, message),
+            },
+        }
+    }
+
+    fn wrapped_lines(&self, width: usize) -> Vec<String> {
+        wrap(&self.unwrapped(), width)
             .into_iter()
             .map(|cow| cow.to_string())
             .collect()
     }
 }
 
-pub struct App {
-    pub terminal_action_rx: Receiver<TerminalAction>,
-    pub twitch_action_tx: Sender<TwitchAction>,
-    pub chat_items: Vec<ChatItem>,
-    pub chat_lines: Vec<String>,
+// Actions (from /me) read as "* user does something"
+fn format_privmsg(username: &str, message: &str) -> String {
+    match message
+        .strip_prefix("\x01ACTION ")
+        .map(|action| action.trim_end_matches('\x01'))
+    {
+        Some(action) => format!("* {} {}", username, action),
+        None => format!("{}: {}", username, message),
+    }
+}
+
+fn describe_connection_status(status: &ConnectionStatus) -> String {
+    match status {
+        ConnectionStatus::Connecting => "[client] Attempting to connect to twitch...".to_owned(),
+        ConnectionStatus::Authenticating { username } => {
+            format!("[client] Attempting to auth as \"{}\"...", username)
+        }
+        ConnectionStatus::Connected { channels } => format!(
+            "[client] Auth successful! Joined {}. Listening to messages now.",
+            channels
+                .iter()
+                .map(|channel| format!("#{}", channel))
+                .collect::<Vec<String>>()
+                .join(", ")
+        ),
+        ConnectionStatus::Disconnected(reason) => match reason {
+            DisconnectReason::ConnectionClosed => "[client] Connection closed.".to_owned(),
+            DisconnectReason::ServerRequested => {
+                "[client] Server requested a reconnect.".to_owned()
+            }
+            DisconnectReason::AuthFailed => "[client] Auth failed.".to_owned(),
+            DisconnectReason::AuthTimedOut => "[client] Auth timed out.".to_owned(),
+            DisconnectReason::TimedOut => "[client] Connection timed out.".to_owned(),
+            DisconnectReason::Error(e) => format!("[client] Connection error ({}).", e),
+            _ => "[client] Disconnected.".to_owned(),
+        },
+        ConnectionStatus::Reconnecting { attempt, delay } => {
+            if delay.is_zero() {
+                "[client] Reconnecting now...".to_owned()
+            } else {
+                format!(
+                    "[client] Reconnecting in {:.1} seconds (attempt {})...",
+                    delay.as_secs_f32(),
+                    attempt
+                )
+            }
+        }
+        _ => "[client] Connection status changed.".to_owned(),
+    }
+}
+
+// The modes that changed, or None if none did
+fn describe_room_modes(channel: &str, modes: &RoomModes) -> Option<String> {
+    let on_off = |flag: bool| if flag { "on" } else { "off" };
+    let mut descriptions: Vec<String> = Vec::new();
+    if let Some(emote_only) = modes.emote_only {
+        descriptions.push(format!("emote-only {}", on_off(emote_only)));
+    }
+    if let Some(minutes) = modes.followers_only {
+        descriptions.push(match minutes {
+            -1 => "followers-only off".to_owned(),
+            0 => "followers-only on".to_owned(),
+            _ => format!("followers-only ({} min)", minutes),
+        });
+    }
+    if let Some(r9k) = modes.r9k {
+        descriptions.push(format!("unique-chat {}", on_off(r9k)));
+    }
+    if let Some(seconds) = modes.slow {
+        descriptions.push(match seconds {
+            0 => "slow off".to_owned(),
+            _ => format!("slow ({}s)", seconds),
+        });
+    }
+    if let Some(subs_only) = modes.subs_only {
+        descriptions.push(format!("subs-only {}", on_off(subs_only)));
+    }
+    if descriptions.is_empty() {
+        None
+    } else {
+        Some(format!(
+            "[#{}] Room modes: {}",
+            channel,
+            descriptions.join(", ")
+        ))
+    }
+}
+
+pub enum BufferKind {
+    // Client and connection messages, and anything that doesn't belong to a channel
+    System,
+    Channel(String),
+}
+
+// What the status bar shows about the connection
+pub struct ConnectionInfo {
+    pub status: ConnectionStatus,
+    // Why we were last disconnected, until we're back in
+    pub disconnect_reason: Option<DisconnectReason>,
+    pub username: Option<String>,
+    pub latency: Option<Duration>,
+    // When the client is going to try again, while it waits to reconnect
+    pub reconnect_at: Option<Instant>,
+}
+
+impl ConnectionInfo {
+    fn update(&mut self, status: ConnectionStatus) {
+        self.reconnect_at = None;
+        match status {
+            ConnectionStatus::Authenticating { ref username } => {
+                self.username = Some(username.clone());
+            }
+            ConnectionStatus::Connected { .. } => self.disconnect_reason = None,
+            ConnectionStatus::Disconnected(ref reason) => {
+                self.disconnect_reason = Some(reason.clone());
+                self.latency = None;
+            }
+            ConnectionStatus::Reconnecting { delay, .. } => {
+                self.reconnect_at = Some(Instant::now() + delay);
+            }
+            _ => {}
+        }
+        self.status = status;
+    }
+}
+
+// An item, and its lines as they were last wrapped. Items are only wrapped once they come into
+// view, and again when the width has changed since.
+pub struct ChatEntry {
+    pub item: ChatItem,
+    lines: Vec<String>,
+    wrapped_width: Option<u16>,
+}
+
+impl ChatEntry {
+    fn new(item: ChatItem) -> Self {
+        ChatEntry {
+            item,
+            lines: Vec::new(),
+            wrapped_width: None,
+        }
+    }
+
+    // Only up to date for the items ChatBuffer::view says are on screen
+    pub fn lines(&self) -> &[String] {
+        &self.lines
+    }
+
+    // Returns the number of lines, which is never 0
+    fn wrap(&mut self, width: u16) -> usize {
+        if self.wrapped_width != Some(width) {
+            self.lines = self.item.wrapped_lines(width.into());
+            if self.lines.is_empty() {
+                self.lines.push(String::new());
+            }
+            self.wrapped_width = Some(width);
+        }
+        self.lines.len()
+    }
+}
+
+// The contents and view state of one tab. Only the newest items are kept in memory (see
+// scrollback.rs), so items are referred to by their number, counting from the first one ever
+// pushed, rather than their index in chat_items.
+pub struct ChatBuffer {
+    pub kind: BufferKind,
+    pub chat_items: VecDeque<ChatEntry>,
+    // The number of the first item in chat_items
+    pub first_item: usize,
+    pub max_items: usize,
+    // Where dropped items go, if anywhere
+    pub spill_file: Option<SpillFile>,
     pub scroll_state: ScrollState,
     pub scroll_active: bool,
-    pub input_field: String,
-    pub input_mode: InputMode,
+    // Items that arrived while this buffer was in the background
+    pub unread: usize,
+    // What was typed in the input field when we switched away from this buffer
+    pub draft: String,
     pub chat_width: u16,
     pub chat_height: u16,
+    // For tab completion
+    pub chatters: RecentNames,
+    pub emotes: RecentNames,
+    pub room_modes: RoomModes,
+}
+
+impl ChatBuffer {
+    // Failing to open the spill file still gives us a buffer, just without one
+    fn new(
+        kind: BufferKind,
+        scrollback: &ScrollbackConfig,
+        chat_width: u16,
+        chat_height: u16,
+    ) -> (Self, io::Result<()>) {
+        let spill_file = match scrollback.spill_dir {
+            Some(ref dir) => {
+                let name = match kind {
+                    BufferKind::System => "system".to_owned(),
+                    BufferKind::Channel(ref channel) => format!("#{}", channel),
+                };
+                SpillFile::open(dir, &name).map(Some)
+            }
+            None => Ok(None),
+        };
+        let (spill_file, result) = match spill_file {
+            Ok(spill_file) => (spill_file, Ok(())),
+            Err(e) => (None, Err(e)),
+        };
+        let buffer = ChatBuffer {
+            kind,
+            chat_items: VecDeque::new(),
+            first_item: 0,
+            max_items: scrollback.max_items.max(1),
+            spill_file,
+            scroll_state: ScrollState::Bottom,
+            scroll_active: false,
+            unread: 0,
+            draft: String::new(),
+            chat_width,
+            chat_height,
+            chatters: RecentNames::default(),
+            emotes: RecentNames::default(),
+            room_modes: RoomModes::default(),
+        };
+        (buffer, result)
+    }
+
+    pub fn channel(&self) -> Option<&String> {
+        match self.kind {
+            BufferKind::System => None,
+            BufferKind::Channel(ref channel) => Some(channel),
+        }
+    }
+
+    pub fn title(&self) -> String {
+        match self.kind {
+            BufferKind::System => "system".to_owned(),
+            BufferKind::Channel(ref channel) => format!("#{}", channel),
+        }
+    }
+
+    // The number the next pushed item will get
+    pub fn next_item(&self) -> usize {
+        self.first_item + self.chat_items.len()
+    }
+
+    // Pushes an item, dropping the oldest ones if there are too many. An error means the spill
+    // file couldn't be written, and we stopped using it.
+    pub fn push_to_chat(&mut self, item: ChatItem) -> io::Result<()> {
+        self.chat_items.push_back(ChatEntry::new(item));
+        self.drop_old_items()
+    }
+
+    // While scrolled up, we keep twice as many items, so that whatever was paged in doesn't go
+    // away again while it's being read
+    fn drop_old_items(&mut self) -> io::Result<()> {
+        let max_items = match self.scroll_state {
+            ScrollState::Bottom => self.max_items,
+            _ => self.max_items * 2,
+        };
+        let mut result = Ok(());
+        while self.chat_items.len() > max_items {
+            let Some(entry) = self.chat_items.pop_front() else {
+                break;
+            };
+            let number = self.first_item;
+            self.first_item += 1;
+            if let Some(ref mut spill_file) = self.spill_file {
+                if let Err(e) = spill_file.drop_item(number, &entry.item) {
+                    self.spill_file = None;
+                    result = Err(e);
+                }
+            }
+        }
+        // The view can't stay on an item that's gone
+        if let ScrollState::Anchored { item, .. } = self.scroll_state {
+            if item < self.first_item {
+                self.scroll_state = ScrollState::Top;
+            }
+        }
+        result
+    }
+
+    // Reads a page of dropped items back in from the spill file. Returns whether there was
+    // anything to read.
+    fn page_in(&mut self) -> io::Result<bool> {
+        let Some(ref mut spill_file) = self.spill_file else {
+            return Ok(false);
+        };
+        // Don't go past what drop_old_items keeps while scrolled up
+        let room = (self.max_items * 2).saturating_sub(self.chat_items.len());
+        if !spill_file.has_older() || room == 0 {
+            return Ok(false);
+        }
+        let items = match spill_file.read_older(PAGE_SIZE.min(room)) {
+            Ok(items) => items,
+            Err(e) => {
+                self.spill_file = None;
+                return Err(e);
+            }
+        };
+        for item in items.into_iter().rev() {
+            self.first_item -= 1;
+            self.chat_items.push_front(ChatEntry::new(item));
+        }
+        Ok(true)
+    }
+
+    pub fn clear(&mut self) {
+        self.first_item = self.next_item();
+        self.chat_items.clear();
+        if let Some(ref mut spill_file) = self.spill_file {
+            spill_file.forget(self.first_item);
+        }
+        self.scroll_state = ScrollState::Bottom;
+        self.scroll_active = false;
+    }
+
+    // Updates the state shown next to one of our messages. Returns false if it isn't in this
+    // buffer.
+    fn set_outgoing_state(&mut self, outgoing_id: u64, new_state: OutgoingState) -> bool {
+        let entry =
+            self.chat_items.iter_mut().rev().find(
+                |entry| matches!(entry.item, ChatItem::Outgoing { id, .. } if id == outgoing_id),
+            );
+        match entry {
+            Some(entry) => {
+                if let ChatItem::Outgoing { ref mut state, .. } = entry.item {
+                    if *state != new_state {
+                        *state = new_state;
+                        entry.wrapped_width = None;
+                    }
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Items are rewrapped as they come into view, so there's nothing else to do here
+    pub fn refresh_chat_size(&mut self, new_chat_width: u16, new_chat_height: u16) {
+        self.chat_width = new_chat_width;
+        self.chat_height = new_chat_height;
+    }
+
+    fn wrap(&mut self, index: usize) -> usize {
+        let width = self.chat_width;
+        self.chat_items[index].wrap(width)
+    }
+
+    // Works out what's on screen, wrapping only the items there. Returns the index of the first
+    // item in view, and how many of its lines are above the view. A view that would leave space
+    // at the bottom turns into Bottom, so that it follows new items again.
+    pub fn view(&mut self) -> (usize, usize) {
+        let height = usize::from(self.chat_height);
+        let top = match self.scroll_state {
+            ScrollState::Bottom => None,
+            ScrollState::Top => Some((0, 0)),
+            ScrollState::Anchored { item, line } => item
+                .checked_sub(self.first_item)
+                .filter(|&index| index < self.chat_items.len())
+                .map(|index| (index, line.min(self.wrap(index) - 1))),
+        };
+        if let Some((index, line)) = top {
+            let mut below = 0;
+            let mut next = index;
+            while next < self.chat_items.len() && below <= height + line {
+                below += self.wrap(next);
+                next += 1;
+            }
+            if below > height + line {
+                self.scroll_active = true;
+                return (index, line);
+            }
+            self.scroll_state = ScrollState::Bottom;
+        }
+
+        let mut index = self.chat_items.len();
+        let mut lines = 0;
+        while index > 0 && lines < height {
+            index -= 1;
+            lines += self.wrap(index);
+        }
+        if lines > height || index > 0 {
+            self.scroll_active = true;
+        } else {
+            self.scroll_active = false;
+            self.scroll_state = ScrollState::Bottom;
+        }
+        (index, lines.saturating_sub(height))
+    }
+
+    // The line before a line of an item in memory, if there is one
+    fn line_before(&mut self, index: usize, line: usize) -> Option<(usize, usize)> {
+        match (index, line) {
+            (_, line) if line > 0 => Some((index, line - 1)),
+            (0, _) => None,
+            (index, _) => Some((index - 1, self.wrap(index - 1) - 1)),
+        }
+    }
+
+    fn anchor(&mut self, index: usize, line: usize) {
+        self.scroll_state = ScrollState::Anchored {
+            item: self.first_item + index,
+            line,
+        };
+    }
+
+    // Scrolls so that an item's first line is around the middle of the view
+    fn scroll_to_item(&mut self, item: usize) {
+        let Some(mut position) = item
+            .checked_sub(self.first_item)
+            .filter(|&index| index < self.chat_items.len())
+            .map(|index| (index, 0))
+        else {
+            return;
+        };
+        for _ in 0..self.chat_height / 2 {
+            match self.line_before(position.0, position.1) {
+                Some(before) => position = before,
+                None => break,
+            }
+        }
+        self.anchor(position.0, position.1);
+        self.view();
+    }
+
+    // At the top, scrolling up reads older items back in, if there are any
+    fn scroll_up(&mut self) -> io::Result<()> {
+        let (index, line) = self.view();
+        let before = match self.line_before(index, line) {
+            Some(before) => Some(before),
+            None => {
+                let first_item = self.first_item;
+                if self.page_in()? {
+                    self.line_before(first_item - self.first_item, 0)
+                } else {
+                    None
+                }
+            }
+        };
+        match before {
+            Some((index, line)) => self.anchor(index, line),
+            None => self.scroll_state = ScrollState::Top,
+        }
+        Ok(())
+    }
+
+    // Home: goes to the top, or pages in older items if we're there already
+    fn scroll_to_top(&mut self) -> io::Result<()> {
+        if let ScrollState::Top = self.scroll_state {
+            self.page_in()?;
+        }
+        self.scroll_state = ScrollState::Top;
+        Ok(())
+    }
+
+    fn scroll_down(&mut self) {
+        let (index, line) = self.view();
+        if let ScrollState::Bottom = self.scroll_state {
+            return;
+        }
+        if line + 1 < self.wrap(index) {
+            self.anchor(index, line + 1);
+        } else {
+            self.anchor(index + 1, 0);
+        }
+        self.view();
+    }
+}
+
+pub struct App {
+    pub twitch_action_tx: Sender<TwitchAction>,
+    // The system buffer always comes first, followed by one buffer per joined channel in the order
+    // we joined them
+    pub buffers: Vec<ChatBuffer>,
+    // Which buffer each pane shows. A buffer is shown in one pane at most, so every pane gets its
+    // own scroll state. Messages we type go to the channel of the focused pane.
+    pub layout: PaneLayout<usize>,
+    pub focused_pane: usize,
+    pub input_field: LineEditor,
+    pub input_mode: InputMode,
+    pub history: InputHistory,
+    pub history_search: Option<HistorySearch>,
+    pub completion: Option<Completion>,
+    pub scrollback_search: Option<ScrollbackSearch>,
+    // Lowercase usernames whose messages we don't show
+    pub ignored_users: HashSet<String>,
+    pub scrollback: ScrollbackConfig,
+    pub connection: ConnectionInfo,
+    // Whether anything changed since the last frame was drawn
+    pub needs_redraw: bool,
 }
 
 impl App {
     pub fn init(
         init_width: u16,
         init_height: u16,
-        terminal_action_rx: Receiver<TerminalAction>,
+        channels: Vec<String>,
+        layout: Option<PaneLayoutConfig>,
+        history: InputHistory,
+        scrollback: ScrollbackConfig,
         twitch_action_tx: Sender<TwitchAction>,
     ) -> Self {
         // TODO: do we want to compute chat_width and chat_height via the render
         // layout/constraints? What we have here is correct but hardcoded
-        App {
-            terminal_action_rx,
+        // Subtract 2 from the left/right borders
+        let chat_width = init_width.saturating_sub(2);
+        // Subtract 1 for the tab bar, 2 for the top/bottom borders, 3 for the initial input area
+        // height, and 1 for the status bar
+        let chat_height = init_height.saturating_sub(7);
+
+        let mut spill_errors = Vec::new();
+        let buffers: Vec<ChatBuffer> = std::iter::once(BufferKind::System)
+            .chain(channels.into_iter().map(BufferKind::Channel))
+            .map(|kind| {
+                let (buffer, result) = ChatBuffer::new(kind, &scrollback, chat_width, chat_height);
+                if let Err(e) = result {
+                    spill_errors.push((buffer.title(), e));
+                }
+                buffer
+            })
+            .collect();
+
+        // Resolve the saved layout against our buffers, leaving out channels we don't have and
+        // panes that repeat a buffer
+        let mut shown = Vec::new();
+        let layout = layout
+            .and_then(|layout| {
+                layout.filter_map(&mut |pane| {
+                    let index = match pane {
+                        None => Some(0),
+                        Some(channel) => buffers
+                            .iter()
+                            .position(|buffer| buffer.channel() == Some(&channel)),
+                    }?;
+                    if shown.contains(&index) {
+                        return None;
+                    }
+                    shown.push(index);
+                    Some(index)
+                })
+            })
+            // Otherwise, start out in the first channel, if we have one
+            .unwrap_or(PaneLayout::Pane(if buffers.len() > 1 { 1 } else { 0 }));
+
+        let mut app = App {
             twitch_action_tx,
-            chat_items: Vec::new(),
-            chat_lines: Vec::new(),
-            scroll_state: ScrollState::Bottom,
-            scroll_active: false,
-            input_field: String::new(),
+            buffers,
+            layout,
+            focused_pane: 0,
+            input_field: LineEditor::default(),
             input_mode: InputMode::Normal,
-            // Subtract 2 from the left/right borders
-            chat_width: init_width.saturating_sub(2),
-            // Subtract 2 for the top/bottom borders, and 3 for the initial input area height
-            chat_height: init_height.saturating_sub(5),
+            history,
+            history_search: None,
+            completion: None,
+            scrollback_search: None,
+            ignored_users: HashSet::new(),
+            scrollback,
+            connection: ConnectionInfo {
+                status: ConnectionStatus::Connecting,
+                disconnect_reason: None,
+                username: None,
+                latency: None,
+                reconnect_at: None,
+            },
+            needs_redraw: true,
+        };
+        for (title, e) in spill_errors {
+            app.report_spill_error(&title, e);
         }
+        app
+    }
+
+    // When the reconnect countdown in the status bar next changes, if it's counting down
+    pub fn next_tick(&self) -> Option<Instant> {
+        let now = Instant::now();
+        let remaining = self.connection.reconnect_at?.checked_duration_since(now)?;
+        // The countdown shows whole seconds, rounded up
+        let to_next_second = match remaining.subsec_nanos() {
+            0 => Duration::from_secs(1),
+            nanos => Duration::from_nanos(nanos.into()),
+        };
+        Some(now + to_next_second)
+    }
+
+    // The buffer in the focused pane
+    pub fn active_buffer_index(&self) -> usize {
+        *self.layout.panes()[self.focused_pane]
+    }
+
+    pub fn active_buffer(&self) -> &ChatBuffer {
+        &self.buffers[self.active_buffer_index()]
+    }
+
+    pub fn active_buffer_mut(&mut self) -> &mut ChatBuffer {
+        let index = self.active_buffer_index();
+        &mut self.buffers[index]
     }
 
-    pub fn push_to_chat(&mut self, item: ChatItem) {
-        let item_lines = item.wrapped_lines(self.chat_width.into());
-        let item_line_count = item_lines.len();
-        self.chat_lines.extend(item_lines);
-        self.chat_items.push(item);
-        if let ScrollState::Offset(n) = self.scroll_state {
-            if n > 0 {
-                self.scroll_state = ScrollState::Offset(n + item_line_count);
+    pub fn active_channel(&self) -> Option<&String> {
+        self.active_buffer().channel()
+    }
+
+    fn channel_buffer(&self, channel: &str) -> Option<usize> {
+        self.buffers
+            .iter()
+            .position(|buffer| buffer.channel().is_some_and(|c| c == channel))
+    }
+
+    fn pane_showing(&self, index: usize) -> Option<usize> {
+        self.layout
+            .panes()
+            .iter()
+            .position(|&&shown| shown == index)
+    }
+
+    // Moves the focus to another pane, or shows another buffer in the focused pane, keeping each
+    // buffer's unsent input around
+    fn change_focus(&mut self, change: impl FnOnce(&mut Self)) {
+        self.end_history_search(true);
+        self.completion = None;
+        self.scrollback_search = None;
+        self.history.stop_browsing();
+        let draft = self.input_field.take_text();
+        self.active_buffer_mut().draft = draft;
+        change(self);
+        let buffer = self.active_buffer_mut();
+        buffer.unread = 0;
+        let draft = std::mem::take(&mut buffer.draft);
+        self.input_field.set_text(draft);
+    }
+
+    // Shows a buffer in the focused pane, or focuses the pane that already shows it
+    pub fn switch_buffer(&mut self, index: usize) {
+        if index >= self.buffers.len() || index == self.active_buffer_index() {
+            return;
+        }
+        self.change_focus(|app| match app.pane_showing(index) {
+            Some(pane) => app.focused_pane = pane,
+            None => {
+                if let Some(shown) = app.layout.pane_mut(app.focused_pane) {
+                    *shown = index;
+                }
             }
+        });
+    }
+
+    fn cycle_buffer(&mut self, forward: bool) {
+        let count = self.buffers.len();
+        let active = self.active_buffer_index();
+        let index = if forward {
+            (active + 1) % count
+        } else {
+            (active + count - 1) % count
         };
+        self.switch_buffer(index);
     }
 
-    pub fn get_scroll_offset_limit(&self) -> usize {
-        self.chat_lines
-            .len()
-            .saturating_sub(self.chat_height.into())
+    fn cycle_pane(&mut self, forward: bool) {
+        let count = self.layout.pane_count();
+        let pane = if forward {
+            (self.focused_pane + 1) % count
+        } else {
+            (self.focused_pane + count - 1) % count
+        };
+        self.change_focus(|app| app.focused_pane = pane);
     }
 
-    pub fn refresh_chat_size(&mut self, new_chat_width: u16, new_chat_height: u16) {
-        if self.chat_width != new_chat_width {
-            self.chat_width = new_chat_width;
-            self.rewrap_lines();
-        }
-        // Update height and adjust scroll state
-        if self.chat_height != new_chat_height {
-            let height_delta = new_chat_height as i32 - self.chat_height as i32;
-            self.chat_height = new_chat_height;
-
-            self.scroll_state = match self.scroll_state {
-                // If there is no overflow anymore, reset scroll state to the initial state (Bottom
-                // with scroll inactive)
-                _ if self.chat_lines.len() <= (new_chat_height as usize) => {
-                    self.scroll_active = false;
-                    ScrollState::Bottom
-                }
-                // If we were in Bottom or Top, stay there
-                ScrollState::Bottom => ScrollState::Bottom,
-                ScrollState::Top => ScrollState::Top,
-                // Otherwise, we'll try to fix the topmost displayed line. That is, we'll try to
-                // preserve the number of hidden lines above, which is (line count - offset -
-                // chat height).
-                // Basically, if chat height changes by delta, then offset must change by -delta.
-                // Then the new scroll state will be Offset(new offset), unless the new offset is 0
-                // or negative, in which case we just set it to Bottom.
-                // Notice that, at this point, we can assume there is overflow (otherwise we would
-                // have pattern-matched earlier). So in the Bottom case, we don't need to disable
-                // scrolling.
-                ScrollState::Offset(n) => {
-                    let new_offset = n as i32 - height_delta;
-                    if new_offset > 0 {
-                        ScrollState::Offset(new_offset as usize)
-                    } else {
-                        ScrollState::Bottom
-                    }
+    // Splits the focused pane, showing the next buffer that isn't on screen yet in the new pane
+    fn split_pane(&mut self, direction: SplitDirection) {
+        let count = self.buffers.len();
+        let active = self.active_buffer_index();
+        let hidden = (1..count)
+            .map(|offset| (active + offset) % count)
+            .find(|&index| self.pane_showing(index).is_none());
+        match hidden {
+            Some(index) => {
+                let pane = self.focused_pane;
+                self.layout.split_pane(pane, direction, index);
+                self.change_focus(|app| app.focused_pane = pane + 1);
+            }
+            None => self.push_to_system(ChatItem::Debug {
+                content: "[client] Every buffer is already on screen.".to_owned(),
+            }),
+        }
+    }
+
+    fn close_pane(&mut self) {
+        if self.layout.pane_count() > 1 {
+            let pane = self.focused_pane;
+            self.change_focus(|app| {
+                app.layout.remove_pane(pane);
+                app.focused_pane = pane.saturating_sub(1);
+            });
+        }
+    }
+
+    fn save_layout(&mut self) {
+        let buffers = &self.buffers;
+        let layout = self
+            .layout
+            .clone()
+            .filter_map(&mut |index| Some(buffers[index].channel().cloned()));
+        let content = match layout.map(|layout| save_layout(&layout)) {
+            Some(Ok(())) => "[client] Saved the pane layout to the configuration file.".to_owned(),
+            Some(Err(e)) => format!("[client] Failed to save the pane layout ({:?}).", e),
+            None => return,
+        };
+        self.push_to_system(ChatItem::Debug { content });
+    }
+
+    // Adds an item to a buffer, counting it as unread if that buffer is not on screen
+    fn push_to_buffer(&mut self, index: usize, item: ChatItem) {
+        if self.pane_showing(index).is_none() {
+            self.buffers[index].unread += 1;
+        }
+        if let Err(e) = self.buffers[index].push_to_chat(item) {
+            let title = self.buffers[index].title();
+            self.report_spill_error(&title, e);
+        }
+    }
+
+    fn report_spill_error(&mut self, title: &str, e: io::Error) {
+        self.push_to_system(ChatItem::Debug {
+            content: format!(
+                "[error] Could not use the scrollback file of {}, older messages will be lost ({}).",
+                title, e
+            ),
+        });
+    }
+
+    pub fn push_to_system(&mut self, item: ChatItem) {
+        self.push_to_buffer(0, item);
+    }
+
+    // Joining a channel also brings it up in the focused pane
+    fn join_channel(&mut self, channel: String) {
+        match self.channel_buffer(&channel) {
+            Some(index) => self.switch_buffer(index),
+            None => {
+                self.twitch_action_tx
+                    .send(TwitchAction::Join {
+                        channel: channel.clone(),
+                    })
+                    .unwrap();
+                let (chat_width, chat_height) = {
+                    let buffer = self.active_buffer();
+                    (buffer.chat_width, buffer.chat_height)
+                };
+                let (buffer, result) = ChatBuffer::new(
+                    BufferKind::Channel(channel),
+                    &self.scrollback,
+                    chat_width,
+                    chat_height,
+                );
+                if let Err(e) = result {
+                    self.report_spill_error(&buffer.title(), e);
                 }
+                self.buffers.push(buffer);
+                self.switch_buffer(self.buffers.len() - 1);
             }
         }
     }
 
-    pub fn rewrap_lines(&mut self) {
-        let old_line_count = self.chat_lines.len();
-        self.chat_lines = self
-            .chat_items
-            .iter()
-            .map(|item| item.wrapped_lines(self.chat_width.into()))
-            .flatten()
-            .collect();
-        let line_count_delta = self.chat_lines.len() as i32 - old_line_count as i32;
-
-        // TODO: This is almost identical to the scroll adjusting in refresh_chat_size. Maybe we
-        // can yoink it out into its own method, taking an offset delta as a parameter?
-        self.scroll_state = match self.scroll_state {
-            _ if self.chat_lines.len() <= self.chat_height.into() => {
-                self.scroll_active = false;
-                ScrollState::Bottom
-            }
-            ScrollState::Bottom => ScrollState::Bottom,
-            ScrollState::Top => ScrollState::Top,
-            ScrollState::Offset(n) => {
-                let new_offset = n as i32 + line_count_delta;
-                if new_offset > 0 {
-                    ScrollState::Offset(new_offset as usize)
+    fn part_channel(&mut self, channel: String) {
+        if let Some(index) = self.channel_buffer(&channel) {
+            self.twitch_action_tx
+                .send(TwitchAction::Part {
+                    channel: channel.clone(),
+                })
+                .unwrap();
+            // Take the buffer off the screen first: close its pane, or show the previous buffer
+            // if it's the only pane
+            if let Some(pane) = self.pane_showing(index) {
+                if self.layout.pane_count() > 1 {
+                    self.change_focus(|app| {
+                        app.layout.remove_pane(pane);
+                        if app.focused_pane >= pane {
+                            app.focused_pane = app.focused_pane.saturating_sub(1);
+                        }
+                    });
                 } else {
-                    ScrollState::Bottom
+                    self.switch_buffer(index - 1);
+                }
+            }
+            self.buffers.remove(index);
+            self.layout.for_each_pane_mut(&mut |shown| {
+                if *shown > index {
+                    *shown -= 1;
                 }
+            });
+            // The search can be on a buffer that isn't focused, or not even on screen
+            match self.scrollback_search {
+                Some(ref search) if search.buffer == index => self.scrollback_search = None,
+                Some(ref mut search) if search.buffer > index => search.buffer -= 1,
+                _ => {}
             }
+        } else {
+            self.push_to_system(ChatItem::Debug {
+                content: format!("[client] Not in #{}.", channel),
+            });
         }
     }
 
-    pub fn try_recv_terminal_action(&mut self) {
-        if let Ok(action) = self.terminal_action_rx.try_recv() {
-            match action {
-                TerminalAction::PrintDebug(debug_message) => {
-                    self.push_to_chat(ChatItem::Debug {
-                        content: debug_message,
+    // Handles what was typed in the input field on Enter: /join and /part, or a message for the
+    // active channel
+    // Sends a message, or runs a command. Returns true if we should quit.
+    fn submit_input(&mut self, input: &str) -> bool {
+        let command = match parse_command(input) {
+            Some(Ok(command)) => command,
+            Some(Err(e)) => {
+                let content = match e {
+                    CommandError::Unknown(name) => format!(
+                        "[client] Unknown command /{}. Type /help for a list of commands.",
+                        name
+                    ),
+                    CommandError::Usage(info) => format!("[client] Usage: {}", info.usage),
+                    CommandError::BadChannel(channel) => {
+                        format!("[client] Bad channel name \"{}\".", channel)
+                    }
+                };
+                self.push_to_system(ChatItem::Debug { content });
+                return false;
+            }
+            None => {
+                self.send_to_active_channel(input.to_owned());
+                return false;
+            }
+        };
+
+        match command {
+            Command::Join(channel) => self.join_channel(channel),
+            Command::Part(Some(channel)) => self.part_channel(channel),
+            Command::Part(None) => match self.active_channel().cloned() {
+                Some(channel) => self.part_channel(channel),
+                None => self.push_to_system(ChatItem::Debug {
+                    content: "[client] Not in any channel.".to_owned(),
+                }),
+            },
+            // Actions are CTCP messages
+            Command::Me(message) => {
+                self.send_to_active_channel(format!("\x01ACTION {}\x01", message));
+            }
+            // Twitch takes whispers as a chat command in any channel
+            Command::Whisper { username, message } => {
+                self.send_to_active_channel(format!("/w {} {}", username, message));
+            }
+            Command::Raw(line) => {
+                self.twitch_action_tx
+                    .send(TwitchAction::SendRaw(line))
+                    .unwrap();
+            }
+            Command::Clear => self.active_buffer_mut().clear(),
+            Command::Quit => return true,
+            Command::Ignore(Some(username)) => {
+                let content = format!("[client] Ignoring messages from {}.", username);
+                self.ignored_users.insert(username);
+                self.push_to_system(ChatItem::Debug { content });
+            }
+            Command::Ignore(None) => {
+                let content = if self.ignored_users.is_empty() {
+                    "[client] Not ignoring anyone.".to_owned()
+                } else {
+                    let mut ignored: Vec<&String> = self.ignored_users.iter().collect();
+                    ignored.sort();
+                    format!(
+                        "[client] Ignoring messages from {}.",
+                        ignored
+                            .into_iter()
+                            .cloned()
+                            .collect::<Vec<String>>()
+                            .join(", ")
+                    )
+                };
+                self.push_to_system(ChatItem::Debug { content });
+            }
+            Command::Unignore(username) => {
+                let content = if self.ignored_users.remove(&username) {
+                    format!("[client] No longer ignoring {}.", username)
+                } else {
+                    format!("[client] {} wasn't ignored.", username)
+                };
+                self.push_to_system(ChatItem::Debug { content });
+            }
+            Command::Help(None) => {
+                self.push_to_system(ChatItem::Debug {
+                    content: "[client] Commands:".to_owned(),
+                });
+                for info in COMMANDS {
+                    self.push_to_system(ChatItem::Debug {
+                        content: format!("  {} - {}", info.usage, info.description),
                     });
                 }
-                TerminalAction::PrintPrivmsg {
-                    channel,
+            }
+            Command::Help(Some(name)) => {
+                let content = match command_info(&name) {
+                    Some(info) => format!("[client] {} - {}", info.usage, info.description),
+                    None => format!(
+                        "[client] Unknown command /{}.",
+                        name.trim_start_matches('/')
+                    ),
+                };
+                self.push_to_system(ChatItem::Debug { content });
+            }
+        }
+        false
+    }
+
+    fn send_to_active_channel(&mut self, message: String) {
+        match self.active_channel().cloned() {
+            Some(channel) => self
+                .twitch_action_tx
+                .send(TwitchAction::SendPrivmsg { channel, message })
+                .unwrap(),
+            None => self.push_to_system(ChatItem::Debug {
+                content: "[client] Not in any channel. Use /join <channel> to join one.".to_owned(),
+            }),
+        }
+    }
+
+    pub fn handle_terminal_action(&mut self, action: TerminalAction) {
+        self.needs_redraw = true;
+        match action {
+            TerminalAction::PrintDebug(debug_message) => {
+                self.push_to_system(ChatItem::Debug {
+                    content: debug_message,
+                });
+            }
+            TerminalAction::PrintPrivmsg {
+                channel,
+                username,
+                message,
+                emotes,
+            } => {
+                if self.ignored_users.contains(&username.to_lowercase()) {
+                    return;
+                }
+                // If there's no buffer, we already left this channel and the message was still
+                // on its way
+                if let Some(index) = self.channel_buffer(&channel) {
+                    let buffer = &mut self.buffers[index];
+                    buffer.chatters.seen(&username);
+                    for emote in emotes {
+                        buffer.emotes.seen(&emote);
+                    }
+                    let item = ChatItem::Privmsg { username, message };
+                    self.push_to_buffer(index, item);
+                }
+            }
+            TerminalAction::Joined { channel, username } => {
+                self.push_to_system(ChatItem::Debug {
+                    content: format!("[client] {} joined #{}.", username, channel),
+                });
+            }
+            TerminalAction::Parted { channel, username } => {
+                self.push_to_system(ChatItem::Debug {
+                    content: format!("[client] {} left #{}.", username, channel),
+                });
+            }
+            TerminalAction::AddChatters { channel, usernames } => {
+                if let Some(index) = self.channel_buffer(&channel) {
+                    for username in usernames {
+                        self.buffers[index].chatters.add(&username);
+                    }
+                }
+            }
+            TerminalAction::RemoveChatter { channel, username } => {
+                if let Some(index) = self.channel_buffer(&channel) {
+                    self.buffers[index].chatters.remove(&username);
+                }
+            }
+            TerminalAction::PrintOutgoing {
+                id,
+                channel,
+                username,
+                message,
+                state,
+            } => {
+                let item = ChatItem::Outgoing {
+                    id,
                     username,
                     message,
-                } => {
-                    self.push_to_chat(ChatItem::Privmsg {
+                    state,
+                };
+                if let Some(index) = self.channel_buffer(&channel) {
+                    self.push_to_buffer(index, item);
+                }
+            }
+            TerminalAction::SetOutgoingState { id, state } => {
+                for buffer in self.buffers.iter_mut() {
+                    if buffer.set_outgoing_state(id, state) {
+                        break;
+                    }
+                }
+            }
+            TerminalAction::PrintNotice { channel, message } => {
+                let content = match channel {
+                    Some(channel) => format!("[notice] [#{}] {}", channel, message),
+                    None => format!("[notice] {}", message),
+                };
+                self.push_to_system(ChatItem::Debug { content });
+            }
+            TerminalAction::PrintUserNotice {
+                channel,
+                username,
+                system_message,
+                message,
+                ..
+            } => {
+                // The system message already describes the event, e.g. "X subscribed at Tier 1"
+                let mut content = format!(
+                    "[#{}] {}",
+                    channel,
+                    system_message
+                        .or(username)
+                        .unwrap_or("(user notice)".to_owned())
+                );
+                if let Some(message) = message {
+                    content.push_str(" -- ");
+                    content.push_str(&message);
+                }
+                self.push_to_system(ChatItem::Debug { content });
+            }
+            TerminalAction::ClearChat {
+                channel,
+                username,
+                ban_duration,
+            } => {
+                let content = match (username, ban_duration) {
+                    (None, _) => format!("[#{}] Chat was cleared by a moderator.", channel),
+                    (Some(username), None) => format!("[#{}] {} was banned.", channel, username),
+                    (Some(username), Some(duration)) => format!(
+                        "[#{}] {} was timed out for {} seconds.",
+                        channel, username, duration
+                    ),
+                };
+                self.push_to_system(ChatItem::Debug { content });
+            }
+            TerminalAction::ClearMessage {
+                channel,
+                username,
+                message,
+            } => {
+                self.push_to_system(ChatItem::Debug {
+                    content: format!(
+                        "[#{}] A message from {} was deleted: {}",
                         channel,
-                        username,
-                        message,
-                    });
+                        username.unwrap_or("someone".to_owned()),
+                        message
+                    ),
+                });
+            }
+            TerminalAction::PrintWhisper { username, message } => {
+                self.push_to_system(ChatItem::Debug {
+                    content: format!("[whisper] {}: {}", username, message),
+                });
+            }
+            TerminalAction::SetHostTarget { channel, target } => {
+                let content = match target {
+                    Some(target) => format!("[#{}] Now hosting #{}.", channel, target),
+                    None => format!("[#{}] Exited host mode.", channel),
+                };
+                self.push_to_system(ChatItem::Debug { content });
+            }
+            TerminalAction::SetConnectionStatus(status) => {
+                self.push_to_system(ChatItem::Debug {
+                    content: describe_connection_status(&status),
+                });
+                self.connection.update(status);
+            }
+            TerminalAction::SetLatency(latency) => self.connection.latency = Some(latency),
+            TerminalAction::UpdateRoomModes { channel, modes } => {
+                if let Some(content) = describe_room_modes(&channel, &modes) {
+                    self.push_to_system(ChatItem::Debug { content });
                 }
-                TerminalAction::PrintPing(content) => {
-                    self.push_to_chat(ChatItem::Ping { content });
+                if let Some(index) = self.channel_buffer(&channel) {
+                    self.buffers[index].room_modes.update(&modes);
                 }
             }
+            // Anything newer than this app, which it doesn't know how to show
+            _ => {}
+        }
+    }
+
+    // Keeps the options of the previous search, if any
+    fn start_scrollback_search(&mut self) {
+        let (regex, case_sensitive) = match self.scrollback_search {
+            Some(ref search) => (search.regex, search.case_sensitive),
+            None => (false, false),
+        };
+        self.scrollback_search = Some(ScrollbackSearch::new(
+            self.active_buffer_index(),
+            regex,
+            case_sensitive,
+        ));
+        self.input_mode = InputMode::Search;
+    }
+
+    fn handle_scrollback_search_key(&mut self, key: KeyEvent) {
+        let Some(ref mut search) = self.scrollback_search else {
+            self.input_mode = InputMode::Normal;
+            return;
+        };
+        match (key.modifiers, key.code) {
+            // Enter keeps the matches highlighted for n and N
+            (_, KeyCode::Enter) => {
+                self.input_mode = InputMode::Normal;
+                return;
+            }
+            (_, KeyCode::Esc) => {
+                self.scrollback_search = None;
+                self.input_mode = InputMode::Normal;
+                return;
+            }
+            (KeyModifiers::ALT, KeyCode::Char('r')) => search.regex = !search.regex,
+            (KeyModifiers::ALT, KeyCode::Char('c')) => {
+                search.case_sensitive = !search.case_sensitive
+            }
+            (KeyModifiers::CONTROL, KeyCode::Char('u')) => search.query.clear(),
+            (_, KeyCode::Backspace) => {
+                search.query.pop();
+            }
+            (KeyModifiers::NONE | KeyModifiers::SHIFT, KeyCode::Char(c)) => search.query.push(c),
+            _ => return,
+        }
+        // Search again from the bottom as the query changes
+        search.update();
+        search.current = None;
+        self.jump_to_match(true);
+    }
+
+    // The items in the searched buffer that match
+    fn search_matches(&self) -> Vec<usize> {
+        let Some(ref search) = self.scrollback_search else {
+            return Vec::new();
+        };
+        let buffer = &self.buffers[search.buffer];
+        buffer
+            .chat_items
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| search.matches(entry.item.username(), entry.item.text()))
+            .map(|(index, _)| buffer.first_item + index)
+            .collect()
+    }
+
+    // Goes to the next older (or newer) match, wrapping around at either end
+    fn jump_to_match(&mut self, older: bool) {
+        let matches = self.search_matches();
+        let Some(ref mut search) = self.scrollback_search else {
+            return;
+        };
+        let target = match (search.current, older) {
+            (Some(current), true) => matches
+                .iter()
+                .rev()
+                .find(|&&index| index < current)
+                .or(matches.last()),
+            (Some(current), false) => matches
+                .iter()
+                .find(|&&index| index > current)
+                .or(matches.first()),
+            (None, _) => matches.last(),
+        };
+        search.match_count = matches.len();
+        if let Some(&target) = target {
+            search.current = Some(target);
+            self.buffers[search.buffer].scroll_to_item(target);
+        }
+    }
+
+    // History is kept per channel, with the system buffer's under the empty name
+    fn history_channel(&self) -> String {
+        self.active_channel().cloned().unwrap_or_default()
+    }
+
+    // Keys while searching the history with Ctrl-R. Returns false for keys that end the search
+    // and should then be handled as usual.
+    fn handle_search_key(&mut self, key: KeyEvent) -> bool {
+        let channel = self.history_channel();
+        let Some(ref mut search) = self.history_search else {
+            return false;
+        };
+        let newest = self.history.len(&channel);
+        match (key.modifiers, key.code) {
+            // Look further back
+            (KeyModifiers::CONTROL, KeyCode::Char('r')) => {
+                let before = search.found.unwrap_or(newest);
+                if let Some(found) = self.history.search(&channel, &search.query, before) {
+                    search.found = Some(found);
+                }
+            }
+            (KeyModifiers::NONE | KeyModifiers::SHIFT, KeyCode::Char(c)) => {
+                search.query.push(c);
+                // The current match might still do
+                let before = search.found.map_or(newest, |found| found + 1);
+                search.found = self.history.search(&channel, &search.query, before);
+            }
+            (_, KeyCode::Backspace) => {
+                search.query.pop();
+                search.found = self.history.search(&channel, &search.query, newest);
+            }
+            (_, KeyCode::Esc) | (KeyModifiers::CONTROL, KeyCode::Char('g')) => {
+                self.end_history_search(false);
+                return true;
+            }
+            // Enter takes the match without sending it, so it can be edited first
+            (_, KeyCode::Enter) => {
+                self.end_history_search(true);
+                return true;
+            }
+            _ => {
+                self.end_history_search(true);
+                return false;
+            }
+        }
+        if let Some(entry) = search
+            .found
+            .and_then(|found| self.history.get(&channel, found))
+        {
+            self.input_field.set_text(entry.to_owned());
+        }
+        true
+    }
+
+    fn start_completion(&mut self) {
+        let (word_start, word) = self.input_field.word_before_cursor();
+        let buffer = self.active_buffer();
+        let candidates = candidates(word, word_start == 0, &buffer.chatters, &buffer.emotes);
+        let Some(first) = candidates.first() else {
+            return;
+        };
+        let original = word.to_owned();
+        self.input_field.replace_before_cursor(word_start, first);
+        self.completion = Some(Completion {
+            word_start,
+            original,
+            candidates,
+            selected: 0,
+        });
+    }
+
+    // Keys while completing: Tab and Shift-Tab go through the candidates, and Esc puts back what
+    // was typed. Returns false for keys that end the completion and should then be handled as
+    // usual.
+    fn handle_completion_key(&mut self, key: KeyEvent) -> bool {
+        let Some(ref mut completion) = self.completion else {
+            return false;
+        };
+        let count = completion.candidates.len();
+        match key.code {
+            KeyCode::Tab => completion.selected = (completion.selected + 1) % count,
+            KeyCode::BackTab => completion.selected = (completion.selected + count - 1) % count,
+            KeyCode::Esc => {
+                let (word_start, original) = (completion.word_start, completion.original.clone());
+                self.input_field
+                    .replace_before_cursor(word_start, &original);
+                self.completion = None;
+                return true;
+            }
+            _ => {
+                self.completion = None;
+                return false;
+            }
+        }
+        let candidate = &completion.candidates[completion.selected];
+        self.input_field
+            .replace_before_cursor(completion.word_start, candidate);
+        true
+    }
+
+    // Keeps the match in the input field, or puts back what was there before the search
+    fn end_history_search(&mut self, accept: bool) {
+        if let Some(search) = self.history_search.take() {
+            if !accept {
+                self.input_field.set_text(search.original);
+            }
+        }
+    }
+
+    // Line editing keys in insert mode, mostly the same as in a shell
+    fn edit_input(&mut self, key: KeyEvent) {
+        let editor = &mut self.input_field;
+        match (key.modifiers, key.code) {
+            (KeyModifiers::NONE, KeyCode::Left) => editor.move_left(),
+            (KeyModifiers::NONE, KeyCode::Right) => editor.move_right(),
+            (KeyModifiers::CONTROL, KeyCode::Left) | (KeyModifiers::ALT, KeyCode::Char('b')) => {
+                editor.move_word_left()
+            }
+            (KeyModifiers::CONTROL, KeyCode::Right) | (KeyModifiers::ALT, KeyCode::Char('f')) => {
+                editor.move_word_right()
+            }
+            (_, KeyCode::Home) | (KeyModifiers::CONTROL, KeyCode::Char('a')) => editor.move_home(),
+            (_, KeyCode::End) | (KeyModifiers::CONTROL, KeyCode::Char('e')) => editor.move_end(),
+            (KeyModifiers::ALT, KeyCode::Backspace)
+            | (KeyModifiers::CONTROL, KeyCode::Char('w')) => editor.kill_word_before(),
+            (_, KeyCode::Backspace) => editor.backspace(),
+            (_, KeyCode::Delete) => editor.delete(),
+            (KeyModifiers::CONTROL, KeyCode::Char('u')) => editor.kill_to_start(),
+            (KeyModifiers::CONTROL, KeyCode::Char('k')) => editor.kill_to_end(),
+            (KeyModifiers::CONTROL, KeyCode::Char('y')) => editor.yank(),
+            (KeyModifiers::NONE | KeyModifiers::SHIFT, KeyCode::Char(c)) => editor.insert_char(c),
+            _ => {}
         }
     }
 
     pub fn handle_key(&mut self, key: KeyEvent) -> bool {
+        self.needs_redraw = true;
+        // Switching tabs with Alt+Left/Right works in both modes
+        if key.modifiers == KeyModifiers::ALT {
+            match key.code {
+                KeyCode::Left => {
+                    self.cycle_buffer(false);
+                    return false;
+                }
+                KeyCode::Right => {
+                    self.cycle_buffer(true);
+                    return false;
+                }
+                _ => {}
+            }
+        }
+
         match self.input_mode {
             InputMode::Normal => match key.code {
                 KeyCode::Char('q') => true,
@@ -220,87 +1441,254 @@ impl App {
                     self.input_mode = InputMode::Insert;
                     false
                 }
-                KeyCode::Up if self.scroll_active => {
-                    let offset_limit = self.get_scroll_offset_limit();
-                    self.scroll_state = match self.scroll_state {
-                        ScrollState::Top => ScrollState::Top,
-                        // Make sure we convert any Offset(offset_limit) into Top
-                        ScrollState::Bottom => {
-                            if offset_limit == 1 {
-                                ScrollState::Top
-                            } else {
-                                ScrollState::Offset(1)
-                            }
-                        }
-                        ScrollState::Offset(n) if n + 1 == offset_limit => ScrollState::Top,
-                        ScrollState::Offset(n) => ScrollState::Offset(n + 1),
-                    };
+                KeyCode::Tab => {
+                    self.cycle_buffer(true);
                     false
                 }
-                KeyCode::Down if self.scroll_active => {
-                    let offset_limit = self.get_scroll_offset_limit();
-                    self.scroll_state = match self.scroll_state {
-                        // Make sure we convert any Offset(0) into Bottom
-                        ScrollState::Bottom | ScrollState::Offset(1) => ScrollState::Bottom,
-                        ScrollState::Offset(n) => ScrollState::Offset(n - 1),
-                        ScrollState::Top => {
-                            if offset_limit == 1 {
-                                ScrollState::Bottom
-                            } else {
-                                ScrollState::Offset(offset_limit - 1)
-                            }
-                        }
-                    };
+                KeyCode::BackTab => {
+                    self.cycle_buffer(false);
+                    false
+                }
+                // Panes: split side by side or stacked, close, cycle focus, resize, and save the
+                // layout to the config file
+                KeyCode::Char('v') => {
+                    self.split_pane(SplitDirection::Horizontal);
+                    false
+                }
+                KeyCode::Char('s') => {
+                    self.split_pane(SplitDirection::Vertical);
+                    false
+                }
+                KeyCode::Char('x') => {
+                    self.close_pane();
+                    false
+                }
+                KeyCode::Char('w') => {
+                    self.cycle_pane(true);
+                    false
+                }
+                KeyCode::Char('W') => {
+                    self.cycle_pane(false);
+                    false
+                }
+                KeyCode::Char('>') => {
+                    self.layout.resize_pane(self.focused_pane, 5);
+                    false
+                }
+                KeyCode::Char('<') => {
+                    self.layout.resize_pane(self.focused_pane, -5);
+                    false
+                }
+                KeyCode::Char('S') => {
+                    self.save_layout();
+                    false
+                }
+                // Jump to a tab by its position, starting at 1 for the system tab
+                KeyCode::Char(c @ '1'..='9') => {
+                    self.switch_buffer(c as usize - '1' as usize);
                     false
                 }
-                KeyCode::Home if self.scroll_active => {
-                    self.scroll_state = ScrollState::Top;
+                KeyCode::Up if self.active_buffer().scroll_active => {
+                    if let Err(e) = self.active_buffer_mut().scroll_up() {
+                        let title = self.active_buffer().title();
+                        self.report_spill_error(&title, e);
+                    }
+                    false
+                }
+                KeyCode::Down if self.active_buffer().scroll_active => {
+                    self.active_buffer_mut().scroll_down();
+                    false
+                }
+                KeyCode::Home if self.active_buffer().scroll_active => {
+                    if let Err(e) = self.active_buffer_mut().scroll_to_top() {
+                        let title = self.active_buffer().title();
+                        self.report_spill_error(&title, e);
+                    }
+                    false
+                }
+                KeyCode::End if self.active_buffer().scroll_active => {
+                    self.active_buffer_mut().scroll_state = ScrollState::Bottom;
+                    false
+                }
+                // Scrollback search: start one, go to older and newer matches, or stop
+                KeyCode::Char('/') => {
+                    self.start_scrollback_search();
+                    false
+                }
+                KeyCode::Char('n') => {
+                    self.jump_to_match(true);
+                    false
+                }
+                KeyCode::Char('N') => {
+                    self.jump_to_match(false);
                     false
                 }
-                KeyCode::End if self.scroll_active => {
-                    self.scroll_state = ScrollState::Bottom;
+                KeyCode::Esc => {
+                    self.scrollback_search = None;
                     false
                 }
                 _ => false,
             },
+            InputMode::Search => {
+                self.handle_scrollback_search_key(key);
+                false
+            }
             InputMode::Insert => match key.code {
+                _ if self.history_search.is_some() && self.handle_search_key(key) => false,
+                _ if self.completion.is_some() && self.handle_completion_key(key) => false,
+                KeyCode::Tab | KeyCode::BackTab => {
+                    self.start_completion();
+                    false
+                }
                 KeyCode::Esc => {
                     self.input_mode = InputMode::Normal;
                     false
                 }
-                KeyCode::Backspace => {
-                    if key.modifiers == KeyModifiers::ALT && self.input_field.len() > 0 {
-                        self.input_field = self.input_field.trim_end().rsplit_once(' ').map_or(
-                            String::new(),
-                            |(m, _)| {
-                                let mut mo = m.to_owned();
-                                mo.push(' ');
-                                mo
-                            },
-                        );
-                    } else {
-                        self.input_field.pop();
-                    };
+                KeyCode::Enter => {
+                    let trimmed = self.input_field.text().trim().to_owned();
+                    if trimmed.is_empty() {
+                        return false;
+                    }
+                    self.input_field.take_text();
+                    self.history.push(&self.history_channel(), trimmed.clone());
+                    self.submit_input(&trimmed)
+                }
+                KeyCode::Up if key.modifiers == KeyModifiers::NONE => {
+                    let channel = self.history_channel();
+                    if let Some(entry) = self.history.previous(&channel, self.input_field.text()) {
+                        self.input_field.set_text(entry.to_owned());
+                    }
                     false
                 }
-                KeyCode::Enter => {
-                    let trimmed = self.input_field.trim();
-                    if trimmed.len() > 0 {
-                        self.twitch_action_tx
-                            .send(TwitchAction::SendPrivmsg {
-                                message: trimmed.to_owned(),
-                            })
-                            .unwrap();
-                        self.input_field.clear();
-                    };
+                KeyCode::Down if key.modifiers == KeyModifiers::NONE => {
+                    if let Some(entry) = self.history.next(&self.history_channel()) {
+                        self.input_field.set_text(entry);
+                    }
                     false
                 }
-                KeyCode::Char(c) => {
-                    self.input_field.push(c);
+                KeyCode::Char('r') if key.modifiers == KeyModifiers::CONTROL => {
+                    self.history_search = Some(HistorySearch {
+                        query: String::new(),
+                        found: None,
+                        original: self.input_field.text().to_owned(),
+                    });
+                    false
+                }
+                _ => {
+                    self.edit_input(key);
                     false
                 }
-                _ => false,
             },
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::mpsc::{self, Receiver};
+
+    fn app(channels: &[&str]) -> (App, Receiver<TwitchAction>) {
+        let (twitch_action_tx, twitch_action_rx) = mpsc::channel();
+        let app = App::init(
+            80,
+            24,
+            channels.iter().map(|channel| channel.to_string()).collect(),
+            None,
+            InputHistory::new(0, None),
+            ScrollbackConfig::default(),
+            twitch_action_tx,
+        );
+        (app, twitch_action_rx)
+    }
+
+    fn search(app: &mut App, query: &str) {
+        app.start_scrollback_search();
+        for c in query.chars() {
+            app.handle_scrollback_search_key(KeyEvent::from(KeyCode::Char(c)));
+        }
+        app.handle_scrollback_search_key(KeyEvent::from(KeyCode::Enter));
+    }
+
+    fn last_system_line(app: &App) -> String {
+        app.buffers[0].chat_items.back().unwrap().item.unwrapped()
+    }
+
+    #[test]
+    fn test_moderation_events_in_system_tab() {
+        let (mut app, _twitch_action_rx) = app(&["forsen"]);
+
+        app.handle_terminal_action(TerminalAction::ClearChat {
+            channel: "forsen".to_owned(),
+            username: Some("xqc".to_owned()),
+            ban_duration: Some(600),
+        });
+        assert_eq!(
+            last_system_line(&app),
+            "[#forsen] xqc was timed out for 600 seconds."
+        );
+
+        app.handle_terminal_action(TerminalAction::ClearMessage {
+            channel: "forsen".to_owned(),
+            username: None,
+            message: "forsenE".to_owned(),
+        });
+        assert_eq!(
+            last_system_line(&app),
+            "[#forsen] A message from someone was deleted: forsenE"
+        );
+
+        app.handle_terminal_action(TerminalAction::PrintUserNotice {
+            channel: "forsen".to_owned(),
+            kind: Some("resub".to_owned()),
+            username: Some("xqc".to_owned()),
+            system_message: None,
+            message: Some("forsenE".to_owned()),
+        });
+        assert_eq!(last_system_line(&app), "[#forsen] xqc -- forsenE");
+
+        // Only the modes that changed are listed, and the channel's tab keeps all of them
+        app.handle_terminal_action(TerminalAction::UpdateRoomModes {
+            channel: "forsen".to_owned(),
+            modes: RoomModes {
+                slow: Some(30),
+                subs_only: Some(true),
+                ..RoomModes::default()
+            },
+        });
+        assert_eq!(
+            last_system_line(&app),
+            "[#forsen] Room modes: slow (30s), subs-only on"
+        );
+        assert_eq!(app.buffers[1].room_modes.slow, Some(30));
+        assert_eq!(app.buffers[0].chat_items.len(), 4);
+    }
+
+    #[test]
+    fn test_part_moves_search_to_shifted_buffer() {
+        let (mut app, _twitch_action_rx) = app(&["forsen", "nymn"]);
+        app.switch_buffer(2);
+        search(&mut app, "forsenE");
+
+        // forsen isn't on screen, so parting it leaves the search in place, one buffer down
+        app.part_channel("forsen".to_owned());
+        let search = app.scrollback_search.as_ref().unwrap();
+        assert_eq!(search.buffer, 1);
+        assert_eq!(
+            app.buffers[search.buffer].channel(),
+            Some(&"nymn".to_owned())
+        );
+        assert!(app.search_matches().is_empty());
+    }
+
+    #[test]
+    fn test_part_clears_search_of_parted_buffer() {
+        let (mut app, _twitch_action_rx) = app(&["forsen", "nymn"]);
+        app.switch_buffer(2);
+        search(&mut app, "forsenE");
+        app.part_channel("nymn".to_owned());
+        assert!(app.scrollback_search.is_none());
+        assert!(app.search_matches().is_empty());
+    }
+}
@@ -1,353 +1,1327 @@
-use std::io::{self, BufRead, BufReader, BufWriter, Write};
-use std::net::TcpStream;
+//! The connection to Twitch chat. [`TwitchClient::spawn`] starts a client on a thread of its own,
+//! which logs in, joins the configured channels and keeps the connection up until every sender of
+//! its actions is dropped.
+
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::path::PathBuf;
 use std::sync::mpsc::{self, Receiver, Sender};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use crate::actions::{TerminalAction, TwitchAction};
-use crate::config::{BotMode, TwitchLogin};
+use crate::actions::{
+    ConnectionStatus, DisconnectReason, OutgoingState, RoomModes, TerminalAction, TwitchAction,
+};
+use crate::config::{BotMode, Transport, TwitchLogin};
+use crate::events::EventQueue;
 use crate::irc::{RawIrcMessage, TwitchIrcCommand, TwitchIrcMessage};
+use crate::random::random_u64;
+use crate::ratelimit::{Outbox, QueuedPrivmsg};
+#[cfg(feature = "tls")]
+use crate::tls::TlsStream;
+use crate::websocket::{self, WebSocketUrl};
 
 const LOGIN_TIMEOUT_SECONDS: u16 = 5;
-const LOGIN_RETRY_SECONDS: u16 = 10;
+const BACKOFF_BASE_MS: u64 = 1_000;
+const BACKOFF_CAP_MS: u64 = 60_000;
+// How long to wait for the client to stop when the app quits
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
+// How often we ping the server, and how long it then has to say anything at all
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(60);
+const PONG_TIMEOUT: Duration = Duration::from_secs(10);
+const KEEPALIVE_PING: &str = "tuisen";
 
 // TODO: implement From<AppConfig> for this type, to make client initialization cleaner
+/// Where and how to connect. The channels are the ones joined on connecting.
+#[derive(Debug)]
 pub struct TwitchClientConfig {
     irc_addr: String,
     login: TwitchLogin,
-    channel: String,
+    channels: Vec<String>,
     bot_mode: BotMode,
+    capabilities: Vec<String>,
+    transport: Transport,
 }
 
 impl TwitchClientConfig {
-    pub fn new(irc_addr: String, login: TwitchLogin, channel: String, bot_mode: BotMode) -> Self {
+    pub fn new(
+        irc_addr: String,
+        login: TwitchLogin,
+        channels: Vec<String>,
+        bot_mode: BotMode,
+        capabilities: Vec<String>,
+        transport: Transport,
+    ) -> Self {
         TwitchClientConfig {
             irc_addr,
             login,
-            channel,
+            channels,
             bot_mode,
+            capabilities,
+            transport,
         }
     }
+
+    pub fn channels(&self) -> &[String] {
+        &self.channels
+    }
 }
 
+/// The app's handle on the client. The connection runs on a thread of its own, taking actions from
+/// the app and sending back what it should show. Dropping every sender stops it, and so does
+/// dropping the receiver for what it sends back.
+#[derive(Debug)]
 pub struct TwitchClient {
-    config: TwitchClientConfig,
-    terminal_action_tx: mpsc::Sender<TerminalAction>,
-    twitch_action_rx: mpsc::Receiver<TwitchAction>,
+    twitch_action_tx: Sender<TwitchAction>,
+    stopped_rx: Receiver<()>,
+}
+
+impl TwitchClient {
+    /// Starts the client. Everything it has to report, including the messages in the channels it
+    /// joined, goes to terminal_action_tx.
+    pub fn spawn(config: TwitchClientConfig, terminal_action_tx: Sender<TerminalAction>) -> Self {
+        let (twitch_action_tx, twitch_action_rx) = mpsc::channel();
+        let (stopped_tx, stopped_rx) = mpsc::channel();
+        thread::spawn(move || {
+            let _ = connect_and_listen(config, twitch_action_rx, terminal_action_tx);
+            let _ = stopped_tx.send(());
+        });
+        TwitchClient {
+            twitch_action_tx,
+            stopped_rx,
+        }
+    }
+
+    /// For sending actions to the client
+    pub fn sender(&self) -> Sender<TwitchAction> {
+        self.twitch_action_tx.clone()
+    }
+
+    /// Stops the client once the app has dropped its senders too, giving it a moment to say
+    /// goodbye to the server. A client that's stuck connecting is left behind.
+    pub fn shutdown(self) {
+        drop(self.twitch_action_tx);
+        let _ = self.stopped_rx.recv_timeout(SHUTDOWN_TIMEOUT);
+    }
 }
 
 enum TwitchLoginResult {
-    Success,
+    Success(CapNegotiation),
     Fail,
     Timeout,
 }
 
-enum RawStreamAction {
-    Receive(String),
-    EndOfStream,
+// Outcome of a CAP REQ. Twitch answers a single request with either an ACK or a NAK for the
+// whole list, but we track both sides in case the requests get split up.
+#[derive(Default)]
+struct CapNegotiation {
+    granted: Vec<String>,
+    denied: Vec<String>,
+}
+
+impl CapNegotiation {
+    fn is_settled(&self, requested: &[String]) -> bool {
+        requested
+            .iter()
+            .all(|cap| self.granted.contains(cap) || self.denied.contains(cap))
+    }
+}
+
+type IrcReader = BufReader<Box<dyn Read + Send>>;
+type IrcWriter = BufWriter<Box<dyn Write + Send>>;
+
+// An open connection to the IRC server, whatever the transport. The reader half goes to the reader
+// thread and the writer half stays with the session; the socket is kept around to shut both down.
+struct Connection {
+    reader: Box<dyn Read + Send>,
+    writer: Box<dyn Write + Send>,
+    socket: TcpStream,
+}
+
+impl Connection {
+    fn open(irc_addr: &str, transport: &Transport) -> io::Result<Self> {
+        match transport {
+            Transport::Tcp => {
+                let socket = TcpStream::connect(irc_addr)?;
+                Ok(Connection {
+                    reader: Box::new(socket.try_clone()?),
+                    writer: Box::new(socket.try_clone()?),
+                    socket,
+                })
+            }
+            Transport::Tls { ca_file } => {
+                let socket = TcpStream::connect(irc_addr)?;
+                let (reader, writer) = tls_halves(&socket, host_of(irc_addr), ca_file)?;
+                Ok(Connection {
+                    reader,
+                    writer,
+                    socket,
+                })
+            }
+            Transport::WebSocket { ca_file } => {
+                let url = WebSocketUrl::parse(irc_addr)?;
+                let socket = TcpStream::connect((url.host.as_str(), url.port))?;
+                let (reader, writer): (Box<dyn Read + Send>, Box<dyn Write + Send>) = if url.secure
+                {
+                    tls_halves(&socket, &url.host, ca_file)?
+                } else {
+                    (Box::new(socket.try_clone()?), Box::new(socket.try_clone()?))
+                };
+                let (reader, writer) = websocket::connect(reader, writer, &url)?;
+                Ok(Connection {
+                    reader: Box::new(reader),
+                    writer: Box::new(writer),
+                    socket,
+                })
+            }
+        }
+    }
+}
+
+#[cfg(feature = "tls")]
+fn tls_halves(
+    socket: &TcpStream,
+    hostname: &str,
+    ca_file: &Option<PathBuf>,
+) -> io::Result<(Box<dyn Read + Send>, Box<dyn Write + Send>)> {
+    let stream = TlsStream::connect(socket.try_clone()?, hostname, ca_file.as_deref())?;
+    Ok((Box::new(stream.clone()), Box::new(stream)))
+}
+
+#[cfg(not(feature = "tls"))]
+fn tls_halves(
+    _socket: &TcpStream,
+    _hostname: &str,
+    _ca_file: &Option<PathBuf>,
+) -> io::Result<(Box<dyn Read + Send>, Box<dyn Write + Send>)> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "tuisen was built without TLS support",
+    ))
+}
+
+// The host part of a "host:port" address, which is what the server certificate has to match
+fn host_of(addr: &str) -> &str {
+    let host = addr.rsplit_once(':').map_or(addr, |(host, _)| host);
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+// Where the client reports to the app. Once the app drops its receiver nobody is listening, which
+// ends the client just like the app dropping its sender; until the loops notice, what we report
+// goes nowhere.
+struct TerminalActionTx {
+    tx: Sender<TerminalAction>,
+    closed: Cell<bool>,
+}
+
+impl TerminalActionTx {
+    fn new(tx: Sender<TerminalAction>) -> Self {
+        TerminalActionTx {
+            tx,
+            closed: Cell::new(false),
+        }
+    }
+
+    fn send(&self, action: TerminalAction) {
+        if self.tx.send(action).is_err() {
+            self.closed.set(true);
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        self.closed.get()
+    }
+}
+
+// Everything the client loop waits on. Lines come from the reader thread of the session they're
+// tagged with, and the ones from an earlier session are left over from its shutdown.
+enum ClientEvent {
+    Received {
+        session: u64,
+        line: String,
+    },
+    // The connection was closed, or reading from it failed
+    EndOfStream {
+        session: u64,
+        error: Option<io::Error>,
+    },
+    Action(TwitchAction),
+    // The app went away
+    AppClosed,
 }
 
 fn try_login(
-    raw_rx: &Receiver<RawStreamAction>,
-    writer: &mut BufWriter<TcpStream>,
-    pass: &String,
-    nick: &String,
+    events: &EventQueue<ClientEvent>,
+    session: u64,
+    pending: &mut VecDeque<ClientEvent>,
+    writer: &mut IrcWriter,
+    pass: &str,
+    nick: &str,
+    capabilities: &[String],
+) -> io::Result<TwitchLoginResult> {
+    // Request capabilities before registering, so that the server ACKs them right away
+    if !capabilities.is_empty() {
+        send_message(writer, &TwitchIrcMessage::cap_req(capabilities))?;
+    }
+    send_message(writer, &TwitchIrcMessage::pass(pass))?;
+    send_message(writer, &TwitchIrcMessage::nick(nick))?;
+
+    // Anything that isn't part of the login is held until after it, in the order it came in
+    let mut held = VecDeque::new();
+    let result = wait_for_welcome(events, session, pending, &mut held, capabilities);
+    while let Some(event) = held.pop_back() {
+        pending.push_front(event);
+    }
+    Ok(result)
+}
+
+// Waits until we get both the welcome message (001) and an answer for every requested cap
+fn wait_for_welcome(
+    events: &EventQueue<ClientEvent>,
+    session: u64,
+    pending: &mut VecDeque<ClientEvent>,
+    held: &mut VecDeque<ClientEvent>,
+    capabilities: &[String],
 ) -> TwitchLoginResult {
-    writer
-        .write(format!("PASS {}\r\n", pass).as_bytes())
-        .unwrap();
-    writer
-        .write(format!("NICK {}\r\n", nick).as_bytes())
-        .unwrap();
-    writer.flush().unwrap();
-
-    if let Ok(raw_action) = raw_rx.recv_timeout(Duration::from_secs(LOGIN_TIMEOUT_SECONDS.into())) {
-        match raw_action {
-            RawStreamAction::Receive(raw) => RawIrcMessage::try_from(raw)
-                .ok()
-                .and_then(|irc_message| TwitchIrcMessage::try_from(irc_message).ok())
-                .and_then(|twitch_irc_message| {
-                    if let TwitchIrcCommand::Numeric { command: 1, .. } = twitch_irc_message.command
-                    {
-                        Some(TwitchLoginResult::Success)
-                    } else {
-                        None
-                    }
-                })
-                .map_or_else(|| TwitchLoginResult::Fail, |res| res),
-            RawStreamAction::EndOfStream => TwitchLoginResult::Fail,
+    let deadline = Instant::now() + Duration::from_secs(LOGIN_TIMEOUT_SECONDS.into());
+    let mut negotiation = CapNegotiation::default();
+    let mut welcomed = false;
+    while !welcomed || !negotiation.is_settled(capabilities) {
+        let raw = match pending.pop_front() {
+            Some(ClientEvent::Received {
+                session: from,
+                line,
+            }) if from == session => line,
+            Some(ClientEvent::EndOfStream { session: from, .. }) if from == session => {
+                return TwitchLoginResult::Fail
+            }
+            Some(ClientEvent::Received { .. } | ClientEvent::EndOfStream { .. }) => continue,
+            Some(event) => {
+                held.push_back(event);
+                continue;
+            }
+            None => {
+                let events = events.wait(Some(deadline));
+                match events.is_empty() {
+                    // If we got welcomed but the caps were never answered, treat them as denied
+                    true if welcomed => break,
+                    true => return TwitchLoginResult::Timeout,
+                    false => pending.extend(events),
+                }
+                continue;
+            }
+        };
+        let command = RawIrcMessage::try_from(raw.as_str())
+            .ok()
+            .and_then(|irc_message| TwitchIrcMessage::try_from(irc_message).ok())
+            .map(|twitch_irc_message| twitch_irc_message.command);
+        match command {
+            Some(TwitchIrcCommand::Numeric { command: 1, .. }) => welcomed = true,
+            Some(TwitchIrcCommand::Cap {
+                subcommand,
+                capabilities: caps,
+                ..
+            }) => match subcommand.as_str() {
+                "ACK" => negotiation.granted.extend(caps),
+                "NAK" => negotiation.denied.extend(caps),
+                _ => {}
+            },
+            // The rest of the welcome burst (002-004, MOTD, GLOBALUSERSTATE)
+            _ if welcomed => {}
+            _ => return TwitchLoginResult::Fail,
         }
-    } else {
-        TwitchLoginResult::Timeout
     }
+
+    negotiation.denied.extend(
+        capabilities
+            .iter()
+            .filter(|cap| !negotiation.granted.contains(cap) && !negotiation.denied.contains(cap))
+            .cloned()
+            .collect::<Vec<String>>(),
+    );
+    TwitchLoginResult::Success(negotiation)
 }
 
-// TODO: Handle NOTICE, the missing numeric commands, and other commands if we add capabilities
+// TODO: Handle the missing numeric commands
 fn handle_message(
-    writer: &mut BufWriter<TcpStream>,
-    terminal_action_tx: &Sender<TerminalAction>,
+    writer: &mut IrcWriter,
+    outbox: &mut Outbox,
+    terminal_action_tx: &TerminalActionTx,
     message: TwitchIrcMessage,
+    nick: &str,
     bot_mode: &BotMode,
     default_raw: &String,
 ) -> io::Result<()> {
     match message.command {
         TwitchIrcCommand::Ping { ref content } => {
-            // Print the ping
-            terminal_action_tx
-                .send(TerminalAction::PrintPing(content.to_string()))
-                .unwrap();
-            // Answer the ping
-            writer.write(format!("PONG :{}\r\n", content).as_bytes())?;
-            writer.flush()?;
+            send_message(writer, &TwitchIrcMessage::pong(content))?;
         }
         TwitchIrcCommand::Privmsg {
             ref channel,
             ref sender,
             ref content,
         } => {
-            // Print the privmsg
-            terminal_action_tx
-                .send(TerminalAction::PrintPrivmsg {
-                    channel: channel.to_string(),
-                    username: sender.to_string(),
-                    message: content.to_string(),
-                })
-                .unwrap();
+            // Print the privmsg, preferring the display name (proper capitalization) if we got one
+            let username = message.display_name().unwrap_or(sender);
+            terminal_action_tx.send(TerminalAction::PrintPrivmsg {
+                channel: channel.to_string(),
+                username: username.to_string(),
+                message: content.to_string(),
+                emotes: message.emote_codes(),
+            });
 
             // Check for bot commands
             // TODO: Document this, or remove it, or make it configurable somehow
             if let BotMode::WithPrefix(bot_command_prefix) = bot_mode {
                 if let Some(raw_bot_command) = content.strip_prefix(bot_command_prefix) {
-                    if let Some(echo_arg) = raw_bot_command.strip_prefix("echo ") {
+                    // Replies go through the outbox like everything else, so a busy chat can't get
+                    // us throttled
+                    let reply = if let Some(echo_arg) = raw_bot_command.strip_prefix("echo ") {
                         // Echo some text
-                        writer.write(
-                            format!("PRIVMSG #{} :SingsMic {}\r\n", channel, echo_arg).as_bytes(),
-                        )?;
-                        writer.flush()?;
+                        Some(format!("SingsMic {}", echo_arg))
                     } else if raw_bot_command.starts_with("ping") {
                         // Answer a ping
-                        writer.write(
-                            format!("PRIVMSG #{} :pong FutureMan\r\n", channel).as_bytes(),
-                        )?;
-                        writer.flush()?;
+                        Some("pong FutureMan".to_owned())
                     } else if raw_bot_command == "raid" {
                         // Type +join, for DeepDarkDungeonBot raids
-                        writer.write(format!("PRIVMSG #{} :+join\r\n", channel).as_bytes())?;
-                        writer.flush()?;
+                        Some("+join".to_owned())
+                    } else {
+                        None
+                    };
+                    if let Some(reply) = reply {
+                        let privmsg = QueuedPrivmsg {
+                            id: None,
+                            channel: channel.clone(),
+                            message: reply,
+                        };
+                        if outbox.push_privmsg(privmsg).is_err() {
+                            terminal_action_tx.send(TerminalAction::PrintDebug(format!(
+                                "[client] Dropped a bot reply to #{} (too many queued messages).",
+                                channel
+                            )));
+                        }
                     }
                 }
             }
         }
+        // With the membership capability we hear about everyone, but only our own joins and
+        // parts are worth reporting
         TwitchIrcCommand::Join { joiner, channel } => {
-            terminal_action_tx
-                .send(TerminalAction::PrintDebug(format!(
-                    "[client] {} joined #{}.",
-                    joiner, channel
-                )))
-                .unwrap();
-        }
-        TwitchIrcCommand::Numeric { ref command, .. } => match command {
+            if joiner.eq_ignore_ascii_case(nick) {
+                terminal_action_tx.send(TerminalAction::Joined {
+                    channel: channel.clone(),
+                    username: joiner.clone(),
+                });
+            }
+            terminal_action_tx.send(TerminalAction::AddChatters {
+                channel,
+                usernames: vec![joiner],
+            });
+        }
+        TwitchIrcCommand::Part { parter, channel } => {
+            if parter.eq_ignore_ascii_case(nick) {
+                terminal_action_tx.send(TerminalAction::Parted {
+                    channel: channel.clone(),
+                    username: parter.clone(),
+                });
+            }
+            terminal_action_tx.send(TerminalAction::RemoveChatter {
+                channel,
+                username: parter,
+            });
+        }
+        TwitchIrcCommand::Notice {
+            channel, content, ..
+        } => {
+            terminal_action_tx.send(TerminalAction::PrintNotice {
+                channel,
+                message: content,
+            });
+        }
+        TwitchIrcCommand::UserNotice {
+            channel,
+            kind,
+            login,
+            system_message,
+            content,
+        } => {
+            terminal_action_tx.send(TerminalAction::PrintUserNotice {
+                channel,
+                kind,
+                username: login,
+                system_message,
+                message: content,
+            });
+        }
+        TwitchIrcCommand::ClearChat {
+            channel,
+            target,
+            ban_duration,
+        } => {
+            terminal_action_tx.send(TerminalAction::ClearChat {
+                channel,
+                username: target,
+                ban_duration,
+            });
+        }
+        TwitchIrcCommand::ClearMsg {
+            channel,
+            login,
+            content,
+            ..
+        } => {
+            terminal_action_tx.send(TerminalAction::ClearMessage {
+                channel,
+                username: login,
+                message: content,
+            });
+        }
+        TwitchIrcCommand::RoomState {
+            channel,
+            emote_only,
+            followers_only,
+            r9k,
+            slow,
+            subs_only,
+        } => {
+            terminal_action_tx.send(TerminalAction::UpdateRoomModes {
+                channel,
+                modes: RoomModes {
+                    emote_only,
+                    followers_only,
+                    r9k,
+                    slow,
+                    subs_only,
+                },
+            });
+        }
+        // Moderators, VIPs and broadcasters get a higher message rate limit in their channel
+        TwitchIrcCommand::UserState { ref channel, .. } => {
+            outbox.set_elevated(
+                channel,
+                message.is_mod() || message.is_vip() || message.is_broadcaster(),
+            );
+        }
+        // This describes our own user; there is nothing to print for it
+        TwitchIrcCommand::GlobalUserState { .. } => {}
+        TwitchIrcCommand::Whisper {
+            ref sender,
+            ref content,
+            ..
+        } => {
+            let username = message.display_name().unwrap_or(sender);
+            terminal_action_tx.send(TerminalAction::PrintWhisper {
+                username: username.to_string(),
+                message: content.to_string(),
+            });
+        }
+        TwitchIrcCommand::HostTarget {
+            channel, target, ..
+        } => {
+            terminal_action_tx.send(TerminalAction::SetHostTarget { channel, target });
+        }
+        // Handled by the session loop, which cycles the connection
+        TwitchIrcCommand::Reconnect => {}
+        TwitchIrcCommand::Numeric {
+            ref command,
+            ref params,
+        } => match command {
             // Welcome messages after 001; we ignore them
             2 | 3 | 4 | 375 | 372 | 376 => {}
-            // Join list messages; we ignore them for now. TODO: do we want to build a names list
-            // with these?
-            353 | 366 => {}
+            // Names list: "<nick> = #<channel> :<name> <name>...", for completion
+            353 => {
+                if let [_, _, channel, names] = &params[..] {
+                    terminal_action_tx.send(TerminalAction::AddChatters {
+                        channel: channel.trim_start_matches('#').to_owned(),
+                        usernames: names.split_whitespace().map(|n| n.to_owned()).collect(),
+                    });
+                }
+            }
+            // End of the names list
+            366 => {}
             // TODO: are there any others? Maybe 421 for unsupported IRC commands?
             _ => {
                 terminal_action_tx
-                    .send(TerminalAction::PrintDebug(format!("[raw] {}", default_raw)))
-                    .unwrap();
+                    .send(TerminalAction::PrintDebug(format!("[raw] {}", default_raw)));
             }
         },
         _ => {
-            terminal_action_tx
-                .send(TerminalAction::PrintDebug(format!("[raw] {}", default_raw)))
-                .unwrap();
+            terminal_action_tx.send(TerminalAction::PrintDebug(format!("[raw] {}", default_raw)));
         }
     };
     Ok(())
 }
 
-// Main entrypoint for client, should be called from a spawned thread
-pub fn connect_and_listen(
+// How a connection ended, as seen by the supervisor loop in connect_and_listen
+enum SessionEnd {
+    // The app went away, so there is nothing left to do
+    Quit,
+    Disconnected(DisconnectReason),
+}
+
+// Capped exponential backoff with jitter between reconnection attempts. Each delay is drawn from
+// the upper half of the current window, so clients that dropped together don't all come back at
+// the same moment.
+struct Backoff {
+    attempt: u32,
+}
+
+impl Backoff {
+    fn new() -> Self {
+        Backoff { attempt: 0 }
+    }
+
+    fn reset(&mut self) {
+        self.attempt = 0;
+    }
+
+    fn next_delay(&mut self) -> Duration {
+        let window_ms = (BACKOFF_BASE_MS << self.attempt.min(16)).min(BACKOFF_CAP_MS);
+        self.attempt += 1;
+        let half_ms = window_ms / 2;
+        Duration::from_millis(half_ms + random_u64() % (half_ms + 1))
+    }
+}
+
+// Notices a connection that died without being closed, which would otherwise leave us waiting
+// for lines forever, and measures the latency along the way. We ping the server every so often,
+// and if it doesn't say anything after that, the connection is gone. On a busy channel the PONG
+// can lag behind the chat, so any line counts as a sign of life.
+struct Keepalive {
+    // When we sent the ping we're waiting on a PONG for
+    ping_sent: Option<Instant>,
+    heard_back: bool,
+    next_ping: Instant,
+}
+
+impl Keepalive {
+    // The first ping goes out right away, so we know the latency from the start
+    fn new() -> Self {
+        Keepalive {
+            ping_sent: None,
+            heard_back: true,
+            next_ping: Instant::now(),
+        }
+    }
+
+    fn received(&mut self) {
+        self.heard_back = true;
+    }
+
+    fn ping_sent(&mut self) {
+        let now = Instant::now();
+        self.ping_sent = Some(now);
+        self.heard_back = false;
+        self.next_ping = now + KEEPALIVE_INTERVAL;
+    }
+
+    // Returns the latency, if we were waiting on a PONG
+    fn pong_received(&mut self) -> Option<Duration> {
+        self.ping_sent.take().map(|sent| sent.elapsed())
+    }
+
+    // When to give up on the server if we're waiting to hear from it, or else when to ping it
+    fn deadline(&self) -> Instant {
+        match self.ping_sent {
+            Some(sent) if !self.heard_back => sent + PONG_TIMEOUT,
+            _ => self.next_ping,
+        }
+    }
+}
+
+// Whether the app went away: it dropped its receiver, or it closed during a session that ended
+// before getting to the AppClosed, which is then still pending (e.g. after a failed login)
+fn app_gone(terminal_action_tx: &TerminalActionTx, pending: &VecDeque<ClientEvent>) -> bool {
+    terminal_action_tx.is_closed()
+        || pending
+            .iter()
+            .any(|event| matches!(event, ClientEvent::AppClosed))
+}
+
+// Main entrypoint for client, run on its own thread by TwitchClient::spawn. Keeps the connection
+// alive, reconnecting with backoff whenever it drops, until the app goes away.
+fn connect_and_listen(
     client_config: TwitchClientConfig,
     twitch_action_rx: Receiver<TwitchAction>,
     terminal_action_tx: Sender<TerminalAction>,
 ) -> io::Result<()> {
-    terminal_action_tx
-        .send(TerminalAction::PrintDebug(
-            "[client] Attempting to connect to twitch...".to_string(),
-        ))
-        .unwrap();
+    let terminal_action_tx = TerminalActionTx::new(terminal_action_tx);
+    if let TwitchLogin::Anonymous = client_config.login {
+        terminal_action_tx.send(TerminalAction::PrintDebug(
+            "[client] Login info not specified -- will auth as anonymous user".to_owned(),
+        ));
+    }
 
-    let stream = TcpStream::connect(client_config.irc_addr)?;
+    // Channels to join on every (re)connection. Joins and parts from the app update this list, so
+    // a reconnection puts us back in the same channels.
+    let mut channels = client_config.channels.clone();
+    let mut backoff = Backoff::new();
+    // Rate limits apply to the account rather than the connection, so this outlives sessions
+    let mut outbox = Outbox::new();
 
-    terminal_action_tx
-        .send(TerminalAction::PrintDebug(
-            "[client] Connected to twitch!".to_string(),
-        ))
-        .unwrap();
+    let events = EventQueue::new();
+    events.forward(
+        twitch_action_rx,
+        ClientEvent::Action,
+        ClientEvent::AppClosed,
+    );
+    // Events we've taken off the queue but haven't handled yet. Actions that come in while we're
+    // disconnected or logging in wait here for the next session.
+    let mut pending = VecDeque::new();
+    let mut session = 0;
+
+    loop {
+        if app_gone(&terminal_action_tx, &pending) {
+            return Ok(());
+        }
+        session += 1;
+        let end = run_session(
+            &client_config,
+            session,
+            &mut channels,
+            &mut outbox,
+            &mut backoff,
+            &events,
+            &mut pending,
+            &terminal_action_tx,
+        );
+        // No point in waiting to reconnect if nobody's left to reconnect for
+        if app_gone(&terminal_action_tx, &pending) {
+            return Ok(());
+        }
+        let reason = match end {
+            Ok(SessionEnd::Quit) => return Ok(()),
+            Ok(SessionEnd::Disconnected(reason)) => reason,
+            Err(e) => DisconnectReason::Error(e.to_string()),
+        };
+
+        // Twitch asks us to reconnect before restarting a server, so there is no point in waiting
+        let delay = match reason {
+            DisconnectReason::ServerRequested => {
+                backoff.reset();
+                Duration::ZERO
+            }
+            _ => backoff.next_delay(),
+        };
+        terminal_action_tx.send(TerminalAction::SetConnectionStatus(
+            ConnectionStatus::Disconnected(reason),
+        ));
+        terminal_action_tx.send(TerminalAction::SetConnectionStatus(
+            ConnectionStatus::Reconnecting {
+                attempt: backoff.attempt,
+                delay,
+            },
+        ));
+
+        // Wait out the delay, unless the app goes away in the meantime
+        let deadline = Instant::now() + delay;
+        while Instant::now() < deadline {
+            for event in events.wait(Some(deadline)) {
+                match event {
+                    ClientEvent::Action(_) => pending.push_back(event),
+                    ClientEvent::AppClosed => return Ok(()),
+                    ClientEvent::Received { .. } | ClientEvent::EndOfStream { .. } => {}
+                }
+            }
+        }
+    }
+}
+
+// Runs a single connection: connect, log in, join channels, then pump messages both ways until the
+// connection drops
+#[allow(clippy::too_many_arguments)]
+fn run_session(
+    client_config: &TwitchClientConfig,
+    session: u64,
+    channels: &mut Vec<String>,
+    outbox: &mut Outbox,
+    backoff: &mut Backoff,
+    events: &EventQueue<ClientEvent>,
+    pending: &mut VecDeque<ClientEvent>,
+    terminal_action_tx: &TerminalActionTx,
+) -> io::Result<SessionEnd> {
+    terminal_action_tx.send(TerminalAction::SetConnectionStatus(
+        ConnectionStatus::Connecting,
+    ));
+
+    let connection = Connection::open(&client_config.irc_addr, &client_config.transport)?;
+
+    let reader = BufReader::new(connection.reader);
+    let mut writer = BufWriter::new(connection.writer);
 
-    let reader = BufReader::new(stream.try_clone()?);
-    let mut writer = BufWriter::new(stream);
+    let event_tx = events.sender();
+    let _reader_handle = thread::spawn(move || read_raw(reader, session, event_tx));
 
-    let (raw_tx, raw_rx) = mpsc::channel::<RawStreamAction>();
-    let _reader_handle = thread::spawn(move || read_raw(reader, raw_tx));
+    // Make sure the reader thread stops whenever we leave this session
+    let _shutdown_guard = ShutdownOnDrop(connection.socket);
 
     let (nick, pass) = if let TwitchLogin::Auth {
         ref username,
         ref token,
     } = client_config.login
     {
-        terminal_action_tx
-            .send(TerminalAction::PrintDebug(format!(
-                "[client] Attempting to auth as \"{}\"...",
-                username
-            )))
-            .unwrap();
         (username.clone(), format!("oauth:{}", token))
     } else {
-        terminal_action_tx
-            .send(TerminalAction::PrintDebug(
-                "[client] Login info not specified -- will auth as anonymous user".to_owned(),
-            ))
-            .unwrap();
         ("justinfan1337".to_owned(), "forsenCD".to_owned())
     };
 
-    // Confirm successful auth (or retry) before sending JOIN
-    loop {
-        match try_login(&raw_rx, &mut writer, &pass, &nick) {
-            TwitchLoginResult::Success => {
-                break;
-            }
-            TwitchLoginResult::Fail => {
-                terminal_action_tx
-                    .send(TerminalAction::PrintDebug(format!(
-                        "Auth failed. Retrying in {} seconds...",
-                        LOGIN_RETRY_SECONDS
-                    )))
-                    .unwrap();
+    terminal_action_tx.send(TerminalAction::SetConnectionStatus(
+        ConnectionStatus::Authenticating {
+            username: nick.clone(),
+        },
+    ));
+
+    // Confirm successful auth before sending JOIN. Twitch drops the connection after a failed
+    // login, so a failure ends the session and we retry with a fresh one. So does losing the
+    // connection while we're still logging in.
+    match try_login(
+        events,
+        session,
+        pending,
+        &mut writer,
+        &pass,
+        &nick,
+        &client_config.capabilities,
+    )? {
+        TwitchLoginResult::Success(negotiation) => {
+            if !negotiation.granted.is_empty() {
+                terminal_action_tx.send(TerminalAction::PrintDebug(format!(
+                    "[client] Capabilities granted: {}",
+                    negotiation.granted.join(", ")
+                )));
             }
-            TwitchLoginResult::Timeout => {
-                terminal_action_tx
-                    .send(TerminalAction::PrintDebug(format!(
-                        "Auth timed out. Retrying in {} seconds...",
-                        LOGIN_RETRY_SECONDS
-                    )))
-                    .unwrap();
+            if !negotiation.denied.is_empty() {
+                terminal_action_tx.send(TerminalAction::PrintDebug(format!(
+                    "[client] Capabilities denied: {}",
+                    negotiation.denied.join(", ")
+                )));
             }
         }
-        thread::sleep(Duration::from_secs(LOGIN_RETRY_SECONDS.into()));
+        TwitchLoginResult::Fail => {
+            return Ok(SessionEnd::Disconnected(DisconnectReason::AuthFailed));
+        }
+        TwitchLoginResult::Timeout => {
+            return Ok(SessionEnd::Disconnected(DisconnectReason::AuthTimedOut));
+        }
     }
 
-    terminal_action_tx
-        .send(TerminalAction::PrintDebug(format!(
-            "[client] Auth successful! Connecting to channel #{}...",
-            client_config.channel
-        )))
-        .unwrap();
+    // We're in, so the next drop starts backing off from scratch
+    backoff.reset();
+
+    outbox.clear_joins();
+    for channel in channels.iter() {
+        outbox.push_join(channel.clone());
+    }
 
-    writer.write(format!("JOIN #{}\r\n", client_config.channel).as_bytes())?;
-    writer.flush()?;
+    terminal_action_tx.send(TerminalAction::SetConnectionStatus(
+        ConnectionStatus::Connected {
+            channels: channels.to_vec(),
+        },
+    ));
 
-    terminal_action_tx
-        .send(TerminalAction::PrintDebug(
-            "[client] Listening to messages now.".to_string(),
-        ))
-        .unwrap();
+    let mut keepalive = Keepalive::new();
 
     loop {
-        // Poll stream reader
-        if let Ok(raw_action) = raw_rx.try_recv() {
-            match raw_action {
-                RawStreamAction::Receive(raw) => match RawIrcMessage::try_from(raw.clone()) {
-                    Ok(irc_message) => {
-                        match TwitchIrcMessage::try_from(irc_message) {
-                            Ok(twitch_irc_message) => {
-                                handle_message(
-                                    &mut writer,
-                                    &terminal_action_tx,
-                                    twitch_irc_message,
-                                    &client_config.bot_mode,
-                                    &raw,
-                                )?;
-                            }
-                            Err(twitch_irc_parse_error) => {
-                                terminal_action_tx
-                                        .send(TerminalAction::PrintDebug(format!(
-                                            "[error] Encountered {:?} while parsing this message: \"{}\"",
-                                            twitch_irc_parse_error, &raw
-                                        )))
-                                        .unwrap();
-                            }
-                        };
-                    }
-                    Err(irc_parse_error) => {
-                        terminal_action_tx
-                            .send(TerminalAction::PrintDebug(format!(
-                                "[error] Encountered {:?} while parsing this message: \"{}\"",
-                                irc_parse_error, &raw
-                            )))
-                            .unwrap();
+        // Like the app closing, minus the chance to tell it anything
+        if terminal_action_tx.is_closed() {
+            send_raw(&mut writer, "QUIT")?;
+            return Ok(SessionEnd::Quit);
+        }
+        // Handle everything that's come in, then send whatever the rate limits allow. If nothing
+        // has, we sleep until something does, until the rate limits let the next message go, or
+        // until it's time to check on the connection.
+        if pending.is_empty() {
+            let deadline = outbox
+                .next_ready(Instant::now())
+                .map_or(keepalive.deadline(), |ready| {
+                    ready.min(keepalive.deadline())
+                });
+            pending.extend(events.wait(Some(deadline)));
+        }
+        while let Some(event) = pending.pop_front() {
+            match event {
+                ClientEvent::Received {
+                    session: from,
+                    line,
+                } if from == session => {
+                    keepalive.received();
+                    if let Some(end) = handle_line(
+                        &mut writer,
+                        outbox,
+                        &mut keepalive,
+                        terminal_action_tx,
+                        &nick,
+                        &client_config.bot_mode,
+                        line,
+                    )? {
+                        return Ok(end);
                     }
-                },
-                RawStreamAction::EndOfStream => {
-                    break;
                 }
+                ClientEvent::EndOfStream {
+                    session: from,
+                    error,
+                } if from == session => {
+                    let reason = match error {
+                        None => DisconnectReason::ConnectionClosed,
+                        Some(e) => DisconnectReason::Error(e.to_string()),
+                    };
+                    return Ok(SessionEnd::Disconnected(reason));
+                }
+                ClientEvent::Received { .. } | ClientEvent::EndOfStream { .. } => {}
+                ClientEvent::Action(action) => handle_action(
+                    &mut writer,
+                    channels,
+                    outbox,
+                    terminal_action_tx,
+                    client_config,
+                    action,
+                )?,
+                ClientEvent::AppClosed => {
+                    // Messages still waiting on the rate limit are lost, but the server should
+                    // hear that we're leaving
+                    send_raw(&mut writer, "QUIT")?;
+                    return Ok(SessionEnd::Quit);
+                }
+            }
+            // Flushing as we go keeps the queue for what actually has to wait
+            flush_outbox(&mut writer, outbox, terminal_action_tx)?;
+        }
+        // Only now, so that lines which came in before the deadline still count
+        if Instant::now() >= keepalive.deadline() {
+            if !keepalive.heard_back {
+                return Ok(SessionEnd::Disconnected(DisconnectReason::TimedOut));
             }
+            send_message(&mut writer, &TwitchIrcMessage::ping(KEEPALIVE_PING))?;
+            keepalive.ping_sent();
         }
+        flush_outbox(&mut writer, outbox, terminal_action_tx)?;
+    }
+}
 
-        // Poll twitch actions
-        if let Ok(twitch_action) = twitch_action_rx.try_recv() {
-            match twitch_action {
-                TwitchAction::SendPrivmsg { message } => {
-                    // Ignore this action if the current login is anonymous
-                    if let TwitchLogin::Auth { ref username, .. } = client_config.login {
-                        writer
-                            .write(
-                                format!("PRIVMSG #{} :{}\r\n", client_config.channel, message)
-                                    .as_bytes(),
-                            )
-                            .unwrap();
-                        writer.flush().unwrap();
-                        terminal_action_tx
-                            .send(TerminalAction::PrintPrivmsg {
-                                channel: client_config.channel.clone(),
-                                username: username.clone(),
-                                message,
-                            })
-                            .unwrap();
+// Handles a line from the server. Returns how the session ends, if the line ends it.
+fn handle_line(
+    writer: &mut IrcWriter,
+    outbox: &mut Outbox,
+    keepalive: &mut Keepalive,
+    terminal_action_tx: &TerminalActionTx,
+    nick: &str,
+    bot_mode: &BotMode,
+    raw: String,
+) -> io::Result<Option<SessionEnd>> {
+    match RawIrcMessage::try_from(raw.as_str()) {
+        Ok(irc_message) => {
+            match TwitchIrcMessage::try_from(irc_message) {
+                // Cycle the connection when the server asks us to
+                Ok(TwitchIrcMessage {
+                    command: TwitchIrcCommand::Reconnect,
+                    ..
+                }) => {
+                    return Ok(Some(SessionEnd::Disconnected(
+                        DisconnectReason::ServerRequested,
+                    )));
+                }
+                // The answer to our keepalive ping
+                Ok(TwitchIrcMessage {
+                    command: TwitchIrcCommand::Pong { ref content },
+                    ..
+                }) if content == KEEPALIVE_PING => {
+                    if let Some(latency) = keepalive.pong_received() {
+                        terminal_action_tx.send(TerminalAction::SetLatency(latency));
                     }
                 }
-                _ => {}
-            }
+                Ok(twitch_irc_message) => {
+                    handle_message(
+                        writer,
+                        outbox,
+                        terminal_action_tx,
+                        twitch_irc_message,
+                        nick,
+                        bot_mode,
+                        &raw,
+                    )?;
+                }
+                Err(twitch_irc_parse_error) => {
+                    terminal_action_tx.send(TerminalAction::PrintDebug(format!(
+                        "[error] Encountered {:?} while parsing this message: \"{}\"",
+                        twitch_irc_parse_error, &raw
+                    )));
+                }
+            };
         }
+        Err(irc_parse_error) => {
+            terminal_action_tx.send(TerminalAction::PrintDebug(format!(
+                "[error] Encountered {:?} while parsing this message: \"{}\"",
+                irc_parse_error, &raw
+            )));
+        }
+    }
+    Ok(None)
+}
 
-        // Tick
-        thread::sleep(Duration::from_millis(30))
+fn handle_action(
+    writer: &mut IrcWriter,
+    channels: &mut Vec<String>,
+    outbox: &mut Outbox,
+    terminal_action_tx: &TerminalActionTx,
+    client_config: &TwitchClientConfig,
+    action: TwitchAction,
+) -> io::Result<()> {
+    match action {
+        TwitchAction::Join { channel } => {
+            if !channels.contains(&channel) {
+                channels.push(channel.clone());
+                outbox.push_join(channel);
+            }
+        }
+        TwitchAction::Part { channel } => {
+            if let Some(index) = channels.iter().position(|joined| *joined == channel) {
+                channels.remove(index);
+                // No need to PART a channel we haven't even sent the JOIN for
+                if !outbox.cancel_join(&channel) {
+                    send_message(writer, &TwitchIrcMessage::part(&channel))?;
+                }
+            }
+        }
+        TwitchAction::SendPrivmsg { channel, message } => {
+            // Ignore this action if the current login is anonymous
+            if let TwitchLogin::Auth { ref username, .. } = client_config.login {
+                let id = outbox.new_id();
+                let privmsg = QueuedPrivmsg {
+                    id: Some(id),
+                    channel: channel.clone(),
+                    message: message.clone(),
+                };
+                let state = match outbox.push_privmsg(privmsg) {
+                    Ok(()) => OutgoingState::Queued,
+                    Err(_) => OutgoingState::Dropped,
+                };
+                terminal_action_tx.send(TerminalAction::PrintOutgoing {
+                    id,
+                    channel,
+                    username: username.clone(),
+                    message,
+                    state,
+                });
+            }
+        }
+        TwitchAction::SendRaw(line) => match send_raw(writer, &line) {
+            Ok(()) => {
+                terminal_action_tx.send(TerminalAction::PrintDebug(format!("[raw] > {}", line)));
+            }
+            Err(e) if e.kind() == io::ErrorKind::InvalidInput => {
+                terminal_action_tx.send(TerminalAction::PrintDebug(format!(
+                    "[error] Could not send raw line ({})",
+                    e
+                )));
+            }
+            Err(e) => return Err(e),
+        },
     }
+    Ok(())
+}
 
-    terminal_action_tx
-        .send(TerminalAction::PrintDebug(
-            "[client] Connection closed.".to_string(),
-        ))
-        .unwrap();
+fn flush_outbox(
+    writer: &mut IrcWriter,
+    outbox: &mut Outbox,
+    terminal_action_tx: &TerminalActionTx,
+) -> io::Result<()> {
+    let now = Instant::now();
+    while let Some(channel) = outbox.pop_join(now) {
+        send_message(writer, &TwitchIrcMessage::join(&channel))?;
+    }
 
+    while let Some(privmsg) = outbox.pop_privmsg(now) {
+        let result = send_message(
+            writer,
+            &TwitchIrcMessage::privmsg(&privmsg.channel, &privmsg.message),
+        );
+        let state = match result {
+            Ok(()) => OutgoingState::Sent,
+            // The message itself was bad (e.g. it had a line break in it)
+            Err(ref e) if e.kind() == io::ErrorKind::InvalidInput => {
+                terminal_action_tx.send(TerminalAction::PrintDebug(format!(
+                    "[error] Could not send message ({})",
+                    e
+                )));
+                OutgoingState::Dropped
+            }
+            Err(_) => OutgoingState::Dropped,
+        };
+        if let Some(id) = privmsg.id {
+            terminal_action_tx.send(TerminalAction::SetOutgoingState { id, state });
+        }
+        // Anything else means the connection is gone
+        if let Err(e) = result {
+            if e.kind() != io::ErrorKind::InvalidInput {
+                return Err(e);
+            }
+        }
+    }
     Ok(())
 }
 
-fn read_raw(mut reader: BufReader<TcpStream>, raw_tx: Sender<RawStreamAction>) {
+// Shuts the socket down when dropped, which unblocks the reader thread of a finished session
+struct ShutdownOnDrop(TcpStream);
+
+impl Drop for ShutdownOnDrop {
+    fn drop(&mut self) {
+        let _ = self.0.shutdown(Shutdown::Both);
+    }
+}
+
+// Serializes and writes a single message. Messages that can't be serialized are rejected with
+// ErrorKind::InvalidInput, and nothing is written.
+fn send_message(writer: &mut IrcWriter, message: &TwitchIrcMessage) -> io::Result<()> {
+    let line = message.serialize().map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("could not serialize message: {:?}", e),
+        )
+    })?;
+    send_raw(writer, &line)
+}
+
+// Writes a line as is. A line break inside it would smuggle in another message, so those are
+// rejected with ErrorKind::InvalidInput.
+fn send_raw(writer: &mut IrcWriter, line: &str) -> io::Result<()> {
+    if line.contains(['\r', '\n']) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "line break in a raw line",
+        ));
+    }
+    writer.write_all(line.as_bytes())?;
+    writer.write_all(b"\r\n")?;
+    writer.flush()
+}
+
+fn read_raw(mut reader: IrcReader, session: u64, event_tx: Sender<ClientEvent>) {
     let mut buffer = String::new();
 
-    while let Ok(msize) = reader.read_line(&mut buffer) {
-        if msize == 0 {
-            break;
-        };
-        let raw_message = buffer.replace("\r\n", "");
-        raw_tx.send(RawStreamAction::Receive(raw_message)).unwrap();
+    let error = loop {
+        match reader.read_line(&mut buffer) {
+            Ok(0) => break None,
+            Ok(_) => {}
+            Err(e) => break Some(e),
+        }
+        let line = buffer.replace("\r\n", "");
+        // The client is gone if nobody is listening anymore
+        if event_tx
+            .send(ClientEvent::Received { session, line })
+            .is_err()
+        {
+            return;
+        }
         buffer.clear();
+    };
+
+    let _ = event_tx.send(ClientEvent::EndOfStream { session, error });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::TcpListener;
+
+    fn auth_config() -> TwitchClientConfig {
+        TwitchClientConfig::new(
+            "irc.chat.twitch.tv:6667".to_owned(),
+            TwitchLogin::Auth {
+                username: "forsen".to_owned(),
+                token: "token".to_owned(),
+            },
+            Vec::new(),
+            BotMode::Off,
+            Vec::new(),
+            Transport::Tcp,
+        )
+    }
+
+    #[test]
+    fn test_full_outbox_reports_dropped() {
+        let mut writer: IrcWriter = BufWriter::new(Box::new(io::sink()));
+        let mut outbox = Outbox::new();
+        let (tx, terminal_action_rx) = mpsc::channel();
+        let terminal_action_tx = TerminalActionTx::new(tx);
+        let config = auth_config();
+
+        for _ in 0..21 {
+            let action = TwitchAction::SendPrivmsg {
+                channel: "forsen".to_owned(),
+                message: "forsenE".to_owned(),
+            };
+            handle_action(
+                &mut writer,
+                &mut Vec::new(),
+                &mut outbox,
+                &terminal_action_tx,
+                &config,
+                action,
+            )
+            .unwrap();
+        }
+
+        let states: Vec<OutgoingState> = terminal_action_rx
+            .try_iter()
+            .map(|action| match action {
+                TerminalAction::PrintOutgoing { state, .. } => state,
+                _ => panic!("expected PrintOutgoing"),
+            })
+            .collect();
+        assert_eq!(states.len(), 21);
+        assert!(states[..20]
+            .iter()
+            .all(|&state| state == OutgoingState::Queued));
+        assert!(states[20] == OutgoingState::Dropped);
+    }
+
+    #[test]
+    fn test_timeout_reported_as_clear_chat() {
+        let mut writer: IrcWriter = BufWriter::new(Box::new(io::sink()));
+        let (tx, terminal_action_rx) = mpsc::channel();
+        let terminal_action_tx = TerminalActionTx::new(tx);
+        let raw = "@ban-duration=600 :tmi.twitch.tv CLEARCHAT #forsen :xqc".to_owned();
+        let message =
+            TwitchIrcMessage::try_from(RawIrcMessage::try_from(raw.as_str()).unwrap()).unwrap();
+
+        handle_message(
+            &mut writer,
+            &mut Outbox::new(),
+            &terminal_action_tx,
+            message,
+            "forsen",
+            &BotMode::Off,
+            &raw,
+        )
+        .unwrap();
+
+        match terminal_action_rx.try_recv() {
+            Ok(TerminalAction::ClearChat {
+                channel,
+                username,
+                ban_duration,
+            }) => {
+                assert_eq!(channel, "forsen");
+                assert_eq!(username.as_deref(), Some("xqc"));
+                assert_eq!(ban_duration, Some(600));
+            }
+            _ => panic!("expected ClearChat"),
+        }
+    }
+
+    #[test]
+    fn test_dropped_receiver_closes() {
+        let mut writer: IrcWriter = BufWriter::new(Box::new(io::sink()));
+        let (tx, terminal_action_rx) = mpsc::channel();
+        let terminal_action_tx = TerminalActionTx::new(tx);
+        drop(terminal_action_rx);
+
+        let raw = ":tmi.twitch.tv NOTICE * :Login unsuccessful".to_owned();
+        let message =
+            TwitchIrcMessage::try_from(RawIrcMessage::try_from(raw.as_str()).unwrap()).unwrap();
+        handle_message(
+            &mut writer,
+            &mut Outbox::new(),
+            &terminal_action_tx,
+            message,
+            "forsen",
+            &BotMode::Off,
+            &raw,
+        )
+        .unwrap();
+        assert!(terminal_action_tx.is_closed());
     }
 
-    raw_tx.send(RawStreamAction::EndOfStream).unwrap();
+    // A client for an anonymous login to a local server, without capabilities. Returns what the
+    // app would hold on to, and a receiver that hears when the client stops.
+    fn local_client(
+        listener: &TcpListener,
+    ) -> (Sender<TwitchAction>, Receiver<TerminalAction>, Receiver<()>) {
+        let config = TwitchClientConfig::new(
+            listener.local_addr().unwrap().to_string(),
+            TwitchLogin::Anonymous,
+            Vec::new(),
+            BotMode::Off,
+            Vec::new(),
+            Transport::Tcp,
+        );
+        let (twitch_action_tx, twitch_action_rx) = mpsc::channel();
+        let (terminal_action_tx, terminal_action_rx) = mpsc::channel();
+        let (stopped_tx, stopped_rx) = mpsc::channel();
+        thread::spawn(move || {
+            let _ = connect_and_listen(config, twitch_action_rx, terminal_action_tx);
+            let _ = stopped_tx.send(());
+        });
+        (twitch_action_tx, terminal_action_rx, stopped_rx)
+    }
+
+    // Accepts the client's next connection and reads up to its NICK, so it's waiting for the
+    // welcome
+    fn accept_login(listener: &TcpListener) -> TcpStream {
+        let (socket, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(socket.try_clone().unwrap());
+        let mut line = String::new();
+        while !line.starts_with("NICK") {
+            line.clear();
+            reader.read_line(&mut line).unwrap();
+        }
+        socket
+    }
+
+    #[test]
+    fn test_quit_during_failed_login() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        // The receiver is kept alive, so only the quit can stop the client
+        let (twitch_action_tx, _terminal_action_rx, stopped_rx) = local_client(&listener);
+
+        let socket = accept_login(&listener);
+        // The app quits while the client waits for the welcome, and then the login fails
+        drop(twitch_action_tx);
+        thread::sleep(Duration::from_millis(100));
+        (&socket)
+            .write_all(b":tmi.twitch.tv NOTICE * :Login authentication failed\r\n")
+            .unwrap();
+        drop(socket);
+
+        assert!(stopped_rx.recv_timeout(Duration::from_secs(5)).is_ok());
+    }
+
+    #[test]
+    fn test_reconnect_on_request() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let (twitch_action_tx, terminal_action_rx, stopped_rx) = local_client(&listener);
+
+        let socket = accept_login(&listener);
+        (&socket)
+            .write_all(b":tmi.twitch.tv 001 justinfan1337 :Welcome, GLHF!\r\n")
+            .unwrap();
+        (&socket)
+            .write_all(b":tmi.twitch.tv RECONNECT\r\n")
+            .unwrap();
+
+        // The client comes straight back on a new connection
+        let socket = accept_login(&listener);
+        let statuses: Vec<ConnectionStatus> = terminal_action_rx
+            .try_iter()
+            .filter_map(|action| match action {
+                TerminalAction::SetConnectionStatus(status) => Some(status),
+                _ => None,
+            })
+            .collect();
+        assert!(statuses.iter().any(|status| matches!(
+            status,
+            ConnectionStatus::Disconnected(DisconnectReason::ServerRequested)
+        )));
+        assert!(statuses.iter().any(|status| matches!(
+            status,
+            ConnectionStatus::Reconnecting { delay, .. } if delay.is_zero()
+        )));
+
+        drop(twitch_action_tx);
+        drop(socket);
+        assert!(stopped_rx.recv_timeout(Duration::from_secs(5)).is_ok());
+    }
 }
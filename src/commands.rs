@@ -0,0 +1,260 @@
+// Slash commands typed in the input box. Parsing only checks the arguments; App decides what each
+// command actually does.
+
+use tuisen::config::normalize_channel;
+
+pub enum Command {
+    Join(String),
+    // None means the current channel
+    Part(Option<String>),
+    Me(String),
+    Whisper { username: String, message: String },
+    Raw(String),
+    Clear,
+    Quit,
+    // None lists the ignored users
+    Ignore(Option<String>),
+    Unignore(String),
+    Help(Option<String>),
+}
+
+#[derive(Debug)]
+pub struct CommandInfo {
+    pub name: &'static str,
+    pub usage: &'static str,
+    pub description: &'static str,
+}
+
+pub const COMMANDS: &[CommandInfo] = &[
+    CommandInfo {
+        name: "join",
+        usage: "/join <channel>",
+        description: "Join a channel and switch to its tab",
+    },
+    CommandInfo {
+        name: "part",
+        usage: "/part [channel]",
+        description: "Leave a channel, or the current one",
+    },
+    CommandInfo {
+        name: "me",
+        usage: "/me <message>",
+        description: "Send an action message to the current channel",
+    },
+    CommandInfo {
+        name: "w",
+        usage: "/w <user> <message>",
+        description: "Whisper to a user",
+    },
+    CommandInfo {
+        name: "raw",
+        usage: "/raw <line>",
+        description: "Send a line to the server as is",
+    },
+    CommandInfo {
+        name: "clear",
+        usage: "/clear",
+        description: "Clear the current tab",
+    },
+    CommandInfo {
+        name: "quit",
+        usage: "/quit",
+        description: "Quit tuisen",
+    },
+    CommandInfo {
+        name: "ignore",
+        usage: "/ignore [user]",
+        description: "Hide messages from a user, or list the hidden users",
+    },
+    CommandInfo {
+        name: "unignore",
+        usage: "/unignore <user>",
+        description: "Show messages from a user again",
+    },
+    CommandInfo {
+        name: "help",
+        usage: "/help [command]",
+        description: "List the commands, or describe one",
+    },
+];
+
+#[derive(Debug)]
+pub enum CommandError {
+    Unknown(String),
+    // The command exists, but its arguments are wrong
+    Usage(&'static CommandInfo),
+    BadChannel(String),
+}
+
+pub fn command_info(name: &str) -> Option<&'static CommandInfo> {
+    let name = name.strip_prefix('/').unwrap_or(name);
+    COMMANDS.iter().find(|info| info.name == name)
+}
+
+// Returns None if the input is a plain chat message rather than a command
+pub fn parse_command(input: &str) -> Option<Result<Command, CommandError>> {
+    let input = input.trim().strip_prefix('/')?;
+    let (name, args) = match input.split_once(char::is_whitespace) {
+        Some((name, args)) => (name, args.trim()),
+        None => (input, ""),
+    };
+    let Some(info) = command_info(name) else {
+        return Some(Err(CommandError::Unknown(name.to_owned())));
+    };
+    let mut words = args.split_whitespace();
+
+    let command = match (info.name, words.next(), words.next()) {
+        ("join", Some(channel), None) => parse_channel(channel).map(Command::Join),
+        ("part", None, _) => Ok(Command::Part(None)),
+        ("part", Some(channel), None) => parse_channel(channel).map(|c| Command::Part(Some(c))),
+        ("me", Some(_), _) => Ok(Command::Me(args.to_owned())),
+        ("w", Some(username), Some(_)) => {
+            let message = args[username.len()..].trim_start();
+            Ok(Command::Whisper {
+                username: normalize_username(username),
+                message: message.to_owned(),
+            })
+        }
+        ("raw", Some(_), _) => Ok(Command::Raw(args.to_owned())),
+        ("clear", None, _) => Ok(Command::Clear),
+        ("quit", None, _) => Ok(Command::Quit),
+        ("ignore", None, _) => Ok(Command::Ignore(None)),
+        ("ignore", Some(username), None) => Ok(Command::Ignore(Some(normalize_username(username)))),
+        ("unignore", Some(username), None) => Ok(Command::Unignore(normalize_username(username))),
+        ("help", None, _) => Ok(Command::Help(None)),
+        ("help", Some(name), None) => Ok(Command::Help(Some(name.to_owned()))),
+        _ => Err(CommandError::Usage(info)),
+    };
+    Some(command)
+}
+
+fn parse_channel(channel: &str) -> Result<String, CommandError> {
+    normalize_channel(channel).ok_or_else(|| CommandError::BadChannel(channel.to_owned()))
+}
+
+// Usernames are case-insensitive, and people like to type them with an @ in front
+fn normalize_username(username: &str) -> String {
+    username.trim_start_matches('@').to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usage_error(input: &str) -> &'static str {
+        match parse_command(input) {
+            Some(Err(CommandError::Usage(info))) => info.name,
+            _ => panic!("expected a usage error for {:?}", input),
+        }
+    }
+
+    #[test]
+    fn test_chat_messages_are_not_commands() {
+        assert!(parse_command("forsenE").is_none());
+        assert!(parse_command("").is_none());
+        // Only a slash at the very start (after whitespace) makes a command
+        assert!(parse_command("a /join forsen").is_none());
+    }
+
+    #[test]
+    fn test_unknown_commands() {
+        // These come back as errors rather than None, so a typo never ends up in chat
+        for (input, unknown) in [("/jion forsen", "jion"), ("/", ""), ("/ join forsen", "")] {
+            match parse_command(input) {
+                Some(Err(CommandError::Unknown(name))) => assert_eq!(name, unknown),
+                _ => panic!("expected {:?} to be an unknown command", input),
+            }
+        }
+        // Names are matched exactly
+        assert!(matches!(
+            parse_command("/JOIN forsen"),
+            Some(Err(CommandError::Unknown(_)))
+        ));
+    }
+
+    #[test]
+    fn test_join_and_part() {
+        assert!(matches!(
+            parse_command("  /join #Forsen  "),
+            Some(Ok(Command::Join(channel))) if channel == "forsen"
+        ));
+        assert!(matches!(
+            parse_command("/part"),
+            Some(Ok(Command::Part(None)))
+        ));
+        assert!(matches!(
+            parse_command("/part NymN"),
+            Some(Ok(Command::Part(Some(channel)))) if channel == "nymn"
+        ));
+        assert!(matches!(
+            parse_command("/join #"),
+            Some(Err(CommandError::BadChannel(channel))) if channel == "#"
+        ));
+    }
+
+    #[test]
+    fn test_messages_keep_their_spacing() {
+        assert!(matches!(
+            parse_command("/me  waves   at chat "),
+            Some(Ok(Command::Me(message))) if message == "waves   at chat"
+        ));
+        assert!(matches!(
+            parse_command("/w @Forsen  hi  there"),
+            Some(Ok(Command::Whisper { username, message }))
+                if username == "forsen" && message == "hi  there"
+        ));
+        assert!(matches!(
+            parse_command("/raw PRIVMSG #forsen :forsenE"),
+            Some(Ok(Command::Raw(line))) if line == "PRIVMSG #forsen :forsenE"
+        ));
+    }
+
+    #[test]
+    fn test_usage_errors() {
+        assert_eq!(usage_error("/join"), "join");
+        assert_eq!(usage_error("/join forsen nymn"), "join");
+        assert_eq!(usage_error("/part forsen nymn"), "part");
+        assert_eq!(usage_error("/me"), "me");
+        assert_eq!(usage_error("/w forsen"), "w");
+        assert_eq!(usage_error("/raw"), "raw");
+        assert_eq!(usage_error("/clear all"), "clear");
+        assert_eq!(usage_error("/quit now"), "quit");
+        assert_eq!(usage_error("/ignore forsen nymn"), "ignore");
+        assert_eq!(usage_error("/unignore"), "unignore");
+        assert_eq!(usage_error("/help join me"), "help");
+    }
+
+    #[test]
+    fn test_ignore_and_help() {
+        assert!(matches!(
+            parse_command("/ignore"),
+            Some(Ok(Command::Ignore(None)))
+        ));
+        assert!(matches!(
+            parse_command("/ignore @Forsen"),
+            Some(Ok(Command::Ignore(Some(username)))) if username == "forsen"
+        ));
+        assert!(matches!(
+            parse_command("/unignore FORSEN"),
+            Some(Ok(Command::Unignore(username))) if username == "forsen"
+        ));
+        assert!(matches!(
+            parse_command("/help /join"),
+            Some(Ok(Command::Help(Some(name)))) if name == "/join"
+        ));
+        assert_eq!(command_info("/join").unwrap().name, "join");
+        assert_eq!(command_info("w").unwrap().usage, "/w <user> <message>");
+        assert!(command_info("whisper").is_none());
+    }
+
+    #[test]
+    fn test_every_command_has_a_usage() {
+        for info in COMMANDS {
+            assert!(
+                info.usage.starts_with(&format!("/{}", info.name)),
+                "{:?}",
+                info
+            );
+        }
+    }
+}
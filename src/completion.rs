@@ -0,0 +1,202 @@
+// Tab completion in the input field: chatter names, emote codes and slash commands. Each channel
+// buffer remembers the names it has seen, and Tab cycles through the ones matching the word
+// before the cursor.
+
+use std::collections::VecDeque;
+
+use crate::commands::COMMANDS;
+
+// Per channel, so a busy chat can't grow these forever
+const MAX_NAMES: usize = 1000;
+
+// Names in the order we'd like to suggest them, most recently seen first. Names are compared
+// case-insensitively, but the latest spelling is kept (display names have proper capitalization).
+#[derive(Default)]
+pub struct RecentNames {
+    names: VecDeque<String>,
+}
+
+impl RecentNames {
+    // Moves a name to the front
+    pub fn seen(&mut self, name: &str) {
+        self.remove(name);
+        self.names.push_front(name.to_owned());
+        self.names.truncate(MAX_NAMES);
+    }
+
+    // Adds a name at the back, unless we know it already. For names we know about without having
+    // seen them say anything.
+    pub fn add(&mut self, name: &str) {
+        if self.names.len() < MAX_NAMES && !self.contains(name) {
+            self.names.push_back(name.to_owned());
+        }
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.names.retain(|known| !known.eq_ignore_ascii_case(name));
+    }
+
+    fn contains(&self, name: &str) -> bool {
+        self.names
+            .iter()
+            .any(|known| known.eq_ignore_ascii_case(name))
+    }
+
+    fn matching<'a>(&'a self, prefix: &'a str) -> impl Iterator<Item = &'a String> {
+        self.names
+            .iter()
+            .filter(move |name| starts_with_ignore_case(name, prefix))
+    }
+}
+
+// A completion in progress. The candidate in the input field changes with each Tab.
+pub struct Completion {
+    // Where the completed word starts in the input field
+    pub word_start: usize,
+    // The word as it was typed, to put back if the completion is cancelled
+    pub original: String,
+    pub candidates: Vec<String>,
+    pub selected: usize,
+}
+
+// Candidates for a word: commands if it starts the input with a '/', chatters if it starts with an
+// '@', and otherwise chatters followed by emotes
+pub fn candidates(
+    word: &str,
+    at_start: bool,
+    chatters: &RecentNames,
+    emotes: &RecentNames,
+) -> Vec<String> {
+    let mut candidates: Vec<String> = Vec::new();
+    if let Some(prefix) = word.strip_prefix('/').filter(|_| at_start) {
+        candidates.extend(
+            COMMANDS
+                .iter()
+                .filter(|info| info.name.starts_with(prefix))
+                .map(|info| format!("/{}", info.name)),
+        );
+    } else if let Some(prefix) = word.strip_prefix('@') {
+        candidates.extend(chatters.matching(prefix).map(|name| format!("@{}", name)));
+    } else if !word.is_empty() {
+        candidates.extend(chatters.matching(word).cloned());
+        for emote in emotes.matching(word) {
+            if !candidates.contains(emote) {
+                candidates.push(emote.clone());
+            }
+        }
+    }
+    candidates
+}
+
+fn starts_with_ignore_case(name: &str, prefix: &str) -> bool {
+    name.get(..prefix.len())
+        .is_some_and(|start| start.eq_ignore_ascii_case(prefix))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(seen: &[&str]) -> RecentNames {
+        let mut names = RecentNames::default();
+        for name in seen {
+            names.seen(name);
+        }
+        names
+    }
+
+    #[test]
+    fn test_recent_names_first() {
+        let mut chatters = names(&["forsen", "nymn", "Fors"]);
+        assert_eq!(
+            candidates("f", false, &chatters, &RecentNames::default()),
+            ["Fors", "forsen"]
+        );
+
+        // Seeing a name again moves it to the front, with its latest spelling
+        chatters.seen("FORSEN");
+        assert_eq!(
+            candidates("f", false, &chatters, &RecentNames::default()),
+            ["FORSEN", "Fors"]
+        );
+
+        // Names we only know from the member list go after everyone who talked
+        chatters.add("fo");
+        chatters.add("forsen");
+        assert_eq!(
+            candidates("f", false, &chatters, &RecentNames::default()),
+            ["FORSEN", "Fors", "fo"]
+        );
+
+        chatters.remove("fors");
+        assert_eq!(
+            candidates("f", false, &chatters, &RecentNames::default()),
+            ["FORSEN", "fo"]
+        );
+    }
+
+    #[test]
+    fn test_case_insensitive_matching() {
+        let chatters = names(&["Forsen"]);
+        let emotes = names(&["forsenE"]);
+        assert_eq!(
+            candidates("FORS", false, &chatters, &emotes),
+            ["Forsen", "forsenE"]
+        );
+        assert_eq!(candidates("@fOr", false, &chatters, &emotes), ["@Forsen"]);
+        // Non-ASCII names only match exactly
+        let chatters = names(&["Ärrä"]);
+        assert!(candidates("ä", false, &chatters, &emotes).is_empty());
+        assert_eq!(candidates("Ä", false, &chatters, &emotes), ["Ärrä"]);
+    }
+
+    #[test]
+    fn test_commands_only_at_start() {
+        let chatters = names(&["joe"]);
+        assert_eq!(
+            candidates("/", true, &chatters, &RecentNames::default()).len(),
+            COMMANDS.len()
+        );
+        assert_eq!(
+            candidates("/j", true, &chatters, &RecentNames::default()),
+            ["/join"]
+        );
+        assert!(candidates("/j", false, &chatters, &RecentNames::default()).is_empty());
+        // Commands are lowercase, so they don't match case-insensitively
+        assert!(candidates("/J", true, &chatters, &RecentNames::default()).is_empty());
+    }
+
+    #[test]
+    fn test_at_completes_only_chatters() {
+        let chatters = names(&["forsen"]);
+        let emotes = names(&["forsenE"]);
+        assert_eq!(candidates("@f", true, &chatters, &emotes), ["@forsen"]);
+        assert_eq!(candidates("@", false, &chatters, &emotes), ["@forsen"]);
+    }
+
+    #[test]
+    fn test_chatters_and_emotes_deduped() {
+        let chatters = names(&["forsenE", "forsen"]);
+        let emotes = names(&["forsenCD", "forsenE"]);
+        assert_eq!(
+            candidates("forsen", false, &chatters, &emotes),
+            ["forsen", "forsenE", "forsenCD"]
+        );
+        // An empty word has nothing to complete
+        assert!(candidates("", false, &chatters, &emotes).is_empty());
+    }
+
+    #[test]
+    fn test_names_are_capped() {
+        let mut chatters = RecentNames::default();
+        for i in 0..MAX_NAMES + 10 {
+            chatters.seen(&format!("user{}", i));
+        }
+        chatters.add("latecomer");
+        assert_eq!(chatters.names.len(), MAX_NAMES);
+        assert!(!chatters.contains("latecomer"));
+        // The oldest ones went first
+        assert!(!chatters.contains("user9"));
+        assert!(chatters.contains("user10"));
+    }
+}
@@ -1,8 +1,24 @@
-use std::{fs::read_to_string, io, path::Path};
+//! The client's settings in the tuisen configuration file, `tuisen.toml` in the working
+//! directory. Anything that's missing or invalid in it falls back to its default. The TUI keeps
+//! its own settings in the same file, which is why the parsed table is available on its own.
+
+use std::{
+    fmt,
+    fs::read_to_string,
+    io,
+    path::{Path, PathBuf},
+};
 
 use toml::{self, Table, Value};
 
+pub const CONFIG_PATH: &str = "./tuisen.toml";
+
+const DEFAULT_IRC_ADDR: &str = "irc.chat.twitch.tv:6667";
+const DEFAULT_IRC_TLS_ADDR: &str = "irc.chat.twitch.tv:6697";
+const DEFAULT_IRC_WS_URL: &str = "wss://irc-ws.chat.twitch.tv:443";
+
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum ConfigReadError {
     FileNotFound,
     BadPermissions,
@@ -11,27 +27,77 @@ pub enum ConfigReadError {
     OtherError,
 }
 
+#[non_exhaustive]
 pub enum TwitchLogin {
     Anonymous,
     Auth { username: String, token: String },
 }
 
+// Keeps the token out of logs and panic messages
+impl fmt::Debug for TwitchLogin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TwitchLogin::Anonymous => f.write_str("Anonymous"),
+            TwitchLogin::Auth { username, .. } => f
+                .debug_struct("Auth")
+                .field("username", username)
+                .field("token", &"<redacted>")
+                .finish(),
+        }
+    }
+}
+
+#[derive(Debug)]
+#[non_exhaustive]
 pub enum BotMode {
     Off,
     WithPrefix(String),
 }
 
-// TODO: add more stuff, like UI options
+/// Server certificates are always verified; ca_file adds a CA on top of the system ones
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Transport {
+    Tcp,
+    Tls {
+        #[cfg_attr(not(feature = "tls"), allow(dead_code))]
+        ca_file: Option<PathBuf>,
+    },
+    /// IRC over WebSocket, at a ws:// or wss:// URL. ca_file only matters for wss://.
+    WebSocket {
+        #[cfg_attr(not(feature = "tls"), allow(dead_code))]
+        ca_file: Option<PathBuf>,
+    },
+}
+
+impl Transport {
+    /// Twitch's own server for this transport, for when the config doesn't name one
+    pub fn default_server(&self) -> &'static str {
+        match self {
+            Transport::Tcp => DEFAULT_IRC_ADDR,
+            Transport::Tls { .. } => DEFAULT_IRC_TLS_ADDR,
+            Transport::WebSocket { .. } => DEFAULT_IRC_WS_URL,
+        }
+    }
+}
+
+#[derive(Debug)]
 pub struct AppConfig {
     pub login: TwitchLogin,
-    pub channel: Option<String>,
+    pub channels: Option<Vec<String>>,
     pub bot_mode: BotMode,
+    pub capabilities: Option<Vec<String>>,
+    pub transport: Transport,
+    pub server: Option<String>,
 }
 
 pub fn try_read_config() -> Result<AppConfig, ConfigReadError> {
-    let config_path = Path::new("./tuisen.toml");
+    read_config_table().map(|table| AppConfig::from_table(&table))
+}
 
-    let table = read_to_string(config_path)
+/// Reads the config file as it is, for settings of your own
+pub fn read_config_table() -> Result<Table, ConfigReadError> {
+    read_to_string(Path::new(CONFIG_PATH))
         .map_err(|e| match e.kind() {
             io::ErrorKind::NotFound => ConfigReadError::FileNotFound,
             io::ErrorKind::InvalidData => ConfigReadError::InvalidEncoding,
@@ -39,29 +105,82 @@ pub fn try_read_config() -> Result<AppConfig, ConfigReadError> {
             _ => ConfigReadError::OtherError,
         })?
         .parse::<Table>()
-        .map_err(|_| ConfigReadError::InvalidSyntax)?;
-
-    let login = match (table.get("username"), table.get("token")) {
-        (Some(Value::String(username)), Some(Value::String(token))) => TwitchLogin::Auth {
-            username: username.to_owned(),
-            token: token.to_owned(),
-        },
-        _ => TwitchLogin::Anonymous,
-    };
-
-    let channel = match table.get("channel") {
-        Some(Value::String(ref channel_name)) => Some(channel_name.to_owned()),
-        _ => None,
-    };
-
-    let bot_mode = match table.get("bot_prefix") {
-        Some(Value::String(ref prefix)) => BotMode::WithPrefix(prefix.to_owned()),
-        _ => BotMode::Off,
-    };
-
-    Ok(AppConfig {
-        login,
-        channel,
-        bot_mode,
-    })
+        .map_err(|_| ConfigReadError::InvalidSyntax)
+}
+
+impl AppConfig {
+    pub fn from_table(table: &Table) -> Self {
+        let login = match (table.get("username"), table.get("token")) {
+            (Some(Value::String(username)), Some(Value::String(token))) => TwitchLogin::Auth {
+                username: username.to_owned(),
+                token: token.to_owned(),
+            },
+            _ => TwitchLogin::Anonymous,
+        };
+
+        // A list of channels, or a single one (the older, single-channel form)
+        let channels = match (table.get("channels"), table.get("channel")) {
+            (Some(Value::Array(ref channel_names)), _) => Some(
+                channel_names
+                    .iter()
+                    .filter_map(|name| name.as_str().and_then(normalize_channel))
+                    .collect(),
+            ),
+            (_, Some(Value::String(ref channel_name))) => {
+                normalize_channel(channel_name).map(|channel| vec![channel])
+            }
+            _ => None,
+        };
+
+        let bot_mode = match table.get("bot_prefix") {
+            Some(Value::String(ref prefix)) => BotMode::WithPrefix(prefix.to_owned()),
+            _ => BotMode::Off,
+        };
+
+        // An explicit empty list turns capability negotiation off
+        let capabilities = match table.get("capabilities") {
+            Some(Value::Array(ref caps)) => Some(
+                caps.iter()
+                    .filter_map(|cap| cap.as_str().map(|c| c.to_owned()))
+                    .collect(),
+            ),
+            _ => None,
+        };
+
+        let ca_file = match table.get("tls_ca_file") {
+            Some(Value::String(ref ca_file)) => Some(PathBuf::from(ca_file)),
+            _ => None,
+        };
+        let transport = match table.get("transport") {
+            Some(Value::String(ref transport)) if transport == "tcp" => Transport::Tcp,
+            Some(Value::String(ref transport)) if transport == "websocket" => {
+                Transport::WebSocket { ca_file }
+            }
+            _ => Transport::Tls { ca_file },
+        };
+
+        let server = match table.get("server") {
+            Some(Value::String(ref server)) => Some(server.to_owned()),
+            _ => None,
+        };
+
+        AppConfig {
+            login,
+            channels,
+            bot_mode,
+            capabilities,
+            transport,
+            server,
+        }
+    }
+}
+
+/// Turns user input like "#Forsen" into a channel name as we store it (lowercase, without the '#')
+pub fn normalize_channel(name: &str) -> Option<String> {
+    let name = name.trim().trim_start_matches('#');
+    if name.is_empty() || name.contains(char::is_whitespace) {
+        None
+    } else {
+        Some(name.to_lowercase())
+    }
 }
@@ -0,0 +1,311 @@
+// The input field's line editor. The cursor moves by grapheme, so that an emoji or an accented
+// letter made of several code points counts as one character, and its screen position comes from
+// the display width of the text before it, since CJK characters and most emoji take two columns.
+
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+#[derive(Default)]
+pub struct LineEditor {
+    text: String,
+    // Byte offset into text, always on a grapheme boundary
+    cursor: usize,
+    // The last text removed by a kill command, for yanking back in
+    yank_buffer: String,
+}
+
+impl LineEditor {
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    // Replaces the text, with the cursor at the end
+    pub fn set_text(&mut self, text: String) {
+        self.cursor = text.len();
+        self.text = text;
+    }
+
+    pub fn take_text(&mut self) -> String {
+        self.cursor = 0;
+        std::mem::take(&mut self.text)
+    }
+
+    // How many columns the text before the cursor takes up on screen
+    pub fn cursor_width(&self) -> usize {
+        self.width_before(self.cursor)
+    }
+
+    pub fn width_before(&self, offset: usize) -> usize {
+        self.text[..offset].width()
+    }
+
+    // The part of the word under the cursor that comes before it, and where it starts
+    pub fn word_before_cursor(&self) -> (usize, &str) {
+        let before = &self.text[..self.cursor];
+        let start = before
+            .char_indices()
+            .rev()
+            .find(|(_, c)| c.is_whitespace())
+            .map_or(0, |(i, c)| i + c.len_utf8());
+        (start, &before[start..])
+    }
+
+    // Replaces the text between start and the cursor, leaving the cursor after the replacement
+    pub fn replace_before_cursor(&mut self, start: usize, replacement: &str) {
+        self.text.replace_range(start..self.cursor, replacement);
+        self.cursor = start + replacement.len();
+    }
+
+    pub fn insert_char(&mut self, c: char) {
+        self.text.insert(self.cursor, c);
+        let inserted_end = self.cursor + c.len_utf8();
+        // A combining character merges into the grapheme before it, so the end of what we inserted
+        // might not be a boundary anymore
+        let boundary = self.grapheme_ends().find(|&end| end >= inserted_end);
+        self.cursor = boundary.unwrap_or(inserted_end);
+    }
+
+    pub fn move_left(&mut self) {
+        self.cursor = self.previous_grapheme();
+    }
+
+    pub fn move_right(&mut self) {
+        self.cursor = self.next_grapheme();
+    }
+
+    pub fn move_word_left(&mut self) {
+        self.cursor = self.previous_word();
+    }
+
+    pub fn move_word_right(&mut self) {
+        self.cursor = self.next_word();
+    }
+
+    pub fn move_home(&mut self) {
+        self.cursor = 0;
+    }
+
+    pub fn move_end(&mut self) {
+        self.cursor = self.text.len();
+    }
+
+    pub fn backspace(&mut self) {
+        let start = self.previous_grapheme();
+        self.text.replace_range(start..self.cursor, "");
+        self.cursor = start;
+    }
+
+    pub fn delete(&mut self) {
+        let end = self.next_grapheme();
+        self.text.replace_range(self.cursor..end, "");
+    }
+
+    // Ctrl-W: the word before the cursor, and any spaces after it
+    pub fn kill_word_before(&mut self) {
+        self.kill(self.previous_word(), self.cursor);
+    }
+
+    // Ctrl-U
+    pub fn kill_to_start(&mut self) {
+        self.kill(0, self.cursor);
+    }
+
+    // Ctrl-K
+    pub fn kill_to_end(&mut self) {
+        self.kill(self.cursor, self.text.len());
+    }
+
+    // Ctrl-Y
+    pub fn yank(&mut self) {
+        self.text.insert_str(self.cursor, &self.yank_buffer);
+        self.cursor += self.yank_buffer.len();
+    }
+
+    fn kill(&mut self, start: usize, end: usize) {
+        // Don't lose the yank buffer to a kill that removes nothing
+        if start < end {
+            self.yank_buffer = self.text[start..end].to_owned();
+            self.text.replace_range(start..end, "");
+            self.cursor = start;
+        }
+    }
+
+    fn previous_grapheme(&self) -> usize {
+        self.text[..self.cursor]
+            .grapheme_indices(true)
+            .next_back()
+            .map_or(0, |(i, _)| i)
+    }
+
+    fn next_grapheme(&self) -> usize {
+        self.grapheme_ends()
+            .find(|&end| end > self.cursor)
+            .unwrap_or(self.cursor)
+    }
+
+    fn grapheme_ends(&self) -> impl Iterator<Item = usize> + '_ {
+        self.text
+            .grapheme_indices(true)
+            .map(|(i, grapheme)| i + grapheme.len())
+    }
+
+    // Words are whatever sits between whitespace. Going left skips the whitespace right before the
+    // cursor, then the word before it.
+    fn previous_word(&self) -> usize {
+        self.text[..self.cursor]
+            .trim_end()
+            .char_indices()
+            .rev()
+            .find(|(_, c)| c.is_whitespace())
+            .map_or(0, |(i, c)| i + c.len_utf8())
+    }
+
+    fn next_word(&self) -> usize {
+        let after = &self.text[self.cursor..];
+        let word_start = after.len() - after.trim_start().len();
+        after[word_start..]
+            .find(char::is_whitespace)
+            .map_or(self.text.len(), |i| self.cursor + word_start + i)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn editor(text: &str) -> LineEditor {
+        let mut editor = LineEditor::default();
+        editor.set_text(text.to_owned());
+        editor
+    }
+
+    fn typed(text: &str) -> LineEditor {
+        let mut editor = LineEditor::default();
+        text.chars().for_each(|c| editor.insert_char(c));
+        editor
+    }
+
+    #[test]
+    fn test_moves_by_grapheme() {
+        // e + combining acute accent, a flag made of two regional indicators, and a family emoji
+        // made of several people joined by zero width joiners
+        let mut editor = editor("e\u{301}🇫🇮👨‍👩‍👧x");
+        editor.move_left();
+        assert_eq!(editor.cursor, editor.text.len() - 1);
+        editor.move_left();
+        editor.move_left();
+        assert_eq!(editor.cursor, "e\u{301}".len());
+        editor.move_left();
+        assert_eq!(editor.cursor, 0);
+        editor.move_left();
+        assert_eq!(editor.cursor, 0);
+
+        editor.move_right();
+        assert_eq!(editor.cursor, "e\u{301}".len());
+        editor.move_right();
+        assert_eq!(editor.cursor, "e\u{301}🇫🇮".len());
+    }
+
+    #[test]
+    fn test_deletes_by_grapheme() {
+        let mut editor = editor("ae\u{301}🇫🇮");
+        editor.backspace();
+        assert_eq!(editor.text(), "ae\u{301}");
+        editor.move_home();
+        editor.move_right();
+        editor.delete();
+        assert_eq!(editor.text(), "a");
+        editor.delete();
+        assert_eq!(editor.text(), "a");
+    }
+
+    #[test]
+    fn test_combining_char_joins_previous_grapheme() {
+        let mut editor = typed("e\u{301}");
+        assert_eq!(editor.text(), "e\u{301}");
+        assert_eq!(editor.cursor, editor.text.len());
+
+        // Typed in the middle, it sticks to the letter before it and the cursor goes past both
+        editor.set_text("ab".to_owned());
+        editor.move_left();
+        editor.insert_char('\u{301}');
+        assert_eq!(editor.text(), "a\u{301}b");
+        assert_eq!(editor.cursor, "a\u{301}".len());
+        editor.move_left();
+        assert_eq!(editor.cursor, 0);
+    }
+
+    #[test]
+    fn test_cursor_width() {
+        let mut editor = editor("a中文e\u{301}");
+        assert_eq!(editor.cursor_width(), 6);
+        editor.move_left();
+        assert_eq!(editor.cursor_width(), 5);
+        editor.move_left();
+        assert_eq!(editor.cursor_width(), 3);
+        assert_eq!(editor.width_before("a中".len()), 3);
+    }
+
+    #[test]
+    fn test_word_moves() {
+        let mut editor = editor("  forsen   nymn xqc ");
+        editor.move_word_left();
+        assert_eq!(editor.cursor, "  forsen   nymn ".len());
+        editor.move_word_left();
+        assert_eq!(editor.cursor, "  forsen   ".len());
+        editor.move_word_left();
+        assert_eq!(editor.cursor, 2);
+        editor.move_word_left();
+        assert_eq!(editor.cursor, 0);
+
+        editor.move_word_right();
+        assert_eq!(editor.cursor, "  forsen".len());
+        editor.move_word_right();
+        assert_eq!(editor.cursor, "  forsen   nymn".len());
+        editor.move_word_right();
+        editor.move_word_right();
+        assert_eq!(editor.cursor, editor.text.len());
+    }
+
+    #[test]
+    fn test_kill_and_yank() {
+        let mut editor = editor("forsen nymn  ");
+        editor.kill_word_before();
+        assert_eq!(editor.text(), "forsen ");
+        editor.move_home();
+        editor.yank();
+        assert_eq!(editor.text(), "nymn  forsen ");
+        assert_eq!(editor.cursor, "nymn  ".len());
+
+        editor.kill_to_end();
+        assert_eq!(editor.text(), "nymn  ");
+        editor.kill_to_start();
+        assert_eq!(editor.text(), "");
+        editor.yank();
+        editor.yank();
+        assert_eq!(editor.text(), "nymn  nymn  ");
+    }
+
+    #[test]
+    fn test_empty_kill_keeps_yank_buffer() {
+        let mut editor = editor("forsen");
+        editor.kill_to_start();
+        editor.kill_to_start();
+        editor.kill_to_end();
+        editor.kill_word_before();
+        editor.yank();
+        assert_eq!(editor.text(), "forsen");
+    }
+
+    #[test]
+    fn test_word_before_cursor() {
+        let mut editor = editor("hello @for");
+        assert_eq!(editor.word_before_cursor(), (6, "@for"));
+        editor.replace_before_cursor(6, "@forsen ");
+        assert_eq!(editor.text(), "hello @forsen ");
+        assert_eq!(editor.word_before_cursor(), (14, ""));
+
+        editor.move_home();
+        assert_eq!(editor.word_before_cursor(), (0, ""));
+    }
+}
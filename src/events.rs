@@ -0,0 +1,63 @@
+//! The app and the client each run a loop that waits on several things at once: the app on the
+//! terminal and the client, the client on the server and the app, and both on timers. Every source
+//! feeds a single channel, from a thread of its own if it has to block, and the loop sleeps on that
+//! channel until something comes in or its next timer is due. It then handles everything that's
+//! waiting in one go, rather than one event per wakeup.
+
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::Instant;
+
+pub struct EventQueue<E> {
+    tx: Sender<E>,
+    rx: Receiver<E>,
+}
+
+impl<E: Send + 'static> EventQueue<E> {
+    pub fn new() -> Self {
+        let (tx, rx) = mpsc::channel();
+        EventQueue { tx, rx }
+    }
+
+    pub fn sender(&self) -> Sender<E> {
+        self.tx.clone()
+    }
+
+    /// Passes everything from another channel on as events, followed by `closed` once nothing can
+    /// be sent on it anymore
+    pub fn forward<T: Send + 'static>(&self, rx: Receiver<T>, wrap: fn(T) -> E, closed: E) {
+        let tx = self.sender();
+        thread::spawn(move || {
+            for item in rx {
+                if tx.send(wrap(item)).is_err() {
+                    return;
+                }
+            }
+            let _ = tx.send(closed);
+        });
+    }
+
+    /// Blocks until there's at least one event, or until the deadline, and returns everything
+    /// that's waiting by then. Nothing means the deadline passed.
+    pub fn wait(&self, deadline: Option<Instant>) -> Vec<E> {
+        // We hold a sender ourselves, so the channel can't disconnect
+        let first = match deadline {
+            Some(deadline) => self
+                .rx
+                .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                .ok(),
+            None => self.rx.recv().ok(),
+        };
+        let mut events: Vec<E> = first.into_iter().collect();
+        if !events.is_empty() {
+            events.extend(self.rx.try_iter());
+        }
+        events
+    }
+}
+
+impl<E: Send + 'static> Default for EventQueue<E> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
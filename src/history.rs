@@ -0,0 +1,271 @@
+// What we typed before, kept per channel. The system buffer's history lives under the empty name.
+// If there's a history file, it's read on startup and written back on exit, one entry per line
+// as "<channel>\t<entry>".
+
+use std::collections::{HashMap, VecDeque};
+use std::fs::{read_to_string, write};
+use std::io;
+use std::path::PathBuf;
+
+pub const DEFAULT_HISTORY_SIZE: usize = 500;
+
+pub struct InputHistory {
+    entries: HashMap<String, VecDeque<String>>,
+    // How many entries to keep per channel
+    max_entries: usize,
+    file: Option<PathBuf>,
+    // While going through the history with Up and Down: the entry we're on, and what was in the
+    // input field before we started
+    browsing: Option<(usize, String)>,
+}
+
+// Ctrl-R: the newest entry containing the query, going further back each time Ctrl-R is pressed
+pub struct HistorySearch {
+    pub query: String,
+    pub found: Option<usize>,
+    // The input field's text from before the search, to put back if it's cancelled
+    pub original: String,
+}
+
+impl InputHistory {
+    pub fn new(max_entries: usize, file: Option<PathBuf>) -> Self {
+        InputHistory {
+            entries: HashMap::new(),
+            max_entries,
+            file,
+            browsing: None,
+        }
+    }
+
+    // A missing file just means there's no history yet
+    pub fn load(max_entries: usize, file: PathBuf) -> io::Result<Self> {
+        let contents = match read_to_string(&file) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e),
+        };
+        let mut history = InputHistory::new(max_entries, Some(file));
+        for line in contents.lines() {
+            if let Some((channel, entry)) = line.split_once('\t') {
+                history.push(channel, entry.to_owned());
+            }
+        }
+        Ok(history)
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        let Some(ref file) = self.file else {
+            return Ok(());
+        };
+        let mut channels: Vec<&String> = self.entries.keys().collect();
+        channels.sort();
+        let mut contents = String::new();
+        for channel in channels {
+            for entry in &self.entries[channel] {
+                contents.push_str(channel);
+                contents.push('\t');
+                contents.push_str(entry);
+                contents.push('\n');
+            }
+        }
+        write(file, contents)
+    }
+
+    pub fn push(&mut self, channel: &str, entry: String) {
+        self.browsing = None;
+        if self.max_entries == 0 {
+            return;
+        }
+        let entries = self.entries.entry(channel.to_owned()).or_default();
+        // Sending the same thing several times in a row only needs one entry
+        if entries.back() != Some(&entry) {
+            entries.push_back(entry);
+        }
+        if entries.len() > self.max_entries {
+            entries.pop_front();
+        }
+    }
+
+    pub fn get(&self, channel: &str, index: usize) -> Option<&str> {
+        self.entries
+            .get(channel)
+            .and_then(|entries| entries.get(index))
+            .map(|entry| entry.as_str())
+    }
+
+    // Up: the entry before the current one. current is what's in the input field, which we put
+    // back once we come out the other end with Down.
+    pub fn previous(&mut self, channel: &str, current: &str) -> Option<&str> {
+        let count = self.entries.get(channel).map_or(0, |entries| entries.len());
+        let index = match self.browsing {
+            Some((0, _)) => return None,
+            Some((index, _)) => index - 1,
+            None if count == 0 => return None,
+            None => count - 1,
+        };
+        let draft = match self.browsing.take() {
+            Some((_, draft)) => draft,
+            None => current.to_owned(),
+        };
+        self.browsing = Some((index, draft));
+        self.get(channel, index)
+    }
+
+    // Down: the entry after the current one, or what we were typing before, after the last one
+    pub fn next(&mut self, channel: &str) -> Option<String> {
+        let (index, draft) = self.browsing.take()?;
+        match self.get(channel, index + 1).map(|entry| entry.to_owned()) {
+            Some(entry) => {
+                self.browsing = Some((index + 1, draft));
+                Some(entry)
+            }
+            None => Some(draft),
+        }
+    }
+
+    pub fn stop_browsing(&mut self) {
+        self.browsing = None;
+    }
+
+    // The index of the newest entry before `before` that contains query
+    pub fn search(&self, channel: &str, query: &str, before: usize) -> Option<usize> {
+        let entries = self.entries.get(channel)?;
+        (0..before.min(entries.len()))
+            .rev()
+            .find(|&index| entries[index].contains(query))
+    }
+
+    pub fn len(&self, channel: &str) -> usize {
+        self.entries.get(channel).map_or(0, |entries| entries.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    use crate::testutil::TempDir;
+
+    fn history_with(entries: &[&str]) -> InputHistory {
+        let mut history = InputHistory::new(DEFAULT_HISTORY_SIZE, None);
+        for entry in entries {
+            history.push("forsen", entry.to_string());
+        }
+        history
+    }
+
+    #[test]
+    fn test_up_and_down_restore_draft() {
+        let mut history = history_with(&["first", "second"]);
+        assert_eq!(history.previous("forsen", "draft"), Some("second"));
+        assert_eq!(history.previous("forsen", "second"), Some("first"));
+        // There's nothing before the oldest entry
+        assert_eq!(history.previous("forsen", "first"), None);
+
+        assert_eq!(history.next("forsen").as_deref(), Some("second"));
+        assert_eq!(history.next("forsen").as_deref(), Some("draft"));
+        // Back at the draft, so Down does nothing more
+        assert_eq!(history.next("forsen"), None);
+
+        // Browsing starts over from the newest entry, with a new draft
+        assert_eq!(history.previous("forsen", "other"), Some("second"));
+        history.stop_browsing();
+        assert_eq!(history.next("forsen"), None);
+    }
+
+    #[test]
+    fn test_channels_have_their_own_history() {
+        let mut history = history_with(&["forsenE"]);
+        history.push("", "/join nymn".to_owned());
+        assert_eq!(history.previous("nymn", ""), None);
+        assert_eq!(history.previous("", ""), Some("/join nymn"));
+        history.stop_browsing();
+        assert_eq!(history.previous("forsen", ""), Some("forsenE"));
+    }
+
+    #[test]
+    fn test_repeats_are_kept_once() {
+        let mut history = history_with(&["a", "b", "b", "a"]);
+        assert_eq!(history.len("forsen"), 3);
+        assert_eq!(history.previous("forsen", ""), Some("a"));
+        assert_eq!(history.previous("forsen", ""), Some("b"));
+        assert_eq!(history.previous("forsen", ""), Some("a"));
+    }
+
+    #[test]
+    fn test_size_cap() {
+        let mut history = InputHistory::new(3, None);
+        for i in 0..5 {
+            history.push("forsen", i.to_string());
+        }
+        assert_eq!(history.len("forsen"), 3);
+        assert_eq!(history.get("forsen", 0), Some("2"));
+        assert_eq!(history.get("forsen", 2), Some("4"));
+
+        let mut history = InputHistory::new(0, None);
+        history.push("forsen", "forsenE".to_owned());
+        assert_eq!(history.len("forsen"), 0);
+        assert_eq!(history.previous("forsen", ""), None);
+    }
+
+    #[test]
+    fn test_search_steps_back() {
+        let history = history_with(&["forsenE", "nymnCorn", "forsenCD", "forsen"]);
+        let newest = history.len("forsen");
+
+        // Each Ctrl-R looks before the last match
+        let found = history.search("forsen", "forsen", newest);
+        assert_eq!(found, Some(3));
+        let found = history.search("forsen", "forsen", found.unwrap());
+        assert_eq!(found, Some(2));
+        let found = history.search("forsen", "forsen", found.unwrap());
+        assert_eq!(found, Some(0));
+        assert_eq!(history.search("forsen", "forsen", found.unwrap()), None);
+
+        assert_eq!(history.search("forsen", "Corn", newest), Some(1));
+        // Searches are case-sensitive
+        assert_eq!(history.search("forsen", "corn", newest), None);
+        assert_eq!(history.search("nymn", "forsen", 10), None);
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let dir = TempDir::new("history-save");
+        let file = dir.path().join("history");
+        let mut history = InputHistory::new(DEFAULT_HISTORY_SIZE, Some(file.clone()));
+        history.push("forsen", "forsenE".to_owned());
+        // Only the first tab on a line separates the channel from the entry
+        history.push("forsen", "a\ttab\tor two".to_owned());
+        history.push("", "/join nymn".to_owned());
+        history.save().unwrap();
+
+        let loaded = InputHistory::load(DEFAULT_HISTORY_SIZE, file.clone()).unwrap();
+        assert_eq!(loaded.len("forsen"), 2);
+        assert_eq!(loaded.get("forsen", 0), Some("forsenE"));
+        assert_eq!(loaded.get("forsen", 1), Some("a\ttab\tor two"));
+        assert_eq!(loaded.get("", 0), Some("/join nymn"));
+
+        // A smaller size on the next start keeps the newest entries
+        let loaded = InputHistory::load(1, file).unwrap();
+        assert_eq!(loaded.get("forsen", 0), Some("a\ttab\tor two"));
+    }
+
+    #[test]
+    fn test_load_missing_or_bad_file() {
+        let dir = TempDir::new("history-load");
+        let history = InputHistory::load(DEFAULT_HISTORY_SIZE, dir.path().join("missing")).unwrap();
+        assert_eq!(history.len(""), 0);
+
+        // Lines without a channel are skipped
+        let file = dir.path().join("history");
+        fs::write(&file, "no channel\nforsen\tforsenE\n").unwrap();
+        let history = InputHistory::load(DEFAULT_HISTORY_SIZE, file).unwrap();
+        assert_eq!(history.len(""), 0);
+        assert_eq!(history.get("forsen", 0), Some("forsenE"));
+
+        // A directory can't be read as a file
+        assert!(InputHistory::load(DEFAULT_HISTORY_SIZE, dir.path().to_owned()).is_err());
+    }
+}
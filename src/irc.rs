@@ -1,6 +1,13 @@
+//! Twitch IRC messages. Lines are parsed in two steps: [`RawIrcMessage`] splits a line into its
+//! blocks without allocating, and [`TwitchIrcMessage`] validates them into a typed command.
+//! [`TwitchIrcMessage::serialize`] turns a message back into a line.
+
+use std::borrow::Cow;
 use std::collections::HashMap;
 
 // TODO: Do we need all these?
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
 pub enum TwitchIrcCommand {
     Privmsg {
         channel: String,
@@ -12,6 +19,7 @@ pub enum TwitchIrcCommand {
         channel: String,
     },
     Part {
+        parter: String,
         channel: String,
     },
     Pass {
@@ -30,75 +38,490 @@ pub enum TwitchIrcCommand {
         command: u16,
         params: Vec<String>,
     },
+    /// The client is "*" in server replies, and absent in our own requests
+    Cap {
+        client: Option<String>,
+        subcommand: String,
+        capabilities: Vec<String>,
+    },
+    /// The channel is None for server-wide notices, which target "*"
+    Notice {
+        channel: Option<String>,
+        msg_id: Option<String>,
+        content: String,
+    },
+    /// Subs, resubs, raids, announcements, etc. The kind comes from the msg-id tag, and the
+    /// content is the (optional) message the user attached.
+    UserNotice {
+        channel: String,
+        kind: Option<String>,
+        login: Option<String>,
+        system_message: Option<String>,
+        content: Option<String>,
+    },
+    /// A target of None means the whole chat was cleared; otherwise the user was banned, or timed
+    /// out if there is a ban duration (in seconds).
+    ClearChat {
+        channel: String,
+        target: Option<String>,
+        ban_duration: Option<u32>,
+    },
+    ClearMsg {
+        channel: String,
+        login: Option<String>,
+        target_message_id: Option<String>,
+        content: String,
+    },
+    /// Twitch sends every field when we join, but only the changed field on updates, so they are
+    /// all optional. Followers-only is -1 when off, or else the required follow age in minutes;
+    /// slow mode is the delay in seconds.
+    RoomState {
+        channel: String,
+        emote_only: Option<bool>,
+        followers_only: Option<i32>,
+        r9k: Option<bool>,
+        slow: Option<u32>,
+        subs_only: Option<bool>,
+    },
+    UserState {
+        channel: String,
+        display_name: Option<String>,
+        color: Option<String>,
+        badges: Vec<Badge>,
+        is_mod: bool,
+    },
+    GlobalUserState {
+        user_id: Option<String>,
+        display_name: Option<String>,
+        color: Option<String>,
+        badges: Vec<Badge>,
+    },
+    Whisper {
+        sender: String,
+        recipient: String,
+        content: String,
+        message_id: Option<String>,
+        thread_id: Option<String>,
+    },
+    /// A target of None means the channel stopped hosting
+    HostTarget {
+        channel: String,
+        target: Option<String>,
+        viewers: Option<u32>,
+    },
+    Reconnect,
 }
 
-pub struct RawIrcMessage {
-    raw_tags: Option<String>,
-    raw_origin: Option<String>,
-    raw_command: String,
-    params: Vec<String>,
+/// A parsed but unvalidated IRC line. It borrows its blocks from the line it was parsed from, and
+/// only allocates if converted with into_owned().
+#[derive(Debug)]
+pub struct RawIrcMessage<'a> {
+    raw_tags: Option<Cow<'a, str>>,
+    raw_origin: Option<Cow<'a, str>>,
+    raw_command: Cow<'a, str>,
+    params: Vec<Cow<'a, str>>,
 }
 
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum RawIrcParseError {
-    BadSpaces,
-    NoParams,
+    MissingCommand,
 }
 
-// TODO: Improve string cloning
+impl RawIrcMessage<'_> {
+    pub fn into_owned(self) -> RawIrcMessage<'static> {
+        RawIrcMessage {
+            raw_tags: self.raw_tags.map(|t| Cow::Owned(t.into_owned())),
+            raw_origin: self.raw_origin.map(|o| Cow::Owned(o.into_owned())),
+            raw_command: Cow::Owned(self.raw_command.into_owned()),
+            params: self
+                .params
+                .into_iter()
+                .map(|p| Cow::Owned(p.into_owned()))
+                .collect(),
+        }
+    }
+}
+
+// Walks over the space-separated blocks of a line without allocating. Runs of spaces count as a
+// single separator, as in the IRCv3 message format.
+struct Blocks<'a> {
+    rest: &'a str,
+}
+
+impl<'a> Blocks<'a> {
+    // What is left of the line, starting at the next block
+    fn remainder(&self) -> &'a str {
+        self.rest.trim_start_matches(' ')
+    }
+
+    fn peek(&self) -> Option<&'a str> {
+        let remainder = self.remainder();
+        Some(remainder)
+            .filter(|r| !r.is_empty())
+            .map(|r| r.split_once(' ').map_or(r, |(block, _)| block))
+    }
+
+    fn next_if(&mut self, pred: impl FnOnce(&str) -> bool) -> Option<&'a str> {
+        let block = self.peek().filter(|b| pred(b))?;
+        self.rest = &self.remainder()[block.len()..];
+        Some(block)
+    }
+}
 
-impl TryFrom<String> for RawIrcMessage {
+impl<'a> TryFrom<&'a str> for RawIrcMessage<'a> {
     type Error = RawIrcParseError;
-    fn try_from(value: String) -> Result<Self, Self::Error> {
-        let mut blocks = value.split(" ").map(|s| s.to_owned()).peekable();
+    fn try_from(value: &'a str) -> Result<Self, Self::Error> {
+        let mut blocks = Blocks { rest: value };
 
-        // Get tags, origin, command as owned strings
+        // Get tags, origin, command as slices of the line
         let raw_tags = blocks
-            .next_if(|b| b.starts_with("@"))
-            .and_then(|b| b.strip_prefix("@").map(|b| b.to_owned()));
+            .next_if(|b| b.starts_with('@'))
+            .map(|b| Cow::Borrowed(&b[1..]));
         let raw_origin = blocks
-            .next_if(|b| b.starts_with(":"))
-            .and_then(|b| b.strip_prefix(":").map(|b| b.to_owned()));
+            .next_if(|b| b.starts_with(':'))
+            .map(|b| Cow::Borrowed(&b[1..]));
         let raw_command = blocks
-            .next_if(|b| !b.is_empty())
-            .ok_or(RawIrcParseError::BadSpaces)?;
-
-        // Get params as owned strings, stripping the trailing param of ":" if any
-        let mut params: Vec<String> = Vec::new();
-        while let Some(param) = blocks.next_if(|b| !b.is_empty()) {
-            if let Some(head) = param.strip_prefix(":") {
-                let mut trailing = head.to_owned();
-                if let Some(_) = blocks.peek() {
-                    trailing.push(' ');
-                    trailing.push_str(&blocks.collect::<Vec<String>>().as_slice().join(" "));
-                }
-                params.push(trailing);
+            .next_if(|_| true)
+            .map(Cow::Borrowed)
+            .ok_or(RawIrcParseError::MissingCommand)?;
+
+        // Get params, stripping the trailing param of ":" if any. The trailing param runs until
+        // the end of the line, spaces included.
+        let mut params: Vec<Cow<'a, str>> = Vec::new();
+        loop {
+            if let Some(trailing) = blocks.remainder().strip_prefix(':') {
+                params.push(Cow::Borrowed(trailing));
                 break;
-            } else {
-                params.push(param.to_owned());
+            }
+            match blocks.next_if(|_| true) {
+                Some(param) => params.push(Cow::Borrowed(param)),
+                None => break,
             }
         }
 
-        if params.is_empty() {
-            Err(RawIrcParseError::NoParams)
-        } else {
-            Ok(RawIrcMessage {
-                raw_tags,
-                raw_origin,
-                raw_command,
-                params,
-            })
-        }
+        // Some commands (e.g. RECONNECT, GLOBALUSERSTATE) have no params at all
+        Ok(RawIrcMessage {
+            raw_tags,
+            raw_origin,
+            raw_command,
+            params,
+        })
     }
 }
 
+#[derive(Debug, Clone, PartialEq)]
 pub struct TwitchIrcMessage {
     pub command: TwitchIrcCommand,
     tags: Option<HashMap<String, String>>,
 }
 
+#[derive(Debug, Clone, PartialEq)]
+pub struct Badge {
+    pub name: String,
+    pub version: String,
+}
+
+// Badge lists look like "moderator/1,subscriber/12". A badge without a version is kept with an
+// empty one.
+fn parse_badges(raw: &str) -> Vec<Badge> {
+    raw.split(',')
+        .filter(|badge| !badge.is_empty())
+        .map(|badge| {
+            let (name, version) = badge.split_once('/').unwrap_or((badge, ""));
+            Badge {
+                name: name.to_owned(),
+                version: version.to_owned(),
+            }
+        })
+        .collect()
+}
+
+impl TwitchIrcMessage {
+    pub fn new(command: TwitchIrcCommand) -> Self {
+        TwitchIrcMessage {
+            command,
+            tags: None,
+        }
+    }
+
+    pub fn with_tags(command: TwitchIrcCommand, tags: HashMap<String, String>) -> Self {
+        TwitchIrcMessage {
+            command,
+            tags: Some(tags).filter(|tags| !tags.is_empty()),
+        }
+    }
+
+    /// Constructors for the messages we send. Outgoing messages leave the sender empty, since
+    /// clients don't write a prefix.
+    pub fn privmsg(channel: &str, content: &str) -> Self {
+        Self::new(TwitchIrcCommand::Privmsg {
+            channel: channel.to_owned(),
+            sender: String::new(),
+            content: content.to_owned(),
+        })
+    }
+
+    pub fn join(channel: &str) -> Self {
+        Self::new(TwitchIrcCommand::Join {
+            joiner: String::new(),
+            channel: channel.to_owned(),
+        })
+    }
+
+    pub fn part(channel: &str) -> Self {
+        Self::new(TwitchIrcCommand::Part {
+            parter: String::new(),
+            channel: channel.to_owned(),
+        })
+    }
+
+    pub fn pass(token: &str) -> Self {
+        Self::new(TwitchIrcCommand::Pass {
+            token: token.to_owned(),
+        })
+    }
+
+    pub fn nick(nick: &str) -> Self {
+        Self::new(TwitchIrcCommand::Nick {
+            nick: nick.to_owned(),
+        })
+    }
+
+    pub fn ping(content: &str) -> Self {
+        Self::new(TwitchIrcCommand::Ping {
+            content: content.to_owned(),
+        })
+    }
+
+    pub fn pong(content: &str) -> Self {
+        Self::new(TwitchIrcCommand::Pong {
+            content: content.to_owned(),
+        })
+    }
+
+    pub fn cap_req(capabilities: &[String]) -> Self {
+        Self::new(TwitchIrcCommand::Cap {
+            client: None,
+            subcommand: "REQ".to_owned(),
+            capabilities: capabilities.to_vec(),
+        })
+    }
+
+    /// Raw access to a tag value, already unescaped. Keys are matched verbatim, so vendor-prefixed
+    /// and client-only keys should include their prefix (e.g. "+example.com/foo").
+    pub fn tag(&self, key: &str) -> Option<&str> {
+        self.tags
+            .as_ref()
+            .and_then(|tags| tags.get(key))
+            .map(|value| value.as_str())
+    }
+
+    pub fn has_tag(&self, key: &str) -> bool {
+        self.tags
+            .as_ref()
+            .is_some_and(|tags| tags.contains_key(key))
+    }
+
+    pub fn tags(&self) -> Option<&HashMap<String, String>> {
+        self.tags.as_ref()
+    }
+
+    // Like tag(), but treats empty values as missing. Twitch sends most tags on every message,
+    // even when they hold nothing (e.g. "color=;").
+    fn non_empty_tag(&self, key: &str) -> Option<&str> {
+        self.tag(key).filter(|value| !value.is_empty())
+    }
+
+    pub fn display_name(&self) -> Option<&str> {
+        self.non_empty_tag("display-name")
+    }
+
+    pub fn color(&self) -> Option<&str> {
+        self.non_empty_tag("color")
+    }
+
+    /// Parses the color tag, which has the form "#RRGGBB"
+    pub fn color_rgb(&self) -> Option<(u8, u8, u8)> {
+        let hex = self.color()?.strip_prefix('#')?;
+        if hex.len() != 6 || !hex.is_ascii() {
+            return None;
+        }
+        let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+        Some((channel(0)?, channel(2)?, channel(4)?))
+    }
+
+    pub fn message_id(&self) -> Option<&str> {
+        self.non_empty_tag("id")
+    }
+
+    pub fn user_id(&self) -> Option<&str> {
+        self.non_empty_tag("user-id")
+    }
+
+    pub fn room_id(&self) -> Option<&str> {
+        self.non_empty_tag("room-id")
+    }
+
+    /// Milliseconds since the Unix epoch
+    pub fn tmi_sent_ts(&self) -> Option<u64> {
+        self.non_empty_tag("tmi-sent-ts")?.parse().ok()
+    }
+
+    pub fn badges(&self) -> Vec<Badge> {
+        self.badge_list("badges")
+    }
+
+    pub fn badge_info(&self) -> Vec<Badge> {
+        self.badge_list("badge-info")
+    }
+
+    pub fn has_badge(&self, name: &str) -> bool {
+        self.badges().iter().any(|badge| badge.name == name)
+    }
+
+    pub fn is_mod(&self) -> bool {
+        self.tag("mod") == Some("1") || self.has_badge("moderator")
+    }
+
+    pub fn is_subscriber(&self) -> bool {
+        self.tag("subscriber") == Some("1") || self.has_badge("subscriber")
+    }
+
+    pub fn is_broadcaster(&self) -> bool {
+        self.has_badge("broadcaster")
+    }
+
+    pub fn is_vip(&self) -> bool {
+        self.has_tag("vip") || self.has_badge("vip")
+    }
+
+    /// The emote codes used in a PRIVMSG, each once. The emotes tag looks like
+    /// `<id>:<start>-<end>,<start>-<end>/<id>:...`, with positions counted in characters.
+    pub fn emote_codes(&self) -> Vec<String> {
+        let TwitchIrcCommand::Privmsg { ref content, .. } = self.command else {
+            return Vec::new();
+        };
+        let Some(emotes) = self.non_empty_tag("emotes") else {
+            return Vec::new();
+        };
+        let chars: Vec<char> = content.chars().collect();
+        let mut codes = Vec::new();
+        for emote in emotes.split('/') {
+            // Every use of an emote has the same code, so the first position is enough
+            let Some((start, end)) = emote
+                .split_once(':')
+                .and_then(|(_, positions)| positions.split(',').next())
+                .and_then(|position| position.split_once('-'))
+            else {
+                continue;
+            };
+            let (Ok(start), Ok(end)) = (start.parse::<usize>(), end.parse::<usize>()) else {
+                continue;
+            };
+            if let Some(code) = chars.get(start..=end) {
+                let code: String = code.iter().collect();
+                if !codes.contains(&code) {
+                    codes.push(code);
+                }
+            }
+        }
+        codes
+    }
+
+    fn badge_list(&self, key: &str) -> Vec<Badge> {
+        self.non_empty_tag(key)
+            .map(parse_badges)
+            .unwrap_or_default()
+    }
+}
+
+// Non-empty tag value lookup for use during parsing, before the message is built
+fn tag_value(tags: &Option<HashMap<String, String>>, key: &str) -> Option<String> {
+    tags.as_ref()
+        .and_then(|tags| tags.get(key))
+        .filter(|value| !value.is_empty())
+        .cloned()
+}
+
+/// Parses the IRCv3 tag block (without the leading '@'), which looks like
+/// "key=value;vendor.com/key2;+client-key=value3". Keys without a value, or with an empty value,
+/// map to an empty string. If a key is repeated, the last value wins.
+pub fn parse_tags(raw_tags: &str) -> Result<HashMap<String, String>, TwitchIrcParseError> {
+    let mut tags = HashMap::new();
+    for raw_tag in raw_tags.split(';').filter(|t| !t.is_empty()) {
+        let (key, raw_value) = raw_tag.split_once('=').unwrap_or((raw_tag, ""));
+        if !is_valid_tag_key(key) {
+            return Err(TwitchIrcParseError::BadTags);
+        }
+        tags.insert(key.to_owned(), unescape_tag_value(raw_value));
+    }
+    Ok(tags)
+}
+
+// A key is an optional '+' (client-only tag), then an optional vendor ending in '/', then a
+// non-empty name made of letters, digits and hyphens. Vendors are hostnames, so we also allow
+// dots in them.
+fn is_valid_tag_key(key: &str) -> bool {
+    let key = key.strip_prefix('+').unwrap_or(key);
+    let (vendor, name) = key.rsplit_once('/').unwrap_or(("", key));
+    let vendor_ok = vendor
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.');
+    let name_ok = !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-');
+    vendor_ok && name_ok && !(key.contains('/') && vendor.is_empty())
+}
+
+pub fn unescape_tag_value(raw_value: &str) -> String {
+    let mut value = String::with_capacity(raw_value.len());
+    let mut chars = raw_value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            value.push(c);
+            continue;
+        }
+        // An invalid escape drops the backslash, and a trailing lone backslash is dropped
+        match chars.next() {
+            Some(':') => value.push(';'),
+            Some('s') => value.push(' '),
+            Some('\\') => value.push('\\'),
+            Some('r') => value.push('\r'),
+            Some('n') => value.push('\n'),
+            Some(other) => value.push(other),
+            None => {}
+        }
+    }
+    value
+}
+
+// Strips the leading '#' from a channel param. Channel names can't have spaces, which could
+// otherwise sneak in through a trailing param.
+fn parse_channel(hash_channel: &str) -> Result<String, TwitchIrcParseError> {
+    hash_channel
+        .strip_prefix('#')
+        .filter(|channel| !channel.is_empty() && !channel.contains(' '))
+        .map(|channel| channel.to_owned())
+        .ok_or(TwitchIrcParseError::BadParams)
+}
+
+// Splits params of the form "#channel [:trailing]"
+fn parse_channel_and_trailing(
+    params: Vec<Cow<str>>,
+) -> Result<(String, Option<String>), TwitchIrcParseError> {
+    let mut params = params.into_iter();
+    match (params.next(), params.next(), params.next()) {
+        (Some(hash_channel), trailing, None) => Ok((
+            parse_channel(&hash_channel)?,
+            trailing.map(|t| t.into_owned()),
+        )),
+        _ => Err(TwitchIrcParseError::BadParams),
+    }
+}
+
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum TwitchIrcParseError {
+    BadTags,
     BadOrigin,
     BadSenderInOrigin,
     MismatchedSenderInOrigin,
@@ -107,18 +530,23 @@ pub enum TwitchIrcParseError {
     MissingSender,
 }
 
-impl TryFrom<RawIrcMessage> for TwitchIrcMessage {
+impl TryFrom<RawIrcMessage<'_>> for TwitchIrcMessage {
     type Error = TwitchIrcParseError;
 
-    fn try_from(value: RawIrcMessage) -> Result<Self, Self::Error> {
-        // TODO: parse tags into hashmap from raw_tags
-        let tags: Option<HashMap<String, String>> = None;
+    fn try_from(value: RawIrcMessage<'_>) -> Result<Self, Self::Error> {
+        // An empty tag block is the same as no tags at all
+        let tags = value
+            .raw_tags
+            .as_deref()
+            .map(parse_tags)
+            .transpose()?
+            .filter(|tags| !tags.is_empty());
 
         let sender: Option<String> = value
             .raw_origin
+            .as_deref()
             .map(|b| {
                 b.strip_suffix("tmi.twitch.tv")
-                    .map(|s| s.to_owned())
                     .ok_or(TwitchIrcParseError::BadOrigin)
             })
             .transpose()?
@@ -131,7 +559,7 @@ impl TryFrom<RawIrcMessage> for TwitchIrcMessage {
                 // If more than one of these are present, then the present components
                 // (nick, username, hostname_prefix) should match. For instance, when receiving a
                 // PRIVMSG from Twitch, they should all match the author's username.
-                let remainder = &prefix;
+                let remainder = prefix;
                 let (nick_opt, remainder) = remainder
                     .split_once("!")
                     .map(|t| (Some(t.0), t.1))
@@ -166,10 +594,10 @@ impl TryFrom<RawIrcMessage> for TwitchIrcMessage {
 
         // Here we validate params if needed for each command
         // TODO: add JOIN, because we receive one when we join a channel
-        match value.raw_command.as_str() {
+        match value.raw_command.as_ref() {
             "PRIVMSG" => {
                 let sender = sender.ok_or(TwitchIrcParseError::MissingSender)?;
-                let [channel, content]: [String; 2] = value
+                let [channel, content]: [Cow<str>; 2] = value
                     .params
                     .try_into()
                     .map_err(|_| TwitchIrcParseError::BadParams)?;
@@ -181,54 +609,269 @@ impl TryFrom<RawIrcMessage> for TwitchIrcMessage {
                     command: TwitchIrcCommand::Privmsg {
                         channel,
                         sender,
-                        content,
+                        content: content.into_owned(),
                     },
                     tags,
                 })
             }
             "PING" => {
-                let [content]: [String; 1] = value
+                let [content]: [Cow<str>; 1] = value
                     .params
                     .try_into()
                     .map_err(|_| TwitchIrcParseError::BadParams)?;
                 Ok(TwitchIrcMessage {
-                    command: TwitchIrcCommand::Ping { content },
+                    command: TwitchIrcCommand::Ping {
+                        content: content.into_owned(),
+                    },
                     tags,
                 })
             }
-            "PONG" => {
-                let [content]: [String; 1] = value
+            "PASS" => {
+                let [token]: [Cow<str>; 1] = value
                     .params
                     .try_into()
                     .map_err(|_| TwitchIrcParseError::BadParams)?;
                 Ok(TwitchIrcMessage {
-                    command: TwitchIrcCommand::Pong { content },
+                    command: TwitchIrcCommand::Pass {
+                        token: token.into_owned(),
+                    },
+                    tags,
+                })
+            }
+            "NICK" => {
+                let [nick]: [Cow<str>; 1] = value
+                    .params
+                    .try_into()
+                    .map_err(|_| TwitchIrcParseError::BadParams)?;
+                Ok(TwitchIrcMessage {
+                    command: TwitchIrcCommand::Nick {
+                        nick: nick.into_owned(),
+                    },
+                    tags,
+                })
+            }
+            "PONG" => {
+                // Twitch answers our pings with "PONG tmi.twitch.tv :<content>", so we only keep
+                // the last param
+                let content = match <[Cow<str>; 2]>::try_from(value.params) {
+                    Ok([_, content]) => content,
+                    Err(params) => {
+                        let [content]: [Cow<str>; 1] = params
+                            .try_into()
+                            .map_err(|_| TwitchIrcParseError::BadParams)?;
+                        content
+                    }
+                };
+                Ok(TwitchIrcMessage {
+                    command: TwitchIrcCommand::Pong {
+                        content: content.into_owned(),
+                    },
                     tags,
                 })
             }
             "JOIN" => {
-                let [hash_channel]: [String; 1] = value
+                let [hash_channel]: [Cow<str>; 1] = value
                     .params
                     .try_into()
                     .map_err(|_| TwitchIrcParseError::BadParams)?;
-                let channel = hash_channel
-                    .split_once('#')
-                    .filter(|t| t.0.is_empty() && !t.1.is_empty())
-                    .map(|t| t.1.to_owned())
-                    .ok_or(TwitchIrcParseError::BadParams)?;
+                let channel = parse_channel(&hash_channel)?;
                 let joiner = sender.ok_or(TwitchIrcParseError::MissingSender)?;
                 Ok(TwitchIrcMessage {
                     command: TwitchIrcCommand::Join { joiner, channel },
                     tags,
                 })
             }
+            "CAP" => {
+                // Twitch replies with e.g. "CAP * ACK :twitch.tv/tags twitch.tv/commands", and
+                // clients send e.g. "CAP REQ :twitch.tv/tags"
+                let mut params = value.params.into_iter();
+                let (client, subcommand, capabilities) =
+                    match (params.next(), params.next(), params.next(), params.next()) {
+                        (Some(client), Some(subcommand), Some(capabilities), None) => {
+                            (Some(client.into_owned()), subcommand, capabilities)
+                        }
+                        (Some(subcommand), Some(capabilities), None, None) => {
+                            (None, subcommand, capabilities)
+                        }
+                        _ => return Err(TwitchIrcParseError::BadParams),
+                    };
+                let capabilities = capabilities
+                    .split(' ')
+                    .filter(|c| !c.is_empty())
+                    .map(|c| c.to_owned())
+                    .collect();
+                Ok(TwitchIrcMessage {
+                    command: TwitchIrcCommand::Cap {
+                        client,
+                        subcommand: subcommand.into_owned(),
+                        capabilities,
+                    },
+                    tags,
+                })
+            }
+            "PART" => {
+                let [hash_channel]: [Cow<str>; 1] = value
+                    .params
+                    .try_into()
+                    .map_err(|_| TwitchIrcParseError::BadParams)?;
+                let channel = parse_channel(&hash_channel)?;
+                let parter = sender.ok_or(TwitchIrcParseError::MissingSender)?;
+                Ok(TwitchIrcMessage {
+                    command: TwitchIrcCommand::Part { parter, channel },
+                    tags,
+                })
+            }
+            "NOTICE" => {
+                let [target, content]: [Cow<str>; 2] = value
+                    .params
+                    .try_into()
+                    .map_err(|_| TwitchIrcParseError::BadParams)?;
+                let channel = match target.as_ref() {
+                    "*" => None,
+                    _ => Some(parse_channel(&target)?),
+                };
+                Ok(TwitchIrcMessage {
+                    command: TwitchIrcCommand::Notice {
+                        channel,
+                        msg_id: tag_value(&tags, "msg-id"),
+                        content: content.into_owned(),
+                    },
+                    tags,
+                })
+            }
+            "USERNOTICE" => {
+                let (channel, content) = parse_channel_and_trailing(value.params)?;
+                Ok(TwitchIrcMessage {
+                    command: TwitchIrcCommand::UserNotice {
+                        channel,
+                        kind: tag_value(&tags, "msg-id"),
+                        login: tag_value(&tags, "login"),
+                        system_message: tag_value(&tags, "system-msg"),
+                        content,
+                    },
+                    tags,
+                })
+            }
+            "CLEARCHAT" => {
+                let (channel, target) = parse_channel_and_trailing(value.params)?;
+                Ok(TwitchIrcMessage {
+                    command: TwitchIrcCommand::ClearChat {
+                        channel,
+                        target,
+                        ban_duration: tag_value(&tags, "ban-duration").and_then(|d| d.parse().ok()),
+                    },
+                    tags,
+                })
+            }
+            "CLEARMSG" => {
+                let (channel, content) = parse_channel_and_trailing(value.params)?;
+                Ok(TwitchIrcMessage {
+                    command: TwitchIrcCommand::ClearMsg {
+                        channel,
+                        login: tag_value(&tags, "login"),
+                        target_message_id: tag_value(&tags, "target-msg-id"),
+                        content: content.ok_or(TwitchIrcParseError::BadParams)?,
+                    },
+                    tags,
+                })
+            }
+            "ROOMSTATE" => {
+                let [hash_channel]: [Cow<str>; 1] = value
+                    .params
+                    .try_into()
+                    .map_err(|_| TwitchIrcParseError::BadParams)?;
+                let flag = |key: &str| tag_value(&tags, key).map(|v| v != "0");
+                Ok(TwitchIrcMessage {
+                    command: TwitchIrcCommand::RoomState {
+                        channel: parse_channel(&hash_channel)?,
+                        emote_only: flag("emote-only"),
+                        followers_only: tag_value(&tags, "followers-only")
+                            .and_then(|v| v.parse().ok()),
+                        r9k: flag("r9k"),
+                        slow: tag_value(&tags, "slow").and_then(|v| v.parse().ok()),
+                        subs_only: flag("subs-only"),
+                    },
+                    tags,
+                })
+            }
+            "USERSTATE" => {
+                let [hash_channel]: [Cow<str>; 1] = value
+                    .params
+                    .try_into()
+                    .map_err(|_| TwitchIrcParseError::BadParams)?;
+                let badges = tag_value(&tags, "badges")
+                    .map(|raw| parse_badges(&raw))
+                    .unwrap_or_default();
+                let is_mod = tag_value(&tags, "mod").as_deref() == Some("1")
+                    || badges.iter().any(|badge| badge.name == "moderator");
+                Ok(TwitchIrcMessage {
+                    command: TwitchIrcCommand::UserState {
+                        channel: parse_channel(&hash_channel)?,
+                        display_name: tag_value(&tags, "display-name"),
+                        color: tag_value(&tags, "color"),
+                        badges,
+                        is_mod,
+                    },
+                    tags,
+                })
+            }
+            "GLOBALUSERSTATE" => Ok(TwitchIrcMessage {
+                command: TwitchIrcCommand::GlobalUserState {
+                    user_id: tag_value(&tags, "user-id"),
+                    display_name: tag_value(&tags, "display-name"),
+                    color: tag_value(&tags, "color"),
+                    badges: tag_value(&tags, "badges")
+                        .map(|raw| parse_badges(&raw))
+                        .unwrap_or_default(),
+                },
+                tags,
+            }),
+            "WHISPER" => {
+                let [recipient, content]: [Cow<str>; 2] = value
+                    .params
+                    .try_into()
+                    .map_err(|_| TwitchIrcParseError::BadParams)?;
+                Ok(TwitchIrcMessage {
+                    command: TwitchIrcCommand::Whisper {
+                        sender: sender.ok_or(TwitchIrcParseError::MissingSender)?,
+                        recipient: recipient.into_owned(),
+                        content: content.into_owned(),
+                        message_id: tag_value(&tags, "message-id"),
+                        thread_id: tag_value(&tags, "thread-id"),
+                    },
+                    tags,
+                })
+            }
+            "HOSTTARGET" => {
+                // The trailing param is "<target> <viewers>", where the target is "-" when
+                // hosting ends. The viewer count may be missing.
+                let [hash_channel, trailing]: [Cow<str>; 2] = value
+                    .params
+                    .try_into()
+                    .map_err(|_| TwitchIrcParseError::BadParams)?;
+                let (target, viewers) = trailing.split_once(' ').unwrap_or((&trailing, ""));
+                Ok(TwitchIrcMessage {
+                    command: TwitchIrcCommand::HostTarget {
+                        channel: parse_channel(&hash_channel)?,
+                        target: Some(target)
+                            .filter(|t| *t != "-" && !t.is_empty())
+                            .map(|t| t.to_owned()),
+                        viewers: viewers.parse().ok(),
+                    },
+                    tags,
+                })
+            }
+            "RECONNECT" => Ok(TwitchIrcMessage {
+                command: TwitchIrcCommand::Reconnect,
+                tags,
+            }),
             raw_command => {
                 // Try to parse as numeric command
                 if let Ok(num) = raw_command.parse::<u16>() {
                     Ok(TwitchIrcMessage {
                         command: TwitchIrcCommand::Numeric {
                             command: num,
-                            params: value.params,
+                            params: value.params.into_iter().map(|p| p.into_owned()).collect(),
                         },
                         tags,
                     })
@@ -241,18 +884,639 @@ impl TryFrom<RawIrcMessage> for TwitchIrcMessage {
 }
 
 #[derive(Debug)]
-pub struct StringifyNotImplementedError;
-
-pub fn stringify_message(
-    message: &TwitchIrcMessage,
-) -> Result<String, StringifyNotImplementedError> {
-    match &message.command {
-        TwitchIrcCommand::Privmsg {
-            channel,
-            sender,
-            content,
-        } => Ok(format!("[#{}] {}: {}", channel, sender, content)),
-        TwitchIrcCommand::Ping { content } => Ok(format!("[ping {}]", content)),
-        _ => Err(StringifyNotImplementedError),
+#[non_exhaustive]
+pub enum TwitchIrcSerializeError {
+    BadTagKey,
+    BadSender,
+    UnrepresentableParam,
+}
+
+impl TwitchIrcMessage {
+    /// Serializes the message into a wire line, without the trailing CRLF. For every message we
+    /// can parse, parsing the serialized line gives back the same message.
+    ///
+    /// Commands that carry a sender (PRIVMSG, JOIN, PART, WHISPER) get a Twitch-style prefix
+    /// unless the sender is empty, which is how outgoing messages are built. Everything that
+    /// can't be represented on the wire, such as CR/LF inside a param, is rejected rather than
+    /// escaped, so user input can never smuggle in a second command.
+    pub fn serialize(&self) -> Result<String, TwitchIrcSerializeError> {
+        let mut line = String::new();
+
+        if let Some(tags) = &self.tags {
+            // Sort the keys so the output is deterministic
+            let mut keys: Vec<&String> = tags.keys().collect();
+            keys.sort();
+            line.push('@');
+            for (i, key) in keys.into_iter().enumerate() {
+                if !is_valid_tag_key(key) {
+                    return Err(TwitchIrcSerializeError::BadTagKey);
+                }
+                if i > 0 {
+                    line.push(';');
+                }
+                line.push_str(key);
+                let value = &tags[key];
+                if !value.is_empty() {
+                    line.push('=');
+                    line.push_str(&escape_tag_value(value));
+                }
+            }
+            line.push(' ');
+        }
+
+        let mut params = IrcParams::default();
+        let (sender, command) = match &self.command {
+            TwitchIrcCommand::Privmsg {
+                channel,
+                sender,
+                content,
+            } => {
+                params.middle(&format!("#{}", channel))?;
+                params.trailing(content)?;
+                (Some(sender), "PRIVMSG".to_owned())
+            }
+            TwitchIrcCommand::Join { joiner, channel } => {
+                params.middle(&format!("#{}", channel))?;
+                (Some(joiner), "JOIN".to_owned())
+            }
+            TwitchIrcCommand::Part { parter, channel } => {
+                params.middle(&format!("#{}", channel))?;
+                (Some(parter), "PART".to_owned())
+            }
+            TwitchIrcCommand::Pass { token } => {
+                params.last(token)?;
+                (None, "PASS".to_owned())
+            }
+            TwitchIrcCommand::Nick { nick } => {
+                params.last(nick)?;
+                (None, "NICK".to_owned())
+            }
+            TwitchIrcCommand::Ping { content } => {
+                params.trailing(content)?;
+                (None, "PING".to_owned())
+            }
+            TwitchIrcCommand::Pong { content } => {
+                params.trailing(content)?;
+                (None, "PONG".to_owned())
+            }
+            TwitchIrcCommand::Numeric {
+                command,
+                params: numeric_params,
+            } => {
+                if let Some((last, middles)) = numeric_params.split_last() {
+                    for middle in middles {
+                        params.middle(middle)?;
+                    }
+                    params.last(last)?;
+                }
+                (None, format!("{:03}", command))
+            }
+            TwitchIrcCommand::Cap {
+                client,
+                subcommand,
+                capabilities,
+            } => {
+                if let Some(client) = client {
+                    params.middle(client)?;
+                }
+                params.middle(subcommand)?;
+                if capabilities.iter().any(|c| c.is_empty() || c.contains(' ')) {
+                    return Err(TwitchIrcSerializeError::UnrepresentableParam);
+                }
+                params.trailing(&capabilities.join(" "))?;
+                (None, "CAP".to_owned())
+            }
+            TwitchIrcCommand::Notice {
+                channel, content, ..
+            } => {
+                match channel {
+                    Some(channel) => params.middle(&format!("#{}", channel))?,
+                    None => params.middle("*")?,
+                }
+                params.trailing(content)?;
+                (None, "NOTICE".to_owned())
+            }
+            TwitchIrcCommand::UserNotice {
+                channel, content, ..
+            } => {
+                params.middle(&format!("#{}", channel))?;
+                if let Some(content) = content {
+                    params.trailing(content)?;
+                }
+                (None, "USERNOTICE".to_owned())
+            }
+            TwitchIrcCommand::ClearChat {
+                channel, target, ..
+            } => {
+                params.middle(&format!("#{}", channel))?;
+                if let Some(target) = target {
+                    params.trailing(target)?;
+                }
+                (None, "CLEARCHAT".to_owned())
+            }
+            TwitchIrcCommand::ClearMsg {
+                channel, content, ..
+            } => {
+                params.middle(&format!("#{}", channel))?;
+                params.trailing(content)?;
+                (None, "CLEARMSG".to_owned())
+            }
+            TwitchIrcCommand::RoomState { channel, .. } => {
+                params.middle(&format!("#{}", channel))?;
+                (None, "ROOMSTATE".to_owned())
+            }
+            TwitchIrcCommand::UserState { channel, .. } => {
+                params.middle(&format!("#{}", channel))?;
+                (None, "USERSTATE".to_owned())
+            }
+            TwitchIrcCommand::GlobalUserState { .. } => (None, "GLOBALUSERSTATE".to_owned()),
+            TwitchIrcCommand::Whisper {
+                sender,
+                recipient,
+                content,
+                ..
+            } => {
+                params.middle(recipient)?;
+                params.trailing(content)?;
+                (Some(sender), "WHISPER".to_owned())
+            }
+            TwitchIrcCommand::HostTarget {
+                channel,
+                target,
+                viewers,
+            } => {
+                params.middle(&format!("#{}", channel))?;
+                let target = match target {
+                    Some(target) if target.is_empty() || target.contains(' ') || target == "-" => {
+                        return Err(TwitchIrcSerializeError::UnrepresentableParam)
+                    }
+                    Some(target) => target.as_str(),
+                    None => "-",
+                };
+                match viewers {
+                    Some(viewers) => params.trailing(&format!("{} {}", target, viewers))?,
+                    None => params.trailing(target)?,
+                }
+                (None, "HOSTTARGET".to_owned())
+            }
+            TwitchIrcCommand::Reconnect => (None, "RECONNECT".to_owned()),
+        };
+
+        if let Some(sender) = sender.filter(|s| !s.is_empty()) {
+            // The sender shows up three times in the prefix, and the parser splits on these
+            if sender.contains(['!', '@', '.', ' ', '\r', '\n', '\0']) {
+                return Err(TwitchIrcSerializeError::BadSender);
+            }
+            line.push_str(&format!(":{}!{}@{}.tmi.twitch.tv ", sender, sender, sender));
+        }
+
+        line.push_str(&command);
+        line.push_str(&params.0);
+        Ok(line)
+    }
+}
+
+// Accumulates the serialized params of a command, each one with its leading space
+#[derive(Default)]
+struct IrcParams(String);
+
+impl IrcParams {
+    fn middle(&mut self, param: &str) -> Result<(), TwitchIrcSerializeError> {
+        if param.is_empty() || param.starts_with(':') || param.contains([' ', '\r', '\n', '\0']) {
+            return Err(TwitchIrcSerializeError::UnrepresentableParam);
+        }
+        self.0.push(' ');
+        self.0.push_str(param);
+        Ok(())
+    }
+
+    fn trailing(&mut self, param: &str) -> Result<(), TwitchIrcSerializeError> {
+        if param.contains(['\r', '\n', '\0']) {
+            return Err(TwitchIrcSerializeError::UnrepresentableParam);
+        }
+        self.0.push_str(" :");
+        self.0.push_str(param);
+        Ok(())
+    }
+
+    // The last param only needs the trailing form if it wouldn't be a valid middle param
+    fn last(&mut self, param: &str) -> Result<(), TwitchIrcSerializeError> {
+        if param.is_empty() || param.starts_with(':') || param.contains(' ') {
+            self.trailing(param)
+        } else {
+            self.middle(param)
+        }
+    }
+}
+
+pub fn escape_tag_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            ';' => escaped.push_str("\\:"),
+            ' ' => escaped.push_str("\\s"),
+            '\\' => escaped.push_str("\\\\"),
+            '\r' => escaped.push_str("\\r"),
+            '\n' => escaped.push_str("\\n"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CORPUS: &str = include_str!("../tests/corpus/twitch_lines.txt");
+
+    fn parse(line: &str) -> Result<TwitchIrcMessage, String> {
+        let raw = RawIrcMessage::try_from(line).map_err(|e| format!("{:?}", e))?;
+        TwitchIrcMessage::try_from(raw).map_err(|e| format!("{:?}", e))
+    }
+
+    fn assert_round_trip(message: &TwitchIrcMessage) {
+        let line = message
+            .serialize()
+            .unwrap_or_else(|e| panic!("could not serialize {:?}: {:?}", message, e));
+        assert!(!line.contains(['\r', '\n']), "line break in {:?}", line);
+        let reparsed =
+            parse(&line).unwrap_or_else(|e| panic!("could not reparse {:?}: {}", line, e));
+        assert_eq!(&reparsed, message, "round trip through {:?}", line);
+        // Serializing is canonical, so a second pass gives the same line
+        assert_eq!(reparsed.serialize().unwrap(), line);
+    }
+
+    // Small deterministic PRNG (xorshift64*), so failures are reproducible from the seed
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 >> 12;
+            self.0 ^= self.0 << 25;
+            self.0 ^= self.0 >> 27;
+            self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+
+        fn chance(&mut self, percent: usize) -> bool {
+            self.below(100) < percent
+        }
+
+        fn pick<'a>(&mut self, items: &[&'a str]) -> &'a str {
+            items[self.below(items.len())]
+        }
+
+        fn text(&mut self, alphabet: &[&str], max_len: usize) -> String {
+            (0..self.below(max_len + 1))
+                .map(|_| self.pick(alphabet))
+                .collect()
+        }
+    }
+
+    const WORD_CHARS: [&str; 8] = ["a", "b", "z", "0", "9", "_", "-", "é"];
+    const TEXT_CHARS: [&str; 14] = [
+        "a", "B", " ", " ", ":", ";", "\\", "#", "@", "!", "=", "é", "😀", "\u{1}",
+    ];
+    const USERNAMES: [&str; 4] = ["forsen", "justinfan1337", "ronni", "a_b_c"];
+    const COMMANDS: [&str; 23] = [
+        "PRIVMSG",
+        "JOIN",
+        "PART",
+        "PASS",
+        "NICK",
+        "PING",
+        "PONG",
+        "CAP",
+        "NOTICE",
+        "USERNOTICE",
+        "CLEARCHAT",
+        "CLEARMSG",
+        "ROOMSTATE",
+        "USERSTATE",
+        "GLOBALUSERSTATE",
+        "WHISPER",
+        "HOSTTARGET",
+        "RECONNECT",
+        "001",
+        "353",
+        "366",
+        "421",
+        "FOO",
+    ];
+    const TAG_KEYS: [&str; 14] = [
+        "badges",
+        "badge-info",
+        "color",
+        "display-name",
+        "id",
+        "mod",
+        "msg-id",
+        "login",
+        "ban-duration",
+        "followers-only",
+        "slow",
+        "emote-only",
+        "+example.com/client-tag",
+        "example.com/vendor-tag",
+    ];
+
+    // Builds a random line that looks like something Twitch could send: random tags (escaped
+    // or not), origin, command and params, with random runs of spaces in between.
+    fn random_line(rng: &mut Rng) -> String {
+        let separator = |rng: &mut Rng| " ".repeat(1 + rng.below(2) * rng.below(3));
+        let mut line = String::new();
+
+        if rng.chance(60) {
+            let tags: Vec<String> = (0..rng.below(5))
+                .map(|_| {
+                    let key = rng.pick(&TAG_KEYS);
+                    match rng.below(4) {
+                        0 => key.to_owned(),
+                        1 => format!("{}=", key),
+                        2 => format!("{}={}", key, escape_tag_value(&rng.text(&TEXT_CHARS, 8))),
+                        // Raw escapes, including invalid and dangling ones
+                        _ => format!("{}={}", key, rng.text(&["a", "\\", "s", ":", "\\s"], 6)),
+                    }
+                })
+                .collect();
+            line.push('@');
+            line.push_str(&tags.join(";"));
+            line.push_str(&separator(rng));
+        }
+
+        if rng.chance(80) {
+            let user = rng.pick(&USERNAMES);
+            let origin = match rng.below(5) {
+                0 => "tmi.twitch.tv".to_owned(),
+                1 => format!("{}.tmi.twitch.tv", user),
+                2 => format!("{}!{}@{}.tmi.twitch.tv", user, user, user),
+                3 => format!("{}!{}@{}.tmi.twitch.tv", user, rng.pick(&USERNAMES), user),
+                _ => "jtv".to_owned(),
+            };
+            line.push(':');
+            line.push_str(&origin);
+            line.push_str(&separator(rng));
+        }
+
+        line.push_str(rng.pick(&COMMANDS));
+
+        for _ in 0..rng.below(4) {
+            line.push_str(&separator(rng));
+            match rng.below(3) {
+                0 => line.push_str(&format!("#{}", rng.pick(&USERNAMES))),
+                1 => line.push('*'),
+                _ => line.push_str(&rng.text(&WORD_CHARS, 6)),
+            }
+            if line.ends_with(' ') {
+                line.push('x');
+            }
+        }
+        if rng.chance(70) {
+            line.push_str(&separator(rng));
+            line.push(':');
+            line.push_str(&rng.text(&TEXT_CHARS, 20));
+        }
+
+        line
+    }
+
+    #[test]
+    fn corpus_lines_parse_and_round_trip() {
+        for line in CORPUS.lines().filter(|l| !l.is_empty()) {
+            let message = parse(line).unwrap_or_else(|e| panic!("{} while parsing {:?}", e, line));
+            assert_round_trip(&message);
+        }
+    }
+
+    #[test]
+    fn parses_privmsg_with_tags() {
+        let message = parse(
+            "@badges=moderator/1,subscriber/12;color=#1E90FF;display-name=Forsen;id=abc;\
+             tmi-sent-ts=1642696567751 :forsen!forsen@forsen.tmi.twitch.tv PRIVMSG #forsen :hi",
+        )
+        .unwrap();
+        assert_eq!(
+            message.command,
+            TwitchIrcCommand::Privmsg {
+                channel: "forsen".to_owned(),
+                sender: "forsen".to_owned(),
+                content: "hi".to_owned(),
+            }
+        );
+        assert_eq!(message.display_name(), Some("Forsen"));
+        assert_eq!(message.color_rgb(), Some((0x1e, 0x90, 0xff)));
+        assert_eq!(message.message_id(), Some("abc"));
+        assert_eq!(message.tmi_sent_ts(), Some(1642696567751));
+        assert!(message.is_mod());
+        assert!(message.is_subscriber());
+        assert!(!message.is_vip());
+        assert_eq!(message.badges()[1].version, "12");
+    }
+
+    #[test]
+    fn unescapes_tag_values() {
+        let tags = parse_tags(r"a=one\stwo\:three\\four\r\n;b;c=;d=\x\").unwrap();
+        assert_eq!(tags["a"], "one two;three\\four\r\n");
+        assert_eq!(tags["b"], "");
+        assert_eq!(tags["c"], "");
+        // Invalid escapes drop the backslash, and a dangling one is dropped entirely
+        assert_eq!(tags["d"], "x");
+    }
+
+    #[test]
+    fn accepts_vendor_and_client_only_tag_keys() {
+        let tags = parse_tags("+example.com/foo=1;example.com/bar=2;+baz=3").unwrap();
+        assert_eq!(tags["+example.com/foo"], "1");
+        assert_eq!(tags["example.com/bar"], "2");
+        assert_eq!(tags["+baz"], "3");
+    }
+
+    #[test]
+    fn rejects_bad_tag_keys() {
+        for raw in ["=value", "/foo=1", "a b=1", "foo/=1", "ké=1"] {
+            assert!(parse_tags(raw).is_err(), "{:?} should not parse", raw);
+        }
+    }
+
+    #[test]
+    fn repeated_tag_keys_keep_the_last_value() {
+        assert_eq!(parse_tags("a=1;a=2").unwrap()["a"], "2");
+    }
+
+    #[test]
+    fn multiple_spaces_separate_blocks() {
+        let message =
+            parse(":ronni!ronni@ronni.tmi.twitch.tv   PRIVMSG  #dallas   :hi  there ").unwrap();
+        assert_eq!(
+            message.command,
+            TwitchIrcCommand::Privmsg {
+                channel: "dallas".to_owned(),
+                sender: "ronni".to_owned(),
+                content: "hi  there ".to_owned(),
+            }
+        );
+        assert!(parse("  PING   :x").is_ok());
+    }
+
+    #[test]
+    fn empty_trailing_param_is_kept() {
+        let message = parse(":a!a@a.tmi.twitch.tv PRIVMSG #c :").unwrap();
+        assert!(
+            matches!(message.command, TwitchIrcCommand::Privmsg { ref content, .. } if content.is_empty())
+        );
+    }
+
+    #[test]
+    fn rejects_lines_without_a_command() {
+        for line in ["", "   ", "@a=b", ":tmi.twitch.tv", "@a=b :tmi.twitch.tv  "] {
+            assert!(
+                RawIrcMessage::try_from(line).is_err(),
+                "{:?} should not parse",
+                line
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_origins_outside_twitch() {
+        assert!(matches!(
+            parse(":jtv PRIVMSG #c :hi"),
+            Err(e) if e == "BadOrigin"
+        ));
+        assert!(matches!(
+            parse(":a!b@a.tmi.twitch.tv PRIVMSG #c :hi"),
+            Err(e) if e == "MismatchedSenderInOrigin"
+        ));
+        assert!(matches!(
+            parse(":a.b.tmi.twitch.tv PRIVMSG #c :hi"),
+            Err(e) if e == "BadSenderInOrigin"
+        ));
+    }
+
+    #[test]
+    fn parses_commands_without_params() {
+        assert_eq!(
+            parse(":tmi.twitch.tv RECONNECT").unwrap().command,
+            TwitchIrcCommand::Reconnect
+        );
+        assert!(matches!(
+            parse("@user-id=1 :tmi.twitch.tv GLOBALUSERSTATE").unwrap().command,
+            TwitchIrcCommand::GlobalUserState { user_id: Some(ref id), .. } if id == "1"
+        ));
+    }
+
+    #[test]
+    fn serializer_rejects_line_breaks() {
+        let injected = TwitchIrcMessage::privmsg("forsen", "hi\r\nPRIVMSG #forsen :pwned");
+        assert!(injected.serialize().is_err());
+        assert!(TwitchIrcMessage::join("forsen\r\nQUIT")
+            .serialize()
+            .is_err());
+        assert!(TwitchIrcMessage::join("for sen").serialize().is_err());
+    }
+
+    #[test]
+    fn serializes_outgoing_messages_without_prefix() {
+        assert_eq!(
+            TwitchIrcMessage::privmsg("forsen", "hi there")
+                .serialize()
+                .unwrap(),
+            "PRIVMSG #forsen :hi there"
+        );
+        assert_eq!(
+            TwitchIrcMessage::cap_req(&[
+                "twitch.tv/tags".to_owned(),
+                "twitch.tv/commands".to_owned()
+            ])
+            .serialize()
+            .unwrap(),
+            "CAP REQ :twitch.tv/tags twitch.tv/commands"
+        );
+        assert_eq!(
+            TwitchIrcMessage::pass("oauth:abc").serialize().unwrap(),
+            "PASS oauth:abc"
+        );
+    }
+
+    #[test]
+    fn tag_escaping_round_trips() {
+        let mut rng = Rng(0x5eed_0001);
+        for _ in 0..5_000 {
+            let value = rng.text(&TEXT_CHARS, 16);
+            assert_eq!(unescape_tag_value(&escape_tag_value(&value)), value);
+        }
+    }
+
+    #[test]
+    fn random_lines_never_panic_and_round_trip() {
+        let mut rng = Rng(0x5eed_0002);
+        let mut seen_commands = Vec::new();
+        for _ in 0..20_000 {
+            let line = random_line(&mut rng);
+            if let Ok(message) = parse(&line) {
+                let discriminant = std::mem::discriminant(&message.command);
+                if !seen_commands.contains(&discriminant) {
+                    seen_commands.push(discriminant);
+                }
+                assert_round_trip(&message);
+            }
+        }
+        // Every command variant should have been generated and parsed at least once
+        assert_eq!(seen_commands.len(), 19);
+    }
+
+    #[test]
+    fn arbitrary_input_never_panics() {
+        let mut rng = Rng(0x5eed_0003);
+        let alphabet = [
+            "@",
+            ":",
+            " ",
+            ";",
+            "=",
+            "!",
+            "#",
+            ".",
+            "\\",
+            "/",
+            "+",
+            "a",
+            "0",
+            "é",
+            "\r",
+            "\n",
+            "\0",
+            "tmi.twitch.tv",
+            "PRIVMSG",
+            "PING",
+            "CAP",
+        ];
+        for _ in 0..20_000 {
+            let line = rng.text(&alphabet, 24);
+            if let Ok(message) = parse(&line) {
+                // Lines with control characters may not be serializable, but must not panic
+                let _ = message.serialize();
+            }
+        }
+    }
+
+    #[test]
+    fn extracts_emote_codes() {
+        let message = parse("@emotes=25:0-4,12-16/1902:6-10 :a!a@a.tmi.twitch.tv PRIVMSG #forsen :Kappa Keepo Kappa").unwrap();
+        assert_eq!(message.emote_codes(), vec!["Kappa", "Keepo"]);
+        // Positions count characters, not bytes
+        let message =
+            parse("@emotes=25:3-7 :a!a@a.tmi.twitch.tv PRIVMSG #forsen :éé Kappa").unwrap();
+        assert_eq!(message.emote_codes(), vec!["Kappa"]);
+        // Positions past the end of the message are skipped
+        let message =
+            parse("@emotes=25:0-4/1902:40-44 :a!a@a.tmi.twitch.tv PRIVMSG #forsen :Kappa").unwrap();
+        assert_eq!(message.emote_codes(), vec!["Kappa"]);
+        let message = parse("@emotes= :a!a@a.tmi.twitch.tv PRIVMSG #forsen :Kappa").unwrap();
+        assert!(message.emote_codes().is_empty());
     }
 }
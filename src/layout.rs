@@ -0,0 +1,174 @@
+// Split-pane layouts: a binary tree of splits, with one pane at each leaf. The app keeps buffer
+// indices in the leaves, and the config file keeps channel names.
+
+// How long a split can get before one of its sides becomes useless, in percent
+const MIN_RATIO: u16 = 10;
+const MAX_RATIO: u16 = 90;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SplitDirection {
+    // Side by side
+    Horizontal,
+    // Stacked on top of each other
+    Vertical,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum PaneLayout<T> {
+    Pane(T),
+    Split {
+        direction: SplitDirection,
+        // Share of the space that goes to the first (left or top) side, in percent
+        ratio: u16,
+        first: Box<PaneLayout<T>>,
+        second: Box<PaneLayout<T>>,
+    },
+}
+
+impl<T> PaneLayout<T> {
+    pub fn split(direction: SplitDirection, ratio: u16, first: Self, second: Self) -> Self {
+        PaneLayout::Split {
+            direction,
+            ratio: ratio.clamp(MIN_RATIO, MAX_RATIO),
+            first: Box::new(first),
+            second: Box::new(second),
+        }
+    }
+
+    // The panes, from left to right and top to bottom. Panes are referred to by their position in
+    // this order.
+    pub fn panes(&self) -> Vec<&T> {
+        match self {
+            PaneLayout::Pane(content) => vec![content],
+            PaneLayout::Split { first, second, .. } => {
+                let mut panes = first.panes();
+                panes.extend(second.panes());
+                panes
+            }
+        }
+    }
+
+    pub fn pane_count(&self) -> usize {
+        match self {
+            PaneLayout::Pane(_) => 1,
+            PaneLayout::Split { first, second, .. } => first.pane_count() + second.pane_count(),
+        }
+    }
+
+    pub fn pane_mut(&mut self, pane: usize) -> Option<&mut T> {
+        match self {
+            PaneLayout::Pane(content) if pane == 0 => Some(content),
+            PaneLayout::Pane(_) => None,
+            PaneLayout::Split { first, second, .. } => {
+                let first_count = first.pane_count();
+                if pane < first_count {
+                    first.pane_mut(pane)
+                } else {
+                    second.pane_mut(pane - first_count)
+                }
+            }
+        }
+    }
+
+    pub fn for_each_pane_mut(&mut self, f: &mut impl FnMut(&mut T)) {
+        match self {
+            PaneLayout::Pane(content) => f(content),
+            PaneLayout::Split { first, second, .. } => {
+                first.for_each_pane_mut(f);
+                second.for_each_pane_mut(f);
+            }
+        }
+    }
+
+    // Grows (or shrinks, with a negative delta) a pane by moving the closest split around it
+    pub fn resize_pane(&mut self, pane: usize, delta: i16) {
+        if let PaneLayout::Split {
+            ratio,
+            first,
+            second,
+            ..
+        } = self
+        {
+            let first_count = first.pane_count();
+            let (target, index, sign) = if pane < first_count {
+                (first, pane, 1)
+            } else {
+                (second, pane - first_count, -1)
+            };
+            if let PaneLayout::Pane(_) = **target {
+                *ratio =
+                    (*ratio as i16 + sign * delta).clamp(MIN_RATIO as i16, MAX_RATIO as i16) as u16;
+            } else {
+                target.resize_pane(index, delta);
+            }
+        }
+    }
+
+    // Converts the contents of every pane, dropping the panes for which f returns None. Returns
+    // None if no panes are left.
+    pub fn filter_map<U>(self, f: &mut impl FnMut(T) -> Option<U>) -> Option<PaneLayout<U>> {
+        match self {
+            PaneLayout::Pane(content) => f(content).map(PaneLayout::Pane),
+            PaneLayout::Split {
+                direction,
+                ratio,
+                first,
+                second,
+            } => match (first.filter_map(f), second.filter_map(f)) {
+                (Some(first), Some(second)) => {
+                    Some(PaneLayout::split(direction, ratio, first, second))
+                }
+                (Some(only), None) | (None, Some(only)) => Some(only),
+                (None, None) => None,
+            },
+        }
+    }
+}
+
+// Moving subtrees around needs a placeholder value to leave behind
+impl<T: Default> PaneLayout<T> {
+    // Turns a pane into an even split between itself and a new pane, which comes second
+    pub fn split_pane(&mut self, pane: usize, direction: SplitDirection, content: T) {
+        match self {
+            PaneLayout::Pane(_) if pane == 0 => {
+                let old = std::mem::replace(self, PaneLayout::Pane(T::default()));
+                *self = PaneLayout::split(direction, 50, old, PaneLayout::Pane(content));
+            }
+            PaneLayout::Pane(_) => {}
+            PaneLayout::Split { first, second, .. } => {
+                let first_count = first.pane_count();
+                if pane < first_count {
+                    first.split_pane(pane, direction, content);
+                } else {
+                    second.split_pane(pane - first_count, direction, content);
+                }
+            }
+        }
+    }
+
+    // Removes a pane, giving its space to its sibling. The last pane can't be removed.
+    pub fn remove_pane(&mut self, pane: usize) -> Option<T> {
+        let PaneLayout::Split { first, second, .. } = self else {
+            return None;
+        };
+        let first_count = first.pane_count();
+        let (target, sibling, index) = if pane < first_count {
+            (first, second, pane)
+        } else {
+            (second, first, pane - first_count)
+        };
+        match **target {
+            PaneLayout::Pane(_) => {
+                let sibling = std::mem::replace(&mut **sibling, PaneLayout::Pane(T::default()));
+                match std::mem::replace(&mut **target, PaneLayout::Pane(T::default())) {
+                    PaneLayout::Pane(removed) => {
+                        *self = sibling;
+                        Some(removed)
+                    }
+                    PaneLayout::Split { .. } => unreachable!(),
+                }
+            }
+            PaneLayout::Split { .. } => target.remove_pane(index),
+        }
+    }
+}
@@ -0,0 +1,64 @@
+//! Twitch chat over IRC, as used by the tuisen TUI.
+//!
+//! [`irc`] parses and serializes Twitch IRC messages. [`client`] keeps a connection to the
+//! server: it logs in, joins channels, answers pings, rate limits what we send and reconnects
+//! with backoff when the connection drops. It takes [`actions::TwitchAction`]s and reports back
+//! through [`actions::TerminalAction`]s. [`config`] reads the client's settings from the tuisen
+//! configuration file.
+//!
+//! The public enums are `#[non_exhaustive]`, since Twitch keeps adding to what it sends, so
+//! matches on them need a catch-all arm.
+//!
+//! ```no_run
+//! use std::sync::mpsc;
+//!
+//! use tuisen::actions::{TerminalAction, TwitchAction};
+//! use tuisen::client::{TwitchClient, TwitchClientConfig};
+//! use tuisen::config::{BotMode, Transport, TwitchLogin};
+//!
+//! let transport = Transport::Tls { ca_file: None };
+//! let config = TwitchClientConfig::new(
+//!     transport.default_server().to_owned(),
+//!     TwitchLogin::Anonymous,
+//!     vec!["forsen".to_owned()],
+//!     BotMode::Off,
+//!     vec!["twitch.tv/tags".to_owned()],
+//!     transport,
+//! );
+//! let (event_tx, event_rx) = mpsc::channel();
+//! let client = TwitchClient::spawn(config, event_tx);
+//!
+//! client
+//!     .sender()
+//!     .send(TwitchAction::SendPrivmsg {
+//!         channel: "forsen".to_owned(),
+//!         message: "forsenE".to_owned(),
+//!     })
+//!     .unwrap();
+//! for event in event_rx {
+//!     if let TerminalAction::PrintPrivmsg { username, message, .. } = event {
+//!         println!("{}: {}", username, message);
+//!     }
+//! }
+//! ```
+
+pub mod actions;
+
+pub mod client;
+
+pub mod config;
+
+// Not part of the API, but the TUI's event loop uses it too
+#[doc(hidden)]
+pub mod events;
+
+pub mod irc;
+
+mod random;
+
+mod ratelimit;
+
+#[cfg(feature = "tls")]
+mod tls;
+
+mod websocket;
@@ -1,5 +1,4 @@
 use std::sync::mpsc;
-use std::time::Duration;
 use std::{io, thread};
 
 use crossterm::event::{
@@ -13,14 +12,10 @@ use crossterm::terminal::{
 
 use ratatui::prelude::{Backend, CrosstermBackend, Terminal};
 
-mod client;
-use client::TwitchClientConfig;
-
-mod actions;
-use actions::{TerminalAction, TwitchAction};
-
-mod config;
-use config::TwitchLogin;
+use tuisen::actions::TerminalAction;
+use tuisen::client::{TwitchClient, TwitchClientConfig};
+use tuisen::config::{self, AppConfig, BotMode, Transport, TwitchLogin};
+use tuisen::events::EventQueue;
 
 mod app;
 use app::App;
@@ -28,10 +23,36 @@ use app::App;
 mod ui;
 use ui::render_ui;
 
-mod irc;
+mod commands;
+
+mod completion;
+
+mod editor;
+
+mod history;
+use history::{InputHistory, DEFAULT_HISTORY_SIZE};
+
+mod layout;
+
+mod scrollback;
+use scrollback::{ScrollbackConfig, DEFAULT_SCROLLBACK_SIZE};
+
+mod search;
+
+mod tui_config;
+use tui_config::TuiConfig;
+
+#[cfg(test)]
+mod testutil;
 
-const DEFAULT_IRC_ADDR: &str = "irc.chat.twitch.tv:6667";
 const DEFAULT_CHANNEL: &str = "forsen";
+// Membership gets us the JOIN, PART and names (353) messages that tab completion learns chatters
+// from
+const DEFAULT_CAPABILITIES: [&str; 3] = [
+    "twitch.tv/tags",
+    "twitch.tv/commands",
+    "twitch.tv/membership",
+];
 
 fn main() -> io::Result<()> {
     // Init buffer
@@ -59,34 +80,54 @@ fn main() -> io::Result<()> {
     app_result
 }
 
+// What the app's loop waits on
+enum AppEvent {
+    Terminal(io::Result<Event>),
+    Client(TerminalAction),
+    // The client thread is gone, which only happens if it panicked
+    ClientStopped,
+}
+
 fn run_app<B: Backend>(terminal: &mut Terminal<B>) -> io::Result<()> {
     // Init event channels and app state
-    let (twitch_action_tx, twitch_action_rx) = mpsc::channel::<TwitchAction>();
     let (terminal_action_tx, terminal_action_rx) = mpsc::channel::<TerminalAction>();
-    let (init_width, init_height) = terminal.size().map(|rect| (rect.width, rect.height))?;
-    let mut app = App::init(
-        init_width,
-        init_height,
-        terminal_action_rx,
-        twitch_action_tx,
-    );
-
     // TODO: improve custom config handling
     // Also maybe move the AppConfig read to the App::init method? Or have the AppConfig live
     // inside the App struct.
-    let client_config = match config::try_read_config() {
-        Ok(app_config) => {
+    let (client_config, tui_config) = match config::read_config_table() {
+        Ok(table) => {
             terminal_action_tx
                 .send(TerminalAction::PrintDebug(
                     "[client] Loaded configuration file.".to_owned(),
                 ))
                 .unwrap();
-            TwitchClientConfig::new(
-                DEFAULT_IRC_ADDR.to_owned(),
+            let app_config = AppConfig::from_table(&table);
+            let tui_config = TuiConfig::from_table(&table);
+            let irc_addr = app_config
+                .server
+                .unwrap_or(app_config.transport.default_server().to_owned());
+            // Channels in the saved layout get joined too
+            let mut channels = app_config
+                .channels
+                .unwrap_or(vec![DEFAULT_CHANNEL.to_owned()]);
+            if let Some(ref layout) = tui_config.layout {
+                for channel in layout.panes().into_iter().flatten() {
+                    if !channels.contains(channel) {
+                        channels.push(channel.clone());
+                    }
+                }
+            }
+            let client_config = TwitchClientConfig::new(
+                irc_addr,
                 app_config.login,
-                app_config.channel.unwrap_or(DEFAULT_CHANNEL.to_owned()),
+                channels,
                 app_config.bot_mode,
-            )
+                app_config
+                    .capabilities
+                    .unwrap_or(DEFAULT_CAPABILITIES.map(|c| c.to_owned()).to_vec()),
+                app_config.transport,
+            );
+            (client_config, tui_config)
         }
         Err(e) => {
             terminal_action_tx
@@ -95,53 +136,127 @@ fn run_app<B: Backend>(terminal: &mut Terminal<B>) -> io::Result<()> {
                     e
                 )))
                 .unwrap();
-            TwitchClientConfig::new(
-                DEFAULT_IRC_ADDR.to_owned(),
+            let transport = Transport::Tls { ca_file: None };
+            let client_config = TwitchClientConfig::new(
+                transport.default_server().to_owned(),
                 TwitchLogin::Anonymous,
-                DEFAULT_CHANNEL.to_owned(),
-                config::BotMode::Off,
-            )
+                vec![DEFAULT_CHANNEL.to_owned()],
+                BotMode::Off,
+                DEFAULT_CAPABILITIES.map(|c| c.to_owned()).to_vec(),
+                transport,
+            );
+            (client_config, TuiConfig::default())
         }
     };
 
-    let _client_handle = thread::spawn(move || {
-        let _ = client::connect_and_listen(client_config, twitch_action_rx, terminal_action_tx);
-    });
+    let history_size = tui_config.history_size.unwrap_or(DEFAULT_HISTORY_SIZE);
+    let history = match tui_config.history_file {
+        Some(history_file) => InputHistory::load(history_size, history_file).unwrap_or_else(|e| {
+            terminal_action_tx
+                .send(TerminalAction::PrintDebug(format!(
+                    "[client] Failed to read the history file ({}).",
+                    e
+                )))
+                .unwrap();
+            InputHistory::new(history_size, None)
+        }),
+        None => InputHistory::new(history_size, None),
+    };
+    let scrollback = ScrollbackConfig {
+        max_items: tui_config
+            .scrollback_size
+            .unwrap_or(DEFAULT_SCROLLBACK_SIZE),
+        spill_dir: tui_config.scrollback_dir,
+    };
 
-    loop {
-        // Draw UI
-        terminal.draw(|f| render_ui(f, &mut app))?;
+    let (init_width, init_height) = terminal.size().map(|rect| (rect.width, rect.height))?;
+    let channels = client_config.channels().to_vec();
+    let client = TwitchClient::spawn(client_config, terminal_action_tx);
+    let mut app = App::init(
+        init_width,
+        init_height,
+        channels,
+        tui_config.layout,
+        history,
+        scrollback,
+        client.sender(),
+    );
 
-        // Poll terminal actions
-        app.try_recv_terminal_action();
+    // Terminal input blocks, so it gets a thread that feeds the event queue. It stops when the
+    // queue goes away with us.
+    let events = EventQueue::new();
+    events.forward(
+        terminal_action_rx,
+        AppEvent::Client,
+        AppEvent::ClientStopped,
+    );
+    let input_tx = events.sender();
+    thread::spawn(move || loop {
+        let event = event::read();
+        let failed = event.is_err();
+        if input_tx.send(AppEvent::Terminal(event)).is_err() || failed {
+            return;
+        }
+    });
+
+    'main: loop {
+        // Draw UI, if there's anything new to show
+        if app.needs_redraw {
+            terminal.draw(|f| render_ui(f, &mut app))?;
+            app.needs_redraw = false;
+        }
 
-        // Poll key events
-        if let Ok(true) = event::poll(Duration::from_millis(30)) {
-            if let Event::Key(key) = event::read()? {
-                if key.kind == KeyEventKind::Release {
+        // Sleep until something happens, then handle everything that's waiting before drawing
+        // again. Nothing happening means it's time to update the status bar's countdown.
+        let app_events = events.wait(app.next_tick());
+        if app_events.is_empty() {
+            app.needs_redraw = true;
+        }
+        for app_event in app_events {
+            let key = match app_event {
+                AppEvent::Terminal(event) => match event? {
+                    Event::Key(key) if key.kind != KeyEventKind::Release => key,
+                    Event::Resize(..) => {
+                        app.needs_redraw = true;
+                        continue;
+                    }
+                    _ => continue,
+                },
+                AppEvent::Client(action) => {
+                    app.handle_terminal_action(action);
                     continue;
                 }
-
-                // Force quit
-                if let KeyEvent {
-                    code: KeyCode::Char('q'),
-                    modifiers: KeyModifiers::CONTROL,
-                    ..
-                } = key
-                {
-                    break;
+                AppEvent::ClientStopped => {
+                    app.handle_terminal_action(TerminalAction::PrintDebug(
+                        "[error] The client stopped. Restart tuisen to reconnect.".to_owned(),
+                    ));
+                    continue;
                 }
+            };
 
-                // Otherwise, let app struct handle it
-                let should_break = app.handle_key(key);
-                if should_break {
-                    break;
-                }
+            // Force quit
+            if let KeyEvent {
+                code: KeyCode::Char('q'),
+                modifiers: KeyModifiers::CONTROL,
+                ..
+            } = key
+            {
+                break 'main;
+            }
+
+            // Otherwise, let app struct handle it
+            let should_break = app.handle_key(key);
+            if should_break {
+                break 'main;
             }
         }
     }
 
-    Ok(())
+    let save_result = app.history.save();
+    // The app holds the client's other sender, so it has to go first
+    drop(app);
+    client.shutdown();
+    save_result
 }
 
 fn cleanup_terminal() -> io::Result<()> {
@@ -0,0 +1,32 @@
+// Randomness for things that only have to be hard to predict, like reconnection jitter and
+// WebSocket keys and masks, without pulling in a RNG crate. None of it is cryptographically secure.
+// Every RandomState is seeded differently, so hashing nothing with a fresh one gives a new value
+// each time.
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+pub fn random_u64() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+pub fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut bytes = [0u8; N];
+    for chunk in bytes.chunks_mut(8) {
+        chunk.copy_from_slice(&random_u64().to_le_bytes()[..chunk.len()]);
+    }
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_random_values_differ() {
+        assert_ne!(random_u64(), random_u64());
+        // Lengths that aren't a multiple of 8 fill the last chunk partly
+        assert_ne!(random_bytes::<12>(), random_bytes::<12>());
+        assert_ne!(random_bytes::<4>(), [0u8; 4]);
+    }
+}
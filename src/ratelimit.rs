@@ -0,0 +1,365 @@
+// Keeps our outgoing traffic under Twitch's chat limits. Going over them gets the account
+// throttled for a while, so anything rate limited goes through the Outbox, which holds messages back
+// until the limiters allow them.
+
+use std::collections::{HashSet, VecDeque};
+use std::time::{Duration, Instant};
+
+// Twitch's documented limits: 20 messages per 30 seconds, or 100 in channels where we're a
+// moderator, VIP or the broadcaster; and 20 JOINs per 10 seconds
+const PRIVMSG_LIMIT: u32 = 20;
+const ELEVATED_PRIVMSG_LIMIT: u32 = 100;
+const PRIVMSG_PERIOD: Duration = Duration::from_secs(30);
+const JOIN_LIMIT: u32 = 20;
+const JOIN_PERIOD: Duration = Duration::from_secs(10);
+
+// Messages beyond this are dropped instead of queued, so a burst can't keep us busy for minutes
+const MAX_QUEUED_PRIVMSGS: usize = 20;
+
+// A sliding window over the sends of the last `period`. A send is allowed while fewer than the
+// limit happened within the window, so no stretch of `period` ever holds more than the limit.
+pub struct RateLimiter {
+    period: Duration,
+    // When each send in the window happened, oldest first
+    sent: VecDeque<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(period: Duration) -> Self {
+        RateLimiter {
+            period,
+            sent: VecDeque::new(),
+        }
+    }
+
+    // Forgets the sends that have left the window
+    fn expire(&mut self, now: Instant) {
+        while let Some(&oldest) = self.sent.front() {
+            if now.saturating_duration_since(oldest) < self.period {
+                break;
+            }
+            self.sent.pop_front();
+        }
+    }
+
+    // Records a send if fewer than `limit` happened in the window
+    pub fn try_acquire(&mut self, limit: u32, now: Instant) -> bool {
+        self.expire(now);
+        if self.sent.len() < limit as usize {
+            self.sent.push_back(now);
+            true
+        } else {
+            false
+        }
+    }
+
+    // When the window will have room for another send under `limit`
+    pub fn ready_at(&self, limit: u32, now: Instant) -> Instant {
+        let in_window = self
+            .sent
+            .iter()
+            .rev()
+            .take_while(|&&sent| now.saturating_duration_since(sent) < self.period)
+            .count();
+        if in_window < limit as usize {
+            now
+        } else {
+            // Enough of the oldest sends have to leave the window to get below the limit
+            self.sent[self.sent.len() - limit as usize] + self.period
+        }
+    }
+}
+
+pub struct QueuedPrivmsg {
+    // Messages the user typed have an id, so the app can follow them from queued to sent (or
+    // dropped). Bot replies don't.
+    pub id: Option<u64>,
+    pub channel: String,
+    pub message: String,
+}
+
+pub struct Outbox {
+    privmsgs: VecDeque<QueuedPrivmsg>,
+    joins: VecDeque<String>,
+    // Elevated channels share the window with the rest, as the limits are for the whole account:
+    // a message to an elevated channel only has to fit under the higher limit
+    privmsg_limiter: RateLimiter,
+    join_limiter: RateLimiter,
+    // Channels where USERSTATE says we're a moderator, VIP or the broadcaster
+    elevated_channels: HashSet<String>,
+    next_id: u64,
+}
+
+impl Outbox {
+    pub fn new() -> Self {
+        Outbox {
+            privmsgs: VecDeque::new(),
+            joins: VecDeque::new(),
+            privmsg_limiter: RateLimiter::new(PRIVMSG_PERIOD),
+            join_limiter: RateLimiter::new(JOIN_PERIOD),
+            elevated_channels: HashSet::new(),
+            next_id: 0,
+        }
+    }
+
+    // A fresh id for a message the app wants to follow
+    pub fn new_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    pub fn set_elevated(&mut self, channel: &str, elevated: bool) {
+        if elevated {
+            self.elevated_channels.insert(channel.to_owned());
+        } else {
+            self.elevated_channels.remove(channel);
+        }
+    }
+
+    // Queues a message, or hands it back if the queue is full
+    pub fn push_privmsg(&mut self, privmsg: QueuedPrivmsg) -> Result<(), QueuedPrivmsg> {
+        if self.privmsgs.len() >= MAX_QUEUED_PRIVMSGS {
+            return Err(privmsg);
+        }
+        self.privmsgs.push_back(privmsg);
+        Ok(())
+    }
+
+    // The next message, if the limits allow sending it now. Messages go out in order, so one
+    // that has to wait holds back the ones behind it.
+    pub fn pop_privmsg(&mut self, now: Instant) -> Option<QueuedPrivmsg> {
+        let limit = self.privmsg_limit(&self.privmsgs.front()?.channel);
+        if self.privmsg_limiter.try_acquire(limit, now) {
+            self.privmsgs.pop_front()
+        } else {
+            None
+        }
+    }
+
+    fn privmsg_limit(&self, channel: &str) -> u32 {
+        if self.elevated_channels.contains(channel) {
+            ELEVATED_PRIVMSG_LIMIT
+        } else {
+            PRIVMSG_LIMIT
+        }
+    }
+
+    pub fn push_join(&mut self, channel: String) {
+        if !self.joins.contains(&channel) {
+            self.joins.push_back(channel);
+        }
+    }
+
+    // Forgets a JOIN that hasn't been sent yet. Returns false if there was none.
+    pub fn cancel_join(&mut self, channel: &str) -> bool {
+        let queued = self.joins.len();
+        self.joins.retain(|joining| joining != channel);
+        self.joins.len() != queued
+    }
+
+    // Queued JOINs only make sense on the connection they were queued for
+    pub fn clear_joins(&mut self) {
+        self.joins.clear();
+    }
+
+    // When the limits will let the next queued message or JOIN through, if anything is queued
+    pub fn next_ready(&self, now: Instant) -> Option<Instant> {
+        let privmsg_ready = self.privmsgs.front().map(|privmsg| {
+            self.privmsg_limiter
+                .ready_at(self.privmsg_limit(&privmsg.channel), now)
+        });
+        let join_ready =
+            (!self.joins.is_empty()).then(|| self.join_limiter.ready_at(JOIN_LIMIT, now));
+        privmsg_ready.into_iter().chain(join_ready).min()
+    }
+
+    pub fn pop_join(&mut self, now: Instant) -> Option<String> {
+        if !self.joins.is_empty() && self.join_limiter.try_acquire(JOIN_LIMIT, now) {
+            self.joins.pop_front()
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn privmsg(channel: &str, message: &str) -> QueuedPrivmsg {
+        QueuedPrivmsg {
+            id: None,
+            channel: channel.to_owned(),
+            message: message.to_owned(),
+        }
+    }
+
+    // Queues messages for a channel, as many as fit
+    fn queue(outbox: &mut Outbox, channel: &str, count: usize) {
+        for i in 0..count {
+            assert!(outbox
+                .push_privmsg(privmsg(channel, &i.to_string()))
+                .is_ok());
+        }
+    }
+
+    fn pop_all(outbox: &mut Outbox, now: Instant) -> usize {
+        std::iter::from_fn(|| outbox.pop_privmsg(now)).count()
+    }
+
+    fn secs(secs: f64) -> Duration {
+        Duration::from_secs_f64(secs)
+    }
+
+    #[test]
+    fn test_privmsg_limit() {
+        let start = Instant::now();
+        let mut outbox = Outbox::new();
+        queue(&mut outbox, "forsen", 20);
+        assert_eq!(pop_all(&mut outbox, start), 20);
+
+        queue(&mut outbox, "forsen", 1);
+        assert_eq!(outbox.next_ready(start), Some(start + PRIVMSG_PERIOD));
+        // Still in the window right up to the edge
+        assert_eq!(pop_all(&mut outbox, start + secs(29.999)), 0);
+        assert_eq!(pop_all(&mut outbox, start + PRIVMSG_PERIOD), 1);
+    }
+
+    #[test]
+    fn test_window_slides() {
+        let start = Instant::now();
+        let mut outbox = Outbox::new();
+        queue(&mut outbox, "forsen", 10);
+        assert_eq!(pop_all(&mut outbox, start), 10);
+        queue(&mut outbox, "forsen", 15);
+        assert_eq!(pop_all(&mut outbox, start + secs(15.0)), 10);
+
+        // Only the first ten have left the window by now, not all twenty
+        assert_eq!(outbox.next_ready(start), Some(start + PRIVMSG_PERIOD));
+        assert_eq!(pop_all(&mut outbox, start + PRIVMSG_PERIOD), 5);
+    }
+
+    #[test]
+    fn test_no_window_goes_over_the_limit() {
+        // Keep the queue full and try to send every 100ms for a few minutes
+        let start = Instant::now();
+        let mut outbox = Outbox::new();
+        let mut sent = Vec::new();
+        for tick in 0..2000 {
+            let now = start + Duration::from_millis(100 * tick);
+            while outbox.push_privmsg(privmsg("forsen", "forsenE")).is_ok() {}
+            while outbox.pop_privmsg(now).is_some() {
+                sent.push(now);
+            }
+        }
+
+        for (i, &first) in sent.iter().enumerate() {
+            let in_window = sent[i..]
+                .iter()
+                .take_while(|&&later| later - first < PRIVMSG_PERIOD)
+                .count();
+            assert!(in_window <= PRIVMSG_LIMIT as usize);
+        }
+        // And it doesn't hold back more than it has to either
+        assert_eq!(sent.len(), 20 * 7);
+    }
+
+    #[test]
+    fn test_elevated_limit() {
+        let start = Instant::now();
+        let mut outbox = Outbox::new();
+        outbox.set_elevated("forsen", true);
+        for _ in 0..5 {
+            queue(&mut outbox, "forsen", 20);
+            assert_eq!(pop_all(&mut outbox, start), 20);
+        }
+        queue(&mut outbox, "forsen", 1);
+        assert_eq!(pop_all(&mut outbox, start), 0);
+        assert_eq!(outbox.next_ready(start), Some(start + PRIVMSG_PERIOD));
+
+        // Back to the normal limit once USERSTATE says we lost the badge
+        outbox.set_elevated("forsen", false);
+        assert_eq!(pop_all(&mut outbox, start + PRIVMSG_PERIOD), 1);
+        queue(&mut outbox, "forsen", 20);
+        assert_eq!(pop_all(&mut outbox, start + PRIVMSG_PERIOD), 19);
+    }
+
+    #[test]
+    fn test_elevated_channels_share_the_limit() {
+        let start = Instant::now();
+        let mut outbox = Outbox::new();
+        outbox.set_elevated("forsen", true);
+
+        // Messages to other channels count towards the elevated limit
+        queue(&mut outbox, "nymn", 20);
+        assert_eq!(pop_all(&mut outbox, start), 20);
+        for _ in 0..4 {
+            queue(&mut outbox, "forsen", 20);
+            assert_eq!(pop_all(&mut outbox, start), 20);
+        }
+        assert_eq!(pop_all(&mut outbox, start), 0);
+
+        // and messages to elevated channels count towards the normal one, so the other channel
+        // has to wait until fewer than 20 are left in the window
+        queue(&mut outbox, "forsen", 20);
+        assert_eq!(pop_all(&mut outbox, start + secs(1.0)), 0);
+        assert_eq!(pop_all(&mut outbox, start + PRIVMSG_PERIOD), 20);
+        queue(&mut outbox, "nymn", 1);
+        assert_eq!(
+            outbox.next_ready(start + PRIVMSG_PERIOD),
+            Some(start + PRIVMSG_PERIOD * 2)
+        );
+    }
+
+    #[test]
+    fn test_waiting_message_holds_back_the_rest() {
+        let start = Instant::now();
+        let mut outbox = Outbox::new();
+        outbox.set_elevated("forsen", true);
+        queue(&mut outbox, "nymn", 20);
+        assert_eq!(pop_all(&mut outbox, start), 20);
+
+        queue(&mut outbox, "nymn", 1);
+        queue(&mut outbox, "forsen", 1);
+        assert_eq!(pop_all(&mut outbox, start), 0);
+        assert_eq!(pop_all(&mut outbox, start + PRIVMSG_PERIOD), 2);
+    }
+
+    #[test]
+    fn test_queue_overflow() {
+        let mut outbox = Outbox::new();
+        queue(&mut outbox, "forsen", MAX_QUEUED_PRIVMSGS);
+        let dropped = outbox
+            .push_privmsg(privmsg("forsen", "forsenE"))
+            .unwrap_err();
+        assert_eq!(dropped.message, "forsenE");
+
+        // Sending one makes room for one
+        assert!(outbox.pop_privmsg(Instant::now()).is_some());
+        assert!(outbox.push_privmsg(privmsg("forsen", "forsenE")).is_ok());
+        assert!(outbox.push_privmsg(privmsg("forsen", "forsenE")).is_err());
+    }
+
+    #[test]
+    fn test_join_limit() {
+        let start = Instant::now();
+        let mut outbox = Outbox::new();
+        for i in 0..25 {
+            outbox.push_join(format!("channel{}", i));
+        }
+        outbox.push_join("channel0".to_owned());
+
+        let joined: Vec<String> = std::iter::from_fn(|| outbox.pop_join(start)).collect();
+        assert_eq!(joined.len(), 20);
+        assert_eq!(joined[0], "channel0");
+        assert_eq!(outbox.next_ready(start), Some(start + JOIN_PERIOD));
+        assert_eq!(outbox.pop_join(start + secs(9.999)), None);
+
+        assert!(outbox.cancel_join("channel24"));
+        assert!(!outbox.cancel_join("channel0"));
+        let joined: Vec<String> =
+            std::iter::from_fn(|| outbox.pop_join(start + JOIN_PERIOD)).collect();
+        assert_eq!(joined, ["channel20", "channel21", "channel22", "channel23"]);
+        assert_eq!(outbox.next_ready(start + JOIN_PERIOD), None);
+    }
+}
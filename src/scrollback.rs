@@ -0,0 +1,396 @@
+// Each tab keeps at most a fixed number of chat items in memory, dropping the oldest ones as new
+// ones come in. With a scrollback directory set, dropped items are appended to a file per tab
+// first, and scrolling past the top of the tab reads them back in a page at a time.
+//
+// The file has one item per line, as its kind and its fields separated by tabs. Fields escape
+// backslashes, tabs and line breaks with a backslash. Only the items dropped in this session are
+// paged back in, so the file is emptied whenever its tab is opened, which also keeps it from
+// growing from one session to the next.
+
+use std::collections::VecDeque;
+use std::fs::{create_dir_all, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use tuisen::actions::OutgoingState;
+
+use crate::app::ChatItem;
+
+pub const DEFAULT_SCROLLBACK_SIZE: usize = 10_000;
+
+// How many items to read back in at once
+pub const PAGE_SIZE: usize = 200;
+
+// How much of the file to read at a time while looking for the start of a page
+const CHUNK_SIZE: u64 = 16 * 1024;
+
+#[derive(Clone)]
+pub struct ScrollbackConfig {
+    // How many items each tab keeps in memory
+    pub max_items: usize,
+    pub spill_dir: Option<PathBuf>,
+}
+
+impl Default for ScrollbackConfig {
+    fn default() -> Self {
+        ScrollbackConfig {
+            max_items: DEFAULT_SCROLLBACK_SIZE,
+            spill_dir: None,
+        }
+    }
+}
+
+// Items are numbered in the order they were pushed to their tab, starting at 0, and the numbers
+// stay the same when older items are dropped.
+pub struct SpillFile {
+    file: File,
+    // Where this session's items start, and where the file ends
+    start: u64,
+    end: u64,
+    // Items numbered below this are in the file
+    pub spilled: usize,
+    // Where each of the items that were paged back in starts in the file, oldest first. The
+    // items before the first of these (or before the end, if there are none) are only in the file.
+    loaded: VecDeque<u64>,
+}
+
+impl SpillFile {
+    pub fn open(dir: &Path, name: &str) -> io::Result<Self> {
+        create_dir_all(dir)?;
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(dir.join(format!("{}.log", name)))?;
+        file.set_len(0)?;
+        Ok(SpillFile {
+            file,
+            start: 0,
+            end: 0,
+            spilled: 0,
+            loaded: VecDeque::new(),
+        })
+    }
+
+    // Called with each item as it's dropped from memory, in order
+    pub fn drop_item(&mut self, number: usize, item: &ChatItem) -> io::Result<()> {
+        if number < self.spilled {
+            // Paged in earlier, so it's in the file already
+            self.loaded.pop_front();
+            return Ok(());
+        }
+        let mut line = encode_item(item);
+        line.push('\n');
+        self.file.write_all(line.as_bytes())?;
+        self.end += line.len() as u64;
+        self.spilled = number + 1;
+        Ok(())
+    }
+
+    // Whether there's anything left to page in
+    pub fn has_older(&self) -> bool {
+        self.unloaded_end() > self.start
+    }
+
+    // Reads back up to count of the newest items that are only in the file, oldest first
+    pub fn read_older(&mut self, count: usize) -> io::Result<Vec<ChatItem>> {
+        let unloaded_end = self.unloaded_end();
+        let mut position = unloaded_end;
+        let mut data = Vec::new();
+        // Every line ends with a newline, so count + 1 of them means we have count whole lines
+        while position > self.start && data.iter().filter(|&&b| b == b'\n').count() <= count {
+            let chunk_size = CHUNK_SIZE.min(position - self.start);
+            position -= chunk_size;
+            let mut chunk = vec![0; chunk_size as usize];
+            self.file.seek(SeekFrom::Start(position))?;
+            self.file.read_exact(&mut chunk)?;
+            chunk.extend(data);
+            data = chunk;
+        }
+
+        let mut lines: Vec<&[u8]> = data[..data.len().saturating_sub(1)]
+            .split(|&b| b == b'\n')
+            .collect();
+        // The first line might be cut off, unless we got to the start
+        if position > self.start || data.is_empty() {
+            lines.remove(0);
+        }
+        let lines = &lines[lines.len().saturating_sub(count)..];
+
+        let mut offset = unloaded_end;
+        let mut offsets = Vec::with_capacity(lines.len());
+        let mut items = Vec::with_capacity(lines.len());
+        for line in lines.iter().rev() {
+            offset -= line.len() as u64 + 1;
+            offsets.push(offset);
+            items.push(decode_item(&String::from_utf8_lossy(line)));
+        }
+        for offset in offsets {
+            self.loaded.push_front(offset);
+        }
+        items.reverse();
+        Ok(items)
+    }
+
+    // Forgets what's in the file, so it isn't paged in anymore. next is the number of the next
+    // item that will be pushed.
+    pub fn forget(&mut self, next: usize) {
+        self.start = self.end;
+        self.spilled = next;
+        self.loaded.clear();
+    }
+
+    fn unloaded_end(&self) -> u64 {
+        self.loaded.front().copied().unwrap_or(self.end)
+    }
+}
+
+// Our own messages are logged like anyone else's once they're sent. By the time they're dropped,
+// nothing is going to change their state anymore, so the others are logged as they're shown.
+fn encode_item(item: &ChatItem) -> String {
+    let fields = match item {
+        ChatItem::Privmsg { username, message }
+        | ChatItem::Outgoing {
+            username,
+            message,
+            state: OutgoingState::Sent,
+            ..
+        } => vec!["privmsg", username.as_str(), message.as_str()],
+        ChatItem::Outgoing { .. } => return format!("debug\t{}", escape(&item.unwrapped())),
+        ChatItem::Debug { content } => vec!["debug", content.as_str()],
+    };
+    fields
+        .into_iter()
+        .map(escape)
+        .collect::<Vec<String>>()
+        .join("\t")
+}
+
+// Anything we can't make sense of comes back as it is in the file
+fn decode_item(line: &str) -> ChatItem {
+    let fields: Vec<String> = line.split('\t').map(unescape).collect();
+    match fields.as_slice() {
+        [kind, username, message] if kind == "privmsg" => ChatItem::Privmsg {
+            username: username.to_owned(),
+            message: message.to_owned(),
+        },
+        [kind, content] if kind == "debug" => ChatItem::Debug {
+            content: content.to_owned(),
+        },
+        _ => ChatItem::Debug {
+            content: line.to_owned(),
+        },
+    }
+}
+
+fn escape(field: &str) -> String {
+    let mut escaped = String::with_capacity(field.len());
+    for c in field.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\t' => escaped.push_str("\\t"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn unescape(field: &str) -> String {
+    let mut unescaped = String::with_capacity(field.len());
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => unescaped.push('\t'),
+            Some('n') => unescaped.push('\n'),
+            Some('r') => unescaped.push('\r'),
+            Some(other) => unescaped.push(other),
+            None => unescaped.push('\\'),
+        }
+    }
+    unescaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    use crate::testutil::TempDir;
+
+    fn privmsg(number: usize) -> ChatItem {
+        // Long enough that a page spans several chunks of the file
+        ChatItem::Privmsg {
+            username: format!("user{}", number),
+            message: format!("message {} {}", number, "forsenE ".repeat(number % 20)),
+        }
+    }
+
+    fn texts(items: &[ChatItem]) -> Vec<String> {
+        items.iter().map(ChatItem::unwrapped).collect()
+    }
+
+    fn expected(numbers: std::ops::Range<usize>) -> Vec<String> {
+        numbers.map(|number| privmsg(number).unwrapped()).collect()
+    }
+
+    #[test]
+    fn test_escape_round_trip() {
+        for field in [
+            "",
+            "forsenE",
+            "a\tb\nc\rd",
+            "back\\slash",
+            "\\t is not a tab",
+            "trailing\\",
+            "\\\\\t\\",
+        ] {
+            let escaped = escape(field);
+            assert!(!escaped.contains(['\t', '\n', '\r']), "{:?}", escaped);
+            assert_eq!(unescape(&escaped), field);
+        }
+        // A lone backslash at the end is kept as it is
+        assert_eq!(unescape("a\\"), "a\\");
+    }
+
+    #[test]
+    fn test_encode_round_trip() {
+        let items = [
+            ChatItem::Privmsg {
+                username: "forsen".to_owned(),
+                message: "tab\there\nand a line break".to_owned(),
+            },
+            ChatItem::Debug {
+                content: "[client] Connected\\".to_owned(),
+            },
+        ];
+        for item in &items {
+            let line = encode_item(item);
+            assert!(!line.contains('\n'));
+            assert_eq!(decode_item(&line).unwrapped(), item.unwrapped());
+        }
+    }
+
+    #[test]
+    fn test_encode_outgoing() {
+        let outgoing = |state| ChatItem::Outgoing {
+            id: 1,
+            username: "forsen".to_owned(),
+            message: "forsenE".to_owned(),
+            state,
+        };
+        // Sent messages come back like anyone else's, the others as they were shown
+        let sent = decode_item(&encode_item(&outgoing(OutgoingState::Sent)));
+        assert!(matches!(sent, ChatItem::Privmsg { .. }));
+        assert_eq!(sent.unwrapped(), "forsen: forsenE");
+        let dropped = decode_item(&encode_item(&outgoing(OutgoingState::Dropped)));
+        assert!(matches!(dropped, ChatItem::Debug { .. }));
+        assert_eq!(dropped.unwrapped(), "forsen: forsenE [not sent]");
+    }
+
+    #[test]
+    fn test_decode_garbage() {
+        let item = decode_item("privmsg\tonly a username");
+        assert!(matches!(item, ChatItem::Debug { .. }));
+        assert_eq!(item.unwrapped(), "privmsg\tonly a username");
+    }
+
+    #[test]
+    fn test_page_back_in() {
+        let dir = TempDir::new("scrollback-page");
+        let mut spill_file = SpillFile::open(dir.path(), "#forsen").unwrap();
+        assert!(!spill_file.has_older());
+
+        let count = 1000;
+        for number in 0..count {
+            spill_file.drop_item(number, &privmsg(number)).unwrap();
+        }
+        let len = fs::metadata(dir.path().join("#forsen.log")).unwrap().len();
+        assert!(len > 4 * CHUNK_SIZE, "only {} bytes", len);
+
+        // Newest first, a page at a time, each page in order
+        let mut end = count;
+        while spill_file.has_older() {
+            let items = spill_file.read_older(PAGE_SIZE).unwrap();
+            let start = end - items.len();
+            assert_eq!(texts(&items), expected(start..end));
+            end = start;
+        }
+        assert_eq!(end, 0);
+        assert!(spill_file.read_older(PAGE_SIZE).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_drop_paged_in_items_again() {
+        let dir = TempDir::new("scrollback-redrop");
+        let mut spill_file = SpillFile::open(dir.path(), "#forsen").unwrap();
+        for number in 0..500 {
+            spill_file.drop_item(number, &privmsg(number)).unwrap();
+        }
+        let path = dir.path().join("#forsen.log");
+        let len = fs::metadata(&path).unwrap().len();
+
+        let items = spill_file.read_older(300).unwrap();
+        assert_eq!(texts(&items), expected(200..500));
+
+        // Dropping paged-in items doesn't write them again, and makes them available to page in
+        // once more
+        for number in 200..350 {
+            spill_file.drop_item(number, &privmsg(number)).unwrap();
+        }
+        assert_eq!(fs::metadata(&path).unwrap().len(), len);
+        assert_eq!(
+            texts(&spill_file.read_older(200).unwrap()),
+            expected(150..350)
+        );
+
+        // New items still go to the end of the file
+        spill_file.drop_item(500, &privmsg(500)).unwrap();
+        assert!(fs::metadata(&path).unwrap().len() > len);
+        assert_eq!(
+            texts(&spill_file.read_older(100).unwrap()),
+            expected(50..150)
+        );
+    }
+
+    #[test]
+    fn test_reopen_empties_file() {
+        let dir = TempDir::new("scrollback-reopen");
+        let mut spill_file = SpillFile::open(dir.path(), "system").unwrap();
+        for number in 0..10 {
+            spill_file.drop_item(number, &privmsg(number)).unwrap();
+        }
+        drop(spill_file);
+
+        let spill_file = SpillFile::open(dir.path(), "system").unwrap();
+        assert!(!spill_file.has_older());
+        assert_eq!(
+            fs::metadata(dir.path().join("system.log")).unwrap().len(),
+            0
+        );
+    }
+
+    #[test]
+    fn test_forget() {
+        let dir = TempDir::new("scrollback-forget");
+        let mut spill_file = SpillFile::open(dir.path(), "system").unwrap();
+        for number in 0..10 {
+            spill_file.drop_item(number, &privmsg(number)).unwrap();
+        }
+        spill_file.read_older(5).unwrap();
+        spill_file.forget(20);
+        assert!(!spill_file.has_older());
+
+        // Only what's dropped after that comes back
+        for number in 20..25 {
+            spill_file.drop_item(number, &privmsg(number)).unwrap();
+        }
+        assert_eq!(texts(&spill_file.read_older(10).unwrap()), expected(20..25));
+    }
+}
@@ -0,0 +1,707 @@
+// Scrollback search. Queries are plain text or regular expressions, optionally restricted to one
+// user's messages with a "from:<user>" term.
+//
+// The regular expressions are a small backtracking subset: literals, '.', classes like [a-z] and
+// [^0-9], the escapes \d \w \s (and their negations \D \W \S) and \b, anchors ^ and $, groups,
+// alternation with '|', and the greedy quantifiers * + ? {n} {n,} {n,m}.
+
+use std::cell::Cell;
+
+// Pathological patterns like (a*)*b can take forever to fail; give up on a line after this many
+// steps
+const MAX_STEPS: usize = 100_000;
+
+#[derive(Debug)]
+pub enum PatternError {
+    UnbalancedParenthesis,
+    UnclosedClass,
+    BadRepetition,
+    TrailingBackslash,
+}
+
+enum Node {
+    Char(char),
+    Any,
+    Class {
+        negated: bool,
+        items: Vec<ClassItem>,
+    },
+    Start,
+    End,
+    WordBoundary,
+    Concat(Vec<Node>),
+    Alternation(Vec<Node>),
+    Repeat {
+        node: Box<Node>,
+        min: u32,
+        max: Option<u32>,
+    },
+}
+
+enum ClassItem {
+    Range(char, char),
+    Digit(bool),
+    Word(bool),
+    Space(bool),
+}
+
+pub struct Pattern {
+    root: Node,
+    case_sensitive: bool,
+}
+
+impl Pattern {
+    pub fn literal(text: &str, case_sensitive: bool) -> Self {
+        Pattern {
+            root: Node::Concat(text.chars().map(Node::Char).collect()),
+            case_sensitive,
+        }
+    }
+
+    pub fn regex(text: &str, case_sensitive: bool) -> Result<Self, PatternError> {
+        let mut parser = Parser {
+            chars: text.chars().collect(),
+            pos: 0,
+        };
+        let root = parser.alternation()?;
+        if parser.pos < parser.chars.len() {
+            // The only thing that stops an alternation early is a ')' without a '('
+            return Err(PatternError::UnbalancedParenthesis);
+        }
+        Ok(Pattern {
+            root,
+            case_sensitive,
+        })
+    }
+
+    pub fn is_match(&self, haystack: &str) -> bool {
+        let chars: Vec<char> = haystack.chars().collect();
+        let steps = Cell::new(0);
+        (0..=chars.len()).any(|start| self.match_at(&chars, start, &steps).is_some())
+    }
+
+    // Byte ranges of the non-overlapping, non-empty matches
+    pub fn find_all(&self, haystack: &str) -> Vec<(usize, usize)> {
+        let offsets: Vec<usize> = haystack
+            .char_indices()
+            .map(|(i, _)| i)
+            .chain([haystack.len()])
+            .collect();
+        let chars: Vec<char> = haystack.chars().collect();
+        let steps = Cell::new(0);
+        let mut found = Vec::new();
+        let mut start = 0;
+        while start < chars.len() {
+            match self.match_at(&chars, start, &steps) {
+                Some(end) if end > start => {
+                    found.push((offsets[start], offsets[end]));
+                    start = end;
+                }
+                _ => start += 1,
+            }
+        }
+        found
+    }
+
+    // The end of the (greedy) match starting at start
+    fn match_at(&self, chars: &[char], start: usize, steps: &Cell<usize>) -> Option<usize> {
+        let mut end = None;
+        let matcher = Matcher {
+            chars,
+            case_sensitive: self.case_sensitive,
+            steps,
+        };
+        matcher.node(&self.root, start, &mut |pos| {
+            end = Some(pos);
+            true
+        });
+        end
+    }
+}
+
+struct Matcher<'a> {
+    chars: &'a [char],
+    case_sensitive: bool,
+    steps: &'a Cell<usize>,
+}
+
+impl Matcher<'_> {
+    // Matches node at pos, then calls next with where it ended. Backtracks into other ways of
+    // matching node until next returns true.
+    fn node(&self, node: &Node, pos: usize, next: &mut dyn FnMut(usize) -> bool) -> bool {
+        self.steps.set(self.steps.get() + 1);
+        if self.steps.get() > MAX_STEPS {
+            return false;
+        }
+        let current = self.chars.get(pos).copied();
+        match node {
+            Node::Char(expected) => {
+                current.is_some_and(|c| self.chars_equal(c, *expected)) && next(pos + 1)
+            }
+            Node::Any => current.is_some() && next(pos + 1),
+            Node::Class { negated, items } => {
+                current.is_some_and(|c| self.class_contains(items, c) != *negated) && next(pos + 1)
+            }
+            Node::Start => pos == 0 && next(pos),
+            Node::End => pos == self.chars.len() && next(pos),
+            Node::WordBoundary => {
+                let before = pos > 0 && is_word_char(self.chars[pos - 1]);
+                let after = current.is_some_and(is_word_char);
+                before != after && next(pos)
+            }
+            Node::Concat(nodes) => self.sequence(nodes, pos, next),
+            Node::Alternation(options) => {
+                for option in options {
+                    if self.node(option, pos, next) {
+                        return true;
+                    }
+                }
+                false
+            }
+            Node::Repeat { node, min, max } => self.repeat(node, *min, *max, 0, pos, next),
+        }
+    }
+
+    fn sequence(&self, nodes: &[Node], pos: usize, next: &mut dyn FnMut(usize) -> bool) -> bool {
+        match nodes.split_first() {
+            None => next(pos),
+            Some((first, rest)) => self.node(first, pos, &mut |p| self.sequence(rest, p, next)),
+        }
+    }
+
+    // Greedy: tries one more repetition before settling for what we have
+    fn repeat(
+        &self,
+        node: &Node,
+        min: u32,
+        max: Option<u32>,
+        count: u32,
+        pos: usize,
+        next: &mut dyn FnMut(usize) -> bool,
+    ) -> bool {
+        if max.is_none_or(|max| count < max) {
+            // A repetition that matches nothing would loop forever
+            let more = self.node(node, pos, &mut |p| {
+                p != pos && self.repeat(node, min, max, count + 1, p, next)
+            });
+            if more {
+                return true;
+            }
+        }
+        count >= min && next(pos)
+    }
+
+    fn chars_equal(&self, a: char, b: char) -> bool {
+        a == b || (!self.case_sensitive && a.to_lowercase().eq(b.to_lowercase()))
+    }
+
+    fn class_contains(&self, items: &[ClassItem], c: char) -> bool {
+        let variants: Vec<char> = if self.case_sensitive {
+            vec![c]
+        } else {
+            c.to_lowercase()
+                .chain(c.to_uppercase())
+                .chain([c])
+                .collect()
+        };
+        items.iter().any(|item| {
+            variants.iter().any(|&c| match *item {
+                ClassItem::Range(low, high) => low <= c && c <= high,
+                ClassItem::Digit(negated) => c.is_ascii_digit() != negated,
+                ClassItem::Word(negated) => is_word_char(c) != negated,
+                ClassItem::Space(negated) => c.is_whitespace() != negated,
+            })
+        })
+    }
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn alternation(&mut self) -> Result<Node, PatternError> {
+        let mut options = vec![self.concat()?];
+        while self.peek() == Some('|') {
+            self.pos += 1;
+            options.push(self.concat()?);
+        }
+        Ok(if options.len() == 1 {
+            options.pop().unwrap()
+        } else {
+            Node::Alternation(options)
+        })
+    }
+
+    fn concat(&mut self) -> Result<Node, PatternError> {
+        let mut nodes = Vec::new();
+        while let Some(c) = self.peek() {
+            if c == '|' || c == ')' {
+                break;
+            }
+            let atom = self.atom()?;
+            nodes.push(self.quantified(atom)?);
+        }
+        Ok(Node::Concat(nodes))
+    }
+
+    fn atom(&mut self) -> Result<Node, PatternError> {
+        let c = self.chars[self.pos];
+        self.pos += 1;
+        Ok(match c {
+            '.' => Node::Any,
+            '^' => Node::Start,
+            '$' => Node::End,
+            '(' => {
+                let inner = self.alternation()?;
+                if self.peek() != Some(')') {
+                    return Err(PatternError::UnbalancedParenthesis);
+                }
+                self.pos += 1;
+                inner
+            }
+            '[' => self.class()?,
+            '\\' => match self.escape()? {
+                Escaped::Char(c) => Node::Char(c),
+                Escaped::Class(item) => Node::Class {
+                    negated: false,
+                    items: vec![item],
+                },
+                Escaped::WordBoundary => Node::WordBoundary,
+            },
+            '*' | '+' | '?' => return Err(PatternError::BadRepetition),
+            c => Node::Char(c),
+        })
+    }
+
+    fn quantified(&mut self, atom: Node) -> Result<Node, PatternError> {
+        let (min, max) = match self.peek() {
+            Some('{') => match self.braces() {
+                Some(bounds) => bounds,
+                // Not a repetition after all, so the brace is a literal
+                None => return Ok(atom),
+            },
+            Some(c @ ('*' | '+' | '?')) => {
+                self.pos += 1;
+                match c {
+                    '*' => (0, None),
+                    '+' => (1, None),
+                    _ => (0, Some(1)),
+                }
+            }
+            _ => return Ok(atom),
+        };
+        if matches!(atom, Node::Start | Node::End | Node::WordBoundary)
+            || max.is_some_and(|max| max < min)
+        {
+            return Err(PatternError::BadRepetition);
+        }
+        Ok(Node::Repeat {
+            node: Box::new(atom),
+            min,
+            max,
+        })
+    }
+
+    // {n}, {n,} or {n,m}. Consumes them only if they're well formed.
+    fn braces(&mut self) -> Option<(u32, Option<u32>)> {
+        let rest: String = self.chars[self.pos + 1..].iter().collect();
+        let (inside, _) = rest.split_once('}')?;
+        let bounds = match inside.split_once(',') {
+            None => {
+                let n = inside.parse().ok()?;
+                (n, Some(n))
+            }
+            Some((min, "")) => (min.parse().ok()?, None),
+            Some((min, max)) => (min.parse().ok()?, Some(max.parse().ok()?)),
+        };
+        self.pos += inside.chars().count() + 2;
+        Some(bounds)
+    }
+
+    fn class(&mut self) -> Result<Node, PatternError> {
+        let negated = self.peek() == Some('^');
+        if negated {
+            self.pos += 1;
+        }
+        let mut items = Vec::new();
+        let mut first = true;
+        loop {
+            let c = self.peek().ok_or(PatternError::UnclosedClass)?;
+            self.pos += 1;
+            // A ']' right at the start is a literal
+            if c == ']' && !first {
+                break;
+            }
+            first = false;
+            let low = match c {
+                '\\' => match self.escape()? {
+                    Escaped::Char(c) => c,
+                    Escaped::Class(item) => {
+                        items.push(item);
+                        continue;
+                    }
+                    Escaped::WordBoundary => '\u{8}',
+                },
+                c => c,
+            };
+            // A range, unless the '-' is the last thing in the class
+            if self.peek() == Some('-') && self.chars.get(self.pos + 1).is_some_and(|&c| c != ']') {
+                self.pos += 1;
+                let high = match self.chars.get(self.pos).copied() {
+                    Some('\\') => {
+                        self.pos += 1;
+                        match self.escape()? {
+                            Escaped::Char(c) => c,
+                            _ => return Err(PatternError::UnclosedClass),
+                        }
+                    }
+                    Some(c) => {
+                        self.pos += 1;
+                        c
+                    }
+                    None => return Err(PatternError::UnclosedClass),
+                };
+                items.push(ClassItem::Range(low, high));
+            } else {
+                items.push(ClassItem::Range(low, low));
+            }
+        }
+        Ok(Node::Class { negated, items })
+    }
+
+    fn escape(&mut self) -> Result<Escaped, PatternError> {
+        let c = self.peek().ok_or(PatternError::TrailingBackslash)?;
+        self.pos += 1;
+        Ok(match c {
+            'd' => Escaped::Class(ClassItem::Digit(false)),
+            'D' => Escaped::Class(ClassItem::Digit(true)),
+            'w' => Escaped::Class(ClassItem::Word(false)),
+            'W' => Escaped::Class(ClassItem::Word(true)),
+            's' => Escaped::Class(ClassItem::Space(false)),
+            'S' => Escaped::Class(ClassItem::Space(true)),
+            'b' => Escaped::WordBoundary,
+            'n' => Escaped::Char('\n'),
+            't' => Escaped::Char('\t'),
+            c => Escaped::Char(c),
+        })
+    }
+}
+
+enum Escaped {
+    Char(char),
+    Class(ClassItem),
+    WordBoundary,
+}
+
+// A search through one buffer's scrollback
+pub struct ScrollbackSearch {
+    pub query: String,
+    pub regex: bool,
+    pub case_sensitive: bool,
+    // What the query looks for. The pattern is None if there's no text to look for (or the regex
+    // is bad), and the username is None if the query has no from: term.
+    pub pattern: Option<Pattern>,
+    pub error: Option<PatternError>,
+    pub username: Option<String>,
+    pub buffer: usize,
+    // The number of the item we jumped to last, and how many items matched then
+    pub current: Option<usize>,
+    pub match_count: usize,
+}
+
+impl ScrollbackSearch {
+    pub fn new(buffer: usize, regex: bool, case_sensitive: bool) -> Self {
+        ScrollbackSearch {
+            query: String::new(),
+            regex,
+            case_sensitive,
+            pattern: None,
+            error: None,
+            username: None,
+            buffer,
+            current: None,
+            match_count: 0,
+        }
+    }
+
+    // Rebuilds the pattern after the query or the options changed
+    pub fn update(&mut self) {
+        self.username = None;
+        let mut text = Vec::new();
+        for word in self.query.split(' ') {
+            match word.strip_prefix("from:") {
+                Some(username) if !username.is_empty() => {
+                    self.username = Some(username.trim_start_matches('@').to_owned());
+                }
+                _ => text.push(word),
+            }
+        }
+        let text = text.join(" ");
+        let text = text.trim();
+
+        self.pattern = None;
+        self.error = None;
+        if text.is_empty() {
+            return;
+        }
+        if self.regex {
+            match Pattern::regex(text, self.case_sensitive) {
+                Ok(pattern) => self.pattern = Some(pattern),
+                Err(e) => self.error = Some(e),
+            }
+        } else {
+            self.pattern = Some(Pattern::literal(text, self.case_sensitive));
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pattern.is_none() && self.username.is_none()
+    }
+
+    pub fn matches(&self, username: Option<&str>, text: &str) -> bool {
+        if self.is_empty() {
+            return false;
+        }
+        if let Some(ref wanted) = self.username {
+            if !username.is_some_and(|username| username.eq_ignore_ascii_case(wanted)) {
+                return false;
+            }
+        }
+        self.pattern
+            .as_ref()
+            .is_none_or(|pattern| pattern.is_match(text))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn regex(pattern: &str) -> Pattern {
+        Pattern::regex(pattern, true).unwrap()
+    }
+
+    fn matches(pattern: &str, haystack: &str) -> bool {
+        regex(pattern).is_match(haystack)
+    }
+
+    fn query(text: &str, regex: bool) -> ScrollbackSearch {
+        let mut search = ScrollbackSearch::new(0, regex, false);
+        search.query = text.to_owned();
+        search.update();
+        search
+    }
+
+    #[test]
+    fn test_literal() {
+        let pattern = Pattern::literal("a.b(", true);
+        assert!(pattern.is_match("xa.b(y"));
+        assert!(!pattern.is_match("axb("));
+        assert_eq!(pattern.find_all("a.b( a.b("), [(0, 4), (5, 9)]);
+        // The byte ranges count multi-byte characters properly
+        assert_eq!(
+            Pattern::literal("é", true).find_all("aéé"),
+            [(1, 3), (3, 5)]
+        );
+    }
+
+    #[test]
+    fn test_any_and_escapes() {
+        assert!(matches("f.rsen", "forsen"));
+        assert!(!matches("f.rsen", "frsen"));
+        assert!(matches(r"a\.b", "a.b"));
+        assert!(!matches(r"a\.b", "axb"));
+        assert!(matches(r"\d\d", "PogChamp 22"));
+        assert!(!matches(r"\d", "no digits"));
+        assert!(matches(r"^\D+$", "no digits"));
+        assert!(matches(r"\w\s\w", "a b"));
+        assert!(!matches(r"^\W", "a"));
+        assert!(matches(r"a\tb", "a\tb"));
+    }
+
+    #[test]
+    fn test_classes() {
+        assert!(matches("^[a-c]+$", "abcabc"));
+        assert!(!matches("^[a-c]+$", "abcd"));
+        assert!(matches("^[^0-9]+$", "forsen"));
+        assert!(!matches("[^0-9]", "123"));
+        // ']' first and '-' last are literals
+        assert!(matches("^[]a-]+$", "]-a"));
+        assert!(matches(r"^[\d_]+$", "1_2"));
+        assert!(matches(r"[\]]", "]"));
+        assert!(matches(r"[a\-z]", "-"));
+        assert!(!matches(r"[a\-z]", "b"));
+    }
+
+    #[test]
+    fn test_anchors_and_word_boundary() {
+        assert!(matches("^forsen", "forsenE"));
+        assert!(!matches("^forsen", "a forsen"));
+        assert!(matches("forsen$", "a forsen"));
+        assert!(!matches("forsen$", "forsenE"));
+        assert!(matches("^$", ""));
+        assert!(matches(r"\bforsen\b", "hi forsen!"));
+        assert!(!matches(r"\bforsen\b", "forsenE"));
+        assert!(!matches(r"\bforsen\b", "xforsen"));
+    }
+
+    #[test]
+    fn test_groups_and_alternation() {
+        assert!(matches("^(forsen|nymn)E$", "nymnE"));
+        assert!(!matches("^(forsen|nymn)E$", "xqcE"));
+        assert!(matches("^a|b$", "ax"));
+        assert!(matches("^a|b$", "xb"));
+        assert!(matches("^(ab)+$", "ababab"));
+        assert!(!matches("^(ab)+$", "ababa"));
+        assert!(matches("^(|a)b$", "b"));
+    }
+
+    #[test]
+    fn test_repetition() {
+        assert!(matches("^ab*c$", "ac"));
+        assert!(matches("^ab*c$", "abbbc"));
+        assert!(!matches("^ab+c$", "ac"));
+        assert!(matches("^ab?c$", "abc"));
+        assert!(!matches("^ab?c$", "abbc"));
+        assert!(matches("^a{3}$", "aaa"));
+        assert!(!matches("^a{3}$", "aaaa"));
+        assert!(matches("^a{2,}$", "aaaaa"));
+        assert!(!matches("^a{2,}$", "a"));
+        assert!(matches("^a{1,2}$", "aa"));
+        assert!(!matches("^a{1,2}$", "aaa"));
+        // Braces that aren't a repetition are literals
+        assert!(matches("a{x}", "a{x}"));
+        assert!(matches("a{1,x}", "a{1,x}"));
+    }
+
+    #[test]
+    fn test_backtracking() {
+        // The greedy .* has to give back what the rest of the pattern needs
+        assert!(matches("^a.*b.*c$", "aXbYbZc"));
+        assert!(matches("^(a|ab)c$", "abc"));
+        assert!(matches("^(a+)+b$", "aaab"));
+        assert!(matches("^(a*)*$", "aaa"));
+        assert_eq!(regex("a.*b").find_all("a1b2b a3b"), [(0, 9)]);
+    }
+
+    #[test]
+    fn test_pathological_pattern_gives_up() {
+        let haystack = "a".repeat(40);
+        assert!(!matches("^(a*)*b$", &haystack));
+    }
+
+    #[test]
+    fn test_case_insensitive() {
+        let pattern = Pattern::regex("^forsen[a-z]$", false).unwrap();
+        assert!(pattern.is_match("FORSENE"));
+        assert!(pattern.is_match("ForsenE"));
+        assert!(!Pattern::regex("^forsen[a-z]$", true)
+            .unwrap()
+            .is_match("ForsenE"));
+        assert!(Pattern::literal("ÄRRÄ", false).is_match("ärrä"));
+        assert!(Pattern::regex("[^A-Z]", false).unwrap().is_match("1"));
+    }
+
+    #[test]
+    fn test_find_all_skips_empty_matches() {
+        assert_eq!(regex("b*").find_all("abba"), [(1, 3)]);
+        assert!(regex("^").find_all("abc").is_empty());
+    }
+
+    #[test]
+    fn test_invalid_patterns() {
+        assert!(matches!(
+            Pattern::regex("(a", true),
+            Err(PatternError::UnbalancedParenthesis)
+        ));
+        assert!(matches!(
+            Pattern::regex("a)", true),
+            Err(PatternError::UnbalancedParenthesis)
+        ));
+        assert!(matches!(
+            Pattern::regex("[a-", true),
+            Err(PatternError::UnclosedClass)
+        ));
+        assert!(matches!(
+            Pattern::regex("[abc", true),
+            Err(PatternError::UnclosedClass)
+        ));
+        assert!(matches!(
+            Pattern::regex("*a", true),
+            Err(PatternError::BadRepetition)
+        ));
+        assert!(matches!(
+            Pattern::regex("a|+", true),
+            Err(PatternError::BadRepetition)
+        ));
+        assert!(matches!(
+            Pattern::regex("^*", true),
+            Err(PatternError::BadRepetition)
+        ));
+        // Only greedy quantifiers, and one at a time
+        assert!(matches!(
+            Pattern::regex("a.*?", true),
+            Err(PatternError::BadRepetition)
+        ));
+        assert!(matches!(
+            Pattern::regex("a{3,1}", true),
+            Err(PatternError::BadRepetition)
+        ));
+        assert!(matches!(
+            Pattern::regex("a\\", true),
+            Err(PatternError::TrailingBackslash)
+        ));
+    }
+
+    #[test]
+    fn test_search_query() {
+        let search = query("forsen", false);
+        assert!(search.matches(Some("nymn"), "hi FORSEN"));
+        assert!(!search.matches(Some("nymn"), "hi nymn"));
+
+        // Not a regex unless asked for
+        let search = query(".*", false);
+        assert!(!search.matches(None, "anything"));
+        assert!(search.matches(None, "a .* b"));
+
+        let search = query("[", true);
+        assert!(search.pattern.is_none());
+        assert!(matches!(search.error, Some(PatternError::UnclosedClass)));
+        assert!(!search.matches(None, "["));
+
+        let search = query("", true);
+        assert!(search.is_empty());
+        assert!(!search.matches(None, ""));
+    }
+
+    #[test]
+    fn test_username_filter() {
+        let search = query("from:@Forsen", false);
+        assert_eq!(search.username.as_deref(), Some("Forsen"));
+        assert!(search.pattern.is_none());
+        // On its own, it matches everything the user said
+        assert!(search.matches(Some("forsen"), "anything"));
+        assert!(!search.matches(Some("nymn"), "anything"));
+        assert!(!search.matches(None, "forsen"));
+
+        let search = query("Pog from:forsen Champ", false);
+        assert!(search.matches(Some("forsen"), "pog champ"));
+        assert!(!search.matches(Some("forsen"), "pog"));
+        assert!(!search.matches(Some("nymn"), "pog champ"));
+
+        // An empty from: is just text
+        let search = query("from:", false);
+        assert!(search.username.is_none());
+        assert!(search.matches(Some("forsen"), "from: here"));
+    }
+}
@@ -0,0 +1,541 @@
+// Minimal bindings to the system OpenSSL (libssl/libcrypto), just enough for a verified TLS client
+// connection. Only built with the "tls" feature.
+
+use std::ffi::{c_char, c_int, c_long, c_short, c_uchar, c_ulong, c_void, CStr, CString};
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, TcpStream};
+use std::os::fd::{AsRawFd, RawFd};
+use std::path::Path;
+use std::ptr;
+use std::sync::{Arc, Mutex};
+
+#[repr(C)]
+struct SslMethod {
+    _private: [u8; 0],
+}
+
+#[repr(C)]
+struct SslCtx {
+    _private: [u8; 0],
+}
+
+#[repr(C)]
+struct Ssl {
+    _private: [u8; 0],
+}
+
+#[repr(C)]
+struct X509VerifyParam {
+    _private: [u8; 0],
+}
+
+#[repr(C)]
+struct PollFd {
+    fd: c_int,
+    events: c_short,
+    revents: c_short,
+}
+
+// Values from the OpenSSL headers (ssl.h, tls1.h and x509_vfy.h)
+const SSL_VERIFY_PEER: c_int = 0x01;
+const SSL_CTRL_SET_TLSEXT_HOSTNAME: c_int = 55;
+const TLSEXT_NAMETYPE_HOST_NAME: c_long = 0;
+
+const SSL_ERROR_WANT_READ: c_int = 2;
+const SSL_ERROR_WANT_WRITE: c_int = 3;
+const SSL_ERROR_SYSCALL: c_int = 5;
+const SSL_ERROR_ZERO_RETURN: c_int = 6;
+
+const X509_V_OK: c_long = 0;
+
+// Values from poll.h
+const POLLIN: c_short = 0x001;
+const POLLOUT: c_short = 0x004;
+// Wait for as long as it takes. Shutting the socket down wakes the poll up.
+const POLL_NO_TIMEOUT: c_int = -1;
+
+// The buffer size OpenSSL's own ERR_error_string uses; longer messages are truncated
+const ERROR_STRING_LEN: usize = 256;
+
+#[link(name = "ssl")]
+extern "C" {
+    fn TLS_client_method() -> *const SslMethod;
+    fn SSL_CTX_new(method: *const SslMethod) -> *mut SslCtx;
+    fn SSL_CTX_free(ctx: *mut SslCtx);
+    fn SSL_CTX_set_verify(
+        ctx: *mut SslCtx,
+        mode: c_int,
+        callback: Option<extern "C" fn(c_int, *mut c_void) -> c_int>,
+    );
+    fn SSL_CTX_set_default_verify_paths(ctx: *mut SslCtx) -> c_int;
+    fn SSL_CTX_load_verify_locations(
+        ctx: *mut SslCtx,
+        ca_file: *const c_char,
+        ca_path: *const c_char,
+    ) -> c_int;
+    fn SSL_new(ctx: *mut SslCtx) -> *mut Ssl;
+    fn SSL_free(ssl: *mut Ssl);
+    fn SSL_set_fd(ssl: *mut Ssl, fd: c_int) -> c_int;
+    fn SSL_ctrl(ssl: *mut Ssl, cmd: c_int, larg: c_long, parg: *mut c_void) -> c_long;
+    fn SSL_set1_host(ssl: *mut Ssl, hostname: *const c_char) -> c_int;
+    fn SSL_get0_param(ssl: *mut Ssl) -> *mut X509VerifyParam;
+    fn SSL_connect(ssl: *mut Ssl) -> c_int;
+    fn SSL_read(ssl: *mut Ssl, buf: *mut c_void, num: c_int) -> c_int;
+    fn SSL_write(ssl: *mut Ssl, buf: *const c_void, num: c_int) -> c_int;
+    fn SSL_get_error(ssl: *const Ssl, ret: c_int) -> c_int;
+    fn SSL_get_verify_result(ssl: *const Ssl) -> c_long;
+    fn SSL_shutdown(ssl: *mut Ssl) -> c_int;
+}
+
+#[link(name = "crypto")]
+extern "C" {
+    fn ERR_get_error() -> c_ulong;
+    fn ERR_error_string_n(error: c_ulong, buf: *mut c_char, len: usize);
+    fn ERR_clear_error();
+    fn X509_verify_cert_error_string(result: c_long) -> *const c_char;
+    fn X509_VERIFY_PARAM_set1_ip(
+        param: *mut X509VerifyParam,
+        ip: *const c_uchar,
+        iplen: usize,
+    ) -> c_int;
+}
+
+extern "C" {
+    fn poll(fds: *mut PollFd, nfds: c_ulong, timeout: c_int) -> c_int;
+}
+
+// Pops the most recent OpenSSL error into an io::Error
+fn openssl_error(context: &str) -> io::Error {
+    let mut message = String::new();
+    unsafe {
+        loop {
+            let code = ERR_get_error();
+            if code == 0 {
+                break;
+            }
+            let mut buf = [0 as c_char; ERROR_STRING_LEN];
+            ERR_error_string_n(code, buf.as_mut_ptr(), buf.len());
+            message = CStr::from_ptr(buf.as_ptr()).to_string_lossy().into_owned();
+        }
+    }
+    if message.is_empty() {
+        io::Error::other(context.to_owned())
+    } else {
+        io::Error::other(format!("{}: {}", context, message))
+    }
+}
+
+struct SslStream {
+    ctx: *mut SslCtx,
+    // Null until the session is created, which comes after steps that can fail
+    ssl: *mut Ssl,
+    // Only a session that finished its handshake has anything to shut down
+    connected: bool,
+    // Kept alive for as long as OpenSSL uses its file descriptor
+    socket: TcpStream,
+}
+
+// The raw pointers are only ever used behind the Mutex in TlsStream
+unsafe impl Send for SslStream {}
+
+impl Drop for SslStream {
+    fn drop(&mut self) {
+        unsafe {
+            if !self.ssl.is_null() {
+                if self.connected {
+                    SSL_shutdown(self.ssl);
+                }
+                SSL_free(self.ssl);
+            }
+            SSL_CTX_free(self.ctx);
+        }
+    }
+}
+
+// What a call into OpenSSL on the non-blocking socket came back with
+enum SslOutcome {
+    Done(io::Result<usize>),
+    // The call has to be repeated once the socket is readable or writable
+    WantRead,
+    WantWrite,
+}
+
+impl SslStream {
+    fn outcome(&self, ret: c_int) -> SslOutcome {
+        if ret > 0 {
+            return SslOutcome::Done(Ok(ret as usize));
+        }
+        let outcome = match unsafe { SSL_get_error(self.ssl, ret) } {
+            SSL_ERROR_ZERO_RETURN => SslOutcome::Done(Ok(0)),
+            SSL_ERROR_WANT_READ => SslOutcome::WantRead,
+            SSL_ERROR_WANT_WRITE => SslOutcome::WantWrite,
+            SSL_ERROR_SYSCALL => {
+                let e = io::Error::last_os_error();
+                // A syscall error with no errno means the peer hung up without a close_notify
+                match e.raw_os_error() {
+                    None | Some(0) => SslOutcome::Done(Ok(0)),
+                    Some(_) => SslOutcome::Done(Err(e)),
+                }
+            }
+            _ => SslOutcome::Done(Err(openssl_error("TLS error"))),
+        };
+        unsafe { ERR_clear_error() };
+        outcome
+    }
+}
+
+// Blocks until the socket is ready for the given poll events, or has been shut down
+fn wait_for(fd: RawFd, events: c_short) -> io::Result<()> {
+    let mut pollfd = PollFd {
+        fd,
+        events,
+        revents: 0,
+    };
+    if unsafe { poll(&mut pollfd, 1, POLL_NO_TIMEOUT) } < 0 {
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+// A TLS connection whose read and write halves can live on different threads. OpenSSL doesn't
+// allow reading and writing the same connection from two threads at once, so both halves share a
+// lock, and the socket is non-blocking so that neither half holds the lock while it waits.
+#[derive(Clone)]
+pub struct TlsStream {
+    inner: Arc<Mutex<SslStream>>,
+    fd: RawFd,
+}
+
+impl TlsStream {
+    // Performs the handshake over an already connected socket. The server certificate is always
+    // verified against the system roots (plus ca_file, if given), and must match the hostname, or
+    // the address if hostname is an IP address.
+    pub fn connect(socket: TcpStream, hostname: &str, ca_file: Option<&Path>) -> io::Result<Self> {
+        let ip = hostname.parse::<IpAddr>().ok();
+        let c_hostname = CString::new(hostname)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "bad TLS hostname"))?;
+        let c_ca_file = ca_file
+            .map(|path| {
+                CString::new(path.to_string_lossy().into_owned())
+                    .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "bad CA file path"))
+            })
+            .transpose()?;
+
+        unsafe {
+            let ctx = SSL_CTX_new(TLS_client_method());
+            if ctx.is_null() {
+                return Err(openssl_error("could not create TLS context"));
+            }
+            // From here on, the stream owns ctx and ssl and frees them on every early return
+            let mut stream = SslStream {
+                ctx,
+                ssl: ptr::null_mut(),
+                connected: false,
+                socket,
+            };
+
+            SSL_CTX_set_verify(ctx, SSL_VERIFY_PEER, None);
+            if SSL_CTX_set_default_verify_paths(ctx) != 1 {
+                return Err(openssl_error("could not load system CA certificates"));
+            }
+            if let Some(c_ca_file) = &c_ca_file {
+                if SSL_CTX_load_verify_locations(ctx, c_ca_file.as_ptr(), ptr::null()) != 1 {
+                    return Err(openssl_error("could not load CA file"));
+                }
+            }
+
+            stream.ssl = SSL_new(ctx);
+            if stream.ssl.is_null() {
+                return Err(openssl_error("could not create TLS session"));
+            }
+            if SSL_set_fd(stream.ssl, stream.socket.as_raw_fd()) != 1 {
+                return Err(openssl_error("could not set up TLS session"));
+            }
+            // SNI is only for hostnames, and an IP address has to be checked against the
+            // certificate's IP address entries rather than its DNS names
+            let peer_set = match ip {
+                Some(ip) => {
+                    let octets = match ip {
+                        IpAddr::V4(ip) => ip.octets().to_vec(),
+                        IpAddr::V6(ip) => ip.octets().to_vec(),
+                    };
+                    X509_VERIFY_PARAM_set1_ip(
+                        SSL_get0_param(stream.ssl),
+                        octets.as_ptr(),
+                        octets.len(),
+                    ) == 1
+                }
+                None => {
+                    SSL_ctrl(
+                        stream.ssl,
+                        SSL_CTRL_SET_TLSEXT_HOSTNAME,
+                        TLSEXT_NAMETYPE_HOST_NAME,
+                        c_hostname.as_ptr() as *mut c_void,
+                    ) == 1
+                        && SSL_set1_host(stream.ssl, c_hostname.as_ptr()) == 1
+                }
+            };
+            if !peer_set {
+                return Err(openssl_error("could not set up TLS session"));
+            }
+
+            if SSL_connect(stream.ssl) != 1 {
+                let verify_result = SSL_get_verify_result(stream.ssl);
+                if verify_result != X509_V_OK {
+                    ERR_clear_error();
+                    let reason = CStr::from_ptr(X509_verify_cert_error_string(verify_result));
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "certificate verification failed: {}",
+                            reason.to_string_lossy()
+                        ),
+                    ));
+                }
+                return Err(openssl_error("TLS handshake failed"));
+            }
+            stream.connected = true;
+
+            // Only now, so the handshake itself can block as long as it needs
+            stream.socket.set_nonblocking(true)?;
+
+            Ok(TlsStream {
+                fd: stream.socket.as_raw_fd(),
+                inner: Arc::new(Mutex::new(stream)),
+            })
+        }
+    }
+
+    // Runs an OpenSSL read or write until it goes through, holding the lock only while OpenSSL
+    // runs and waiting on the socket without it
+    fn retry(&self, mut call: impl FnMut(*mut Ssl) -> c_int) -> io::Result<usize> {
+        loop {
+            let outcome = {
+                let stream = self.inner.lock().unwrap();
+                let ret = call(stream.ssl);
+                stream.outcome(ret)
+            };
+            match outcome {
+                SslOutcome::Done(result) => return result,
+                SslOutcome::WantRead => wait_for(self.fd, POLLIN)?,
+                SslOutcome::WantWrite => wait_for(self.fd, POLLOUT)?,
+            }
+        }
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf.len().min(c_int::MAX as usize) as c_int;
+        let ptr = buf.as_mut_ptr() as *mut c_void;
+        self.retry(|ssl| unsafe { SSL_read(ssl, ptr, len) })
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // A write that has to wait is repeated with the same buffer, as OpenSSL requires
+        let len = buf.len().min(c_int::MAX as usize) as c_int;
+        let ptr = buf.as_ptr() as *const c_void;
+        self.retry(|ssl| unsafe { SSL_write(ssl, ptr, len) })
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::{Shutdown, SocketAddr, TcpListener};
+    use std::path::PathBuf;
+    use std::thread::{self, JoinHandle};
+    use std::time::Duration;
+
+    const SSL_FILETYPE_PEM: c_int = 1;
+
+    #[link(name = "ssl")]
+    extern "C" {
+        fn TLS_server_method() -> *const SslMethod;
+        fn SSL_CTX_use_certificate_chain_file(ctx: *mut SslCtx, file: *const c_char) -> c_int;
+        fn SSL_CTX_use_PrivateKey_file(
+            ctx: *mut SslCtx,
+            file: *const c_char,
+            file_type: c_int,
+        ) -> c_int;
+        fn SSL_accept(ssl: *mut Ssl) -> c_int;
+    }
+
+    // The certificates in tests/tls: ca.pem signed server.pem, which is for localhost and
+    // 127.0.0.1, and other_ca.pem signed nothing we use
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/tls")
+            .join(name)
+    }
+
+    fn c_path(name: &str) -> CString {
+        CString::new(fixture(name).to_str().unwrap()).unwrap()
+    }
+
+    // Accepts one TLS connection on a local port and echoes every line back until the client
+    // hangs up. The thread ends early if the handshake fails.
+    fn echo_server() -> (SocketAddr, JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let (socket, _) = listener.accept().unwrap();
+            unsafe {
+                let ctx = SSL_CTX_new(TLS_server_method());
+                assert!(!ctx.is_null());
+                let mut stream = SslStream {
+                    ctx,
+                    ssl: ptr::null_mut(),
+                    connected: false,
+                    socket,
+                };
+                assert_eq!(
+                    SSL_CTX_use_certificate_chain_file(ctx, c_path("server.pem").as_ptr()),
+                    1
+                );
+                assert_eq!(
+                    SSL_CTX_use_PrivateKey_file(
+                        ctx,
+                        c_path("server.key").as_ptr(),
+                        SSL_FILETYPE_PEM
+                    ),
+                    1
+                );
+                stream.ssl = SSL_new(ctx);
+                assert_eq!(SSL_set_fd(stream.ssl, stream.socket.as_raw_fd()), 1);
+                if SSL_accept(stream.ssl) != 1 {
+                    ERR_clear_error();
+                    return;
+                }
+                stream.connected = true;
+                let mut buf = [0u8; 512];
+                loop {
+                    let ret = SSL_read(stream.ssl, buf.as_mut_ptr() as *mut c_void, 512);
+                    if ret <= 0 {
+                        break;
+                    }
+                    SSL_write(stream.ssl, buf.as_ptr() as *const c_void, ret);
+                }
+            }
+        });
+        (addr, handle)
+    }
+
+    fn connect(hostname: &str, ca_file: &str) -> (io::Result<TlsStream>, JoinHandle<()>) {
+        let (addr, server) = echo_server();
+        let socket = TcpStream::connect(addr).unwrap();
+        let stream = TlsStream::connect(socket, hostname, Some(&fixture(ca_file)));
+        (stream, server)
+    }
+
+    fn assert_verification_fails(result: io::Result<TlsStream>) {
+        let e = result.err().expect("the handshake should fail");
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert!(
+            e.to_string().starts_with("certificate verification failed"),
+            "{}",
+            e
+        );
+    }
+
+    fn assert_echoes(mut stream: TlsStream) {
+        stream.write_all(b"PING :tuisen\r\n").unwrap();
+        let mut buf = [0u8; 14];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"PING :tuisen\r\n");
+    }
+
+    #[test]
+    fn test_connect_to_hostname() {
+        let (stream, server) = connect("localhost", "ca.pem");
+        assert_echoes(stream.unwrap());
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_connect_to_ip_address() {
+        let (stream, server) = connect("127.0.0.1", "ca.pem");
+        assert_echoes(stream.unwrap());
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_wrong_hostname_fails() {
+        let (stream, server) = connect("irc.chat.twitch.tv", "ca.pem");
+        assert_verification_fails(stream);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_wrong_ip_address_fails() {
+        let (stream, server) = connect("127.0.0.2", "ca.pem");
+        assert_verification_fails(stream);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_unknown_ca_fails() {
+        let (stream, server) = connect("localhost", "other_ca.pem");
+        assert_verification_fails(stream);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_missing_ca_file_fails() {
+        let (stream, server) = connect("localhost", "missing.pem");
+        let e = stream.err().expect("loading the CA file should fail");
+        assert!(e.to_string().starts_with("could not load CA file"), "{}", e);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_write_while_reader_waits() {
+        let (stream, server) = connect("localhost", "ca.pem");
+        let stream = stream.unwrap();
+
+        // The reader blocks before anything is sent, so the write has to get past it
+        let mut reader = stream.clone();
+        let reader = thread::spawn(move || {
+            let mut buf = [0u8; 14];
+            reader.read_exact(&mut buf).map(|_| buf)
+        });
+        thread::sleep(Duration::from_millis(100));
+        let mut writer = stream;
+        writer.write_all(b"PING :tuisen\r\n").unwrap();
+        assert_eq!(&reader.join().unwrap().unwrap(), b"PING :tuisen\r\n");
+
+        drop(writer);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_shutdown_unblocks_reader() {
+        let (addr, server) = echo_server();
+        let socket = TcpStream::connect(addr).unwrap();
+        let mut stream = TlsStream::connect(
+            socket.try_clone().unwrap(),
+            "localhost",
+            Some(&fixture("ca.pem")),
+        )
+        .unwrap();
+
+        // This is how the client stops the reader thread of a finished session
+        let reader = thread::spawn(move || stream.read(&mut [0u8; 16]));
+        thread::sleep(Duration::from_millis(100));
+        socket.shutdown(Shutdown::Both).unwrap();
+        // Either the end of the stream or an error, depending on how quickly the server hangs up
+        assert!(!matches!(reader.join().unwrap(), Ok(n) if n > 0));
+
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,239 @@
+// The TUI's own settings in tuisen.toml: the pane layout, the input history and the scrollback.
+// They live in the same file as the client's settings, which tuisen::config reads. Anything
+// that's missing or invalid falls back to its default.
+
+use std::{
+    fs::{read_to_string, write},
+    io,
+    path::PathBuf,
+};
+
+use toml::{Table, Value};
+use toml_edit::{DocumentMut, InlineTable, Item};
+
+use tuisen::config::{normalize_channel, CONFIG_PATH};
+
+use crate::layout::{PaneLayout, SplitDirection};
+
+#[derive(Debug)]
+pub enum ConfigWriteError {
+    BadPermissions,
+    InvalidSyntax,
+    OtherError,
+}
+
+#[derive(Default)]
+pub struct TuiConfig {
+    pub layout: Option<PaneLayoutConfig>,
+    // Where to keep the input history between sessions, and how many entries to keep per channel
+    pub history_file: Option<PathBuf>,
+    pub history_size: Option<usize>,
+    // How many messages each tab keeps in memory, and where the older ones go
+    pub scrollback_size: Option<usize>,
+    pub scrollback_dir: Option<PathBuf>,
+}
+
+// A saved pane layout. Each pane names the channel it shows, or None for the system buffer.
+pub type PaneLayoutConfig = PaneLayout<Option<String>>;
+
+impl TuiConfig {
+    pub fn from_table(table: &Table) -> Self {
+        let layout = table.get("layout").and_then(parse_layout);
+
+        let history_file = match table.get("history_file") {
+            Some(Value::String(ref history_file)) => Some(PathBuf::from(history_file)),
+            _ => None,
+        };
+        let history_size = match table.get("history_size") {
+            Some(Value::Integer(size)) if *size >= 0 => Some(*size as usize),
+            _ => None,
+        };
+
+        let scrollback_size = match table.get("scrollback_size") {
+            Some(Value::Integer(size)) if *size > 0 => Some(*size as usize),
+            _ => None,
+        };
+        let scrollback_dir = match table.get("scrollback_dir") {
+            Some(Value::String(ref scrollback_dir)) => Some(PathBuf::from(scrollback_dir)),
+            _ => None,
+        };
+
+        TuiConfig {
+            layout,
+            history_file,
+            history_size,
+            scrollback_size,
+            scrollback_dir,
+        }
+    }
+}
+
+// A layout is either a pane, named by its channel ("system" for the system buffer), or a split:
+// { split = "horizontal" | "vertical", ratio = <percent>, first = <layout>, second = <layout> }
+// Bad panes are left out, and a split with a bad side is replaced by its other side.
+fn parse_layout(value: &Value) -> Option<PaneLayoutConfig> {
+    match value {
+        Value::String(ref name) if name == "system" => Some(PaneLayout::Pane(None)),
+        Value::String(ref name) => normalize_channel(name).map(|name| PaneLayout::Pane(Some(name))),
+        Value::Table(ref split) => {
+            let direction = match split.get("split") {
+                Some(Value::String(ref direction)) if direction == "horizontal" => {
+                    SplitDirection::Horizontal
+                }
+                Some(Value::String(ref direction)) if direction == "vertical" => {
+                    SplitDirection::Vertical
+                }
+                _ => return None,
+            };
+            let ratio = match split.get("ratio") {
+                Some(Value::Integer(ratio)) => (*ratio).clamp(0, 100) as u16,
+                _ => 50,
+            };
+            match (
+                split.get("first").and_then(parse_layout),
+                split.get("second").and_then(parse_layout),
+            ) {
+                (Some(first), Some(second)) => {
+                    Some(PaneLayout::split(direction, ratio, first, second))
+                }
+                (Some(only), None) | (None, Some(only)) => Some(only),
+                (None, None) => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+fn layout_to_toml(layout: &PaneLayoutConfig) -> toml_edit::Value {
+    match layout {
+        PaneLayout::Pane(None) => "system".into(),
+        PaneLayout::Pane(Some(channel)) => channel.as_str().into(),
+        PaneLayout::Split {
+            direction,
+            ratio,
+            first,
+            second,
+        } => {
+            let mut split = InlineTable::new();
+            split.insert(
+                "split",
+                match direction {
+                    SplitDirection::Horizontal => "horizontal",
+                    SplitDirection::Vertical => "vertical",
+                }
+                .into(),
+            );
+            split.insert("ratio", (*ratio as i64).into());
+            split.insert("first", layout_to_toml(first));
+            split.insert("second", layout_to_toml(second));
+            split.into()
+        }
+    }
+}
+
+// Writes the layout into the config file, leaving the rest of the file (comments included) alone
+pub fn save_layout(layout: &PaneLayoutConfig) -> Result<(), ConfigWriteError> {
+    let contents = match read_to_string(CONFIG_PATH) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+            return Err(ConfigWriteError::BadPermissions)
+        }
+        Err(_) => return Err(ConfigWriteError::OtherError),
+    };
+    let mut document = contents
+        .parse::<DocumentMut>()
+        .map_err(|_| ConfigWriteError::InvalidSyntax)?;
+
+    document["layout"] = match layout_to_toml(layout) {
+        toml_edit::Value::InlineTable(split) => Item::Table(split.into_table()),
+        pane => Item::Value(pane),
+    };
+
+    write(CONFIG_PATH, document.to_string()).map_err(|e| match e.kind() {
+        io::ErrorKind::PermissionDenied => ConfigWriteError::BadPermissions,
+        _ => ConfigWriteError::OtherError,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(contents: &str) -> TuiConfig {
+        TuiConfig::from_table(&contents.parse::<Table>().unwrap())
+    }
+
+    #[test]
+    fn test_from_table() {
+        let config = parse(
+            "username = \"forsen\"\n\
+             history_file = \"history.txt\"\n\
+             history_size = 50\n\
+             scrollback_size = 0\n\
+             scrollback_dir = \"scrollback\"\n\
+             layout = \"#Forsen\"\n",
+        );
+        assert_eq!(config.history_file, Some(PathBuf::from("history.txt")));
+        assert_eq!(config.history_size, Some(50));
+        // An empty scrollback makes no sense, so it's left at the default
+        assert_eq!(config.scrollback_size, None);
+        assert_eq!(config.scrollback_dir, Some(PathBuf::from("scrollback")));
+        assert_eq!(
+            config.layout,
+            Some(PaneLayout::Pane(Some("forsen".to_owned())))
+        );
+
+        let empty = parse("");
+        assert!(empty.layout.is_none());
+        assert!(empty.history_file.is_none());
+        assert!(empty.history_size.is_none());
+    }
+
+    #[test]
+    fn test_parse_layout_drops_bad_panes() {
+        let config = parse(
+            "[layout]\n\
+             split = \"horizontal\"\n\
+             ratio = 150\n\
+             first = \"system\"\n\
+             second = { split = \"diagonal\", first = \"forsen\", second = \"xqc\" }\n",
+        );
+        assert_eq!(config.layout, Some(PaneLayout::Pane(None)));
+
+        let config = parse(
+            "[layout]\n\
+             split = \"vertical\"\n\
+             ratio = 150\n\
+             first = \"system\"\n\
+             second = \"forsen\"\n",
+        );
+        // Splits can't squeeze a side out of sight
+        assert_eq!(
+            config.layout,
+            Some(PaneLayout::split(
+                SplitDirection::Vertical,
+                90,
+                PaneLayout::Pane(None),
+                PaneLayout::Pane(Some("forsen".to_owned())),
+            ))
+        );
+    }
+
+    #[test]
+    fn test_layout_round_trip() {
+        let layout = PaneLayout::split(
+            SplitDirection::Horizontal,
+            30,
+            PaneLayout::Pane(None),
+            PaneLayout::split(
+                SplitDirection::Vertical,
+                60,
+                PaneLayout::Pane(Some("forsen".to_owned())),
+                PaneLayout::Pane(Some("xqc".to_owned())),
+            ),
+        );
+        let config = parse(&format!("layout = {}", layout_to_toml(&layout)));
+        assert_eq!(config.layout, Some(layout));
+    }
+}
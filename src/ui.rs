@@ -1,64 +1,101 @@
-use ratatui::prelude::{Color, Constraint, Direction, Layout, Line, Span, Style, Stylize};
-use ratatui::widgets::{Block, Borders, List, Paragraph};
+use ratatui::prelude::{Color, Constraint, Direction, Layout, Line, Rect, Span, Style, Stylize};
+use ratatui::widgets::{Block, Borders, Clear, List, Paragraph, Tabs};
+use std::time::Instant;
+
 use ratatui::Frame;
+use unicode_width::UnicodeWidthStr;
+
+use tuisen::actions::{ConnectionStatus, DisconnectReason, RoomModes};
 
-use crate::app::{App, InputMode, ScrollState, INSERT_LEN_WARN};
+use crate::app::{App, ChatBuffer, ConnectionInfo, InputMode, INSERT_LEN_WARN};
+use crate::completion::Completion;
+use crate::layout::{PaneLayout, SplitDirection};
+use crate::search::ScrollbackSearch;
 
 pub fn render_ui(frame: &mut Frame, app: &mut App) {
     let main_areas = Layout::default()
         .direction(Direction::Vertical)
         .constraints([
+            Constraint::Length(1),
             Constraint::Min(3),
             Constraint::Length(3), // TODO: make this grow as needed when we input a lot of text
+            Constraint::Length(1),
         ])
         .split(frame.size());
 
-    let chat_area = main_areas[0];
-    let chat_line_count = app.chat_lines.len();
-
-    let chat_inner_width = (chat_area.width - 2) as usize;
-    let chat_inner_height = (chat_area.height - 2) as usize;
-
-    // If the current chat size doesn't match the size in our AppState, update it
-    app.refresh_chat_size(chat_inner_width as u16, chat_inner_height as u16);
-
-    // If scroll is not active yet, check for overflow
-    if !app.scroll_active && chat_line_count > chat_inner_height {
-        app.scroll_active = true;
-    }
-
-    let chat_lines = match app.scroll_state {
-        ScrollState::Bottom => {
-            let lo = chat_line_count.saturating_sub(chat_inner_height);
-            app.chat_lines.get(lo..).unwrap().to_vec()
-        }
-        ScrollState::Offset(offset) => {
-            // At this point, offset should be strictly smaller than (chat_line_count -
-            // chat_inner_height). Otherwise, something went wrong and we panic
-            let lo = chat_line_count - chat_inner_height - offset;
-            app.chat_lines
-                .get(lo..lo + chat_inner_height)
-                .unwrap()
-                .to_vec()
+    // Tab bar, with unread counts for the tabs in the background
+    let tab_titles = app.buffers.iter().map(|buffer| {
+        let mut title = vec![Span::raw(buffer.title())];
+        if buffer.unread > 0 {
+            title.push(Span::raw(format!(" ({})", buffer.unread)).fg(Color::LightYellow));
         }
-        ScrollState::Top => app.chat_lines.get(..chat_inner_height).unwrap().to_vec(),
-    };
+        Line::from(title)
+    });
+    let tabs_widget = Tabs::new(tab_titles)
+        .select(app.active_buffer_index())
+        .highlight_style(Style::default().fg(Color::LightBlue).bold());
+    frame.render_widget(tabs_widget, main_areas[0]);
 
-    let chat_widget = List::new(chat_lines).block(Block::default().borders(Borders::ALL));
-    frame.render_widget(chat_widget, chat_area);
+    // Chat panes. Only highlight the focused one when there's more than one.
+    let layout = app.layout.clone();
+    let highlight_focus = layout.pane_count() > 1;
+    render_panes(frame, app, &layout, main_areas[1], &mut 0, highlight_focus);
 
-    let input_area = main_areas[1];
+    let input_area = main_areas[2];
     let input_border_color = match app.input_mode {
         InputMode::Normal => Color::default(),
         InputMode::Insert => Color::LightBlue,
+        InputMode::Search => Color::LightGreen,
     };
     let mut input_widget_block = Block::default()
         .borders(Borders::ALL)
         .title_top(Line::from(app.input_mode.title_string()).left_aligned());
+    // Show where our messages are going
+    if let Some(channel) = app.active_channel() {
+        input_widget_block =
+            input_widget_block.title_top(Line::from(format!("[ #{} ]", channel)).centered());
+    }
+    // Ctrl-R search, in red when nothing matches
+    if let Some(ref search) = app.history_search {
+        let query_color = if search.found.is_none() && !search.query.is_empty() {
+            Color::LightRed
+        } else {
+            Color::default()
+        };
+        input_widget_block = input_widget_block.title_bottom(
+            Line::from(vec![
+                Span::raw("[ search: "),
+                Span::raw(search.query.clone()).fg(query_color),
+                Span::raw(" ]"),
+            ])
+            .left_aligned(),
+        );
+    }
+    // Scrollback search options and results, kept around after the query is entered
+    if let Some(ref search) = app.scrollback_search {
+        let mut titles = Vec::new();
+        if let InputMode::Normal = app.input_mode {
+            titles.push(Span::raw(format!("[ /{} ]", search.query)));
+        }
+        if search.regex {
+            titles.push(Span::raw("[ regex ]"));
+        }
+        if search.case_sensitive {
+            titles.push(Span::raw("[ case ]"));
+        }
+        titles.push(match search.error {
+            Some(ref e) => Span::raw(format!("[ bad regex: {:?} ]", e)).fg(Color::LightRed),
+            None if search.is_empty() => Span::raw(""),
+            None if search.match_count == 0 => Span::raw("[ no matches ]").fg(Color::LightRed),
+            None => Span::raw(format!("[ {} matches ]", search.match_count)),
+        });
+        input_widget_block = input_widget_block.title_bottom(Line::from(titles).right_aligned());
+    }
     // Custom block styling per mode
     input_widget_block = match app.input_mode {
         InputMode::Insert => {
-            let trim_len = app.input_field.trim_end().len();
+            // Twitch counts the length in characters
+            let trim_len = app.input_field.text().trim_end().chars().count();
             let char_count_color = if trim_len > INSERT_LEN_WARN {
                 Color::LightRed
             } else {
@@ -77,12 +114,269 @@ pub fn render_ui(frame: &mut Frame, app: &mut App) {
     // Set the default border color on top of the previous titles
     input_widget_block = input_widget_block.border_style(Style::default().fg(input_border_color));
 
-    let input_widget = Paragraph::new(app.input_field.clone()).block(input_widget_block);
+    // Scroll sideways when the text gets too long, keeping the cursor in view
+    let (input_text, cursor_width) = match (&app.input_mode, &app.scrollback_search) {
+        (InputMode::Search, Some(search)) => (search.query.as_str(), search.query.width()),
+        _ => (app.input_field.text(), app.input_field.cursor_width()),
+    };
+    let input_inner_width = input_area.width.saturating_sub(2) as usize;
+    let scroll_x = (cursor_width + 1).saturating_sub(input_inner_width);
+    let input_widget = Paragraph::new(input_text)
+        .scroll((0, scroll_x as u16))
+        .block(input_widget_block);
     frame.render_widget(input_widget, input_area);
 
-    if let InputMode::Insert = app.input_mode {
-        let cursor_x = input_area.x + (app.input_field.len() as u16) + 1;
+    if let InputMode::Insert | InputMode::Search = app.input_mode {
+        let cursor_x = input_area.x + cursor_width.saturating_sub(scroll_x) as u16 + 1;
         let cursor_y = input_area.y + 1;
         frame.set_cursor(cursor_x, cursor_y);
     }
+
+    // Completion candidates, in a popup above the completed word
+    if let Some(ref completion) = app.completion {
+        let word_x = app
+            .input_field
+            .width_before(completion.word_start)
+            .saturating_sub(scroll_x) as u16;
+        render_completion_popup(frame, completion, input_area.x + 1 + word_x, input_area.y);
+    }
+
+    render_status_bar(frame, app, main_areas[3]);
+}
+
+// The connection, who we're logged in as, and the focused channel with its room modes
+fn render_status_bar(frame: &mut Frame, app: &App, area: Rect) {
+    let mut spans = vec![connection_span(&app.connection)];
+    if let Some(latency) = app.connection.latency {
+        spans.push(Span::raw(format!(" ({} ms)", latency.as_millis())));
+    }
+    if let Some(ref username) = app.connection.username {
+        spans.push(Span::raw(format!(" | {}", username)));
+    }
+    if let Some(channel) = app.active_channel() {
+        spans.push(Span::raw(format!(" | #{}", channel)));
+        let modes = active_room_modes(&app.active_buffer().room_modes);
+        if !modes.is_empty() {
+            spans.push(Span::raw(format!(" | {}", modes.join(", "))).fg(Color::LightYellow));
+        }
+    }
+    frame.render_widget(Paragraph::new(Line::from(spans)), area);
+}
+
+fn connection_span(connection: &ConnectionInfo) -> Span<'static> {
+    let since_disconnect = |text: String| match connection.disconnect_reason {
+        Some(ref reason) => format!("{} ({})", text, describe_disconnect_reason(reason)),
+        None => text,
+    };
+    match connection.status {
+        ConnectionStatus::Connecting => Span::raw("connecting").fg(Color::LightYellow),
+        ConnectionStatus::Authenticating { .. } => Span::raw("logging in").fg(Color::LightYellow),
+        ConnectionStatus::Connected { .. } => Span::raw("connected").fg(Color::LightGreen),
+        ConnectionStatus::Disconnected(ref reason) => Span::raw(format!(
+            "disconnected ({})",
+            describe_disconnect_reason(reason)
+        ))
+        .fg(Color::LightRed),
+        ConnectionStatus::Reconnecting { .. } => {
+            // Rounded up, so we never show 0 while still waiting
+            let remaining = connection
+                .reconnect_at
+                .and_then(|at| at.checked_duration_since(Instant::now()))
+                .map(|remaining| remaining.as_secs_f32().ceil() as u64)
+                .filter(|&seconds| seconds > 0);
+            let text = match remaining {
+                Some(seconds) => format!("reconnecting in {}s", seconds),
+                None => "reconnecting".to_owned(),
+            };
+            Span::raw(since_disconnect(text)).fg(Color::LightYellow)
+        }
+        _ => Span::raw("unknown").fg(Color::LightYellow),
+    }
+}
+
+fn describe_disconnect_reason(reason: &DisconnectReason) -> String {
+    match reason {
+        DisconnectReason::ConnectionClosed => "connection closed".to_owned(),
+        DisconnectReason::ServerRequested => "server restarting".to_owned(),
+        DisconnectReason::AuthFailed => "auth failed".to_owned(),
+        DisconnectReason::AuthTimedOut => "auth timed out".to_owned(),
+        DisconnectReason::TimedOut => "timed out".to_owned(),
+        DisconnectReason::Error(e) => e.clone(),
+        _ => "unknown".to_owned(),
+    }
+}
+
+// Only the modes that restrict something
+fn active_room_modes(modes: &RoomModes) -> Vec<String> {
+    let mut active = Vec::new();
+    if modes.emote_only == Some(true) {
+        active.push("emote-only".to_owned());
+    }
+    match modes.followers_only {
+        Some(0) => active.push("followers-only".to_owned()),
+        Some(minutes) if minutes > 0 => {
+            active.push(format!("followers-only ({} min)", minutes));
+        }
+        _ => {}
+    }
+    if modes.r9k == Some(true) {
+        active.push("unique-chat".to_owned());
+    }
+    if let Some(seconds) = modes.slow.filter(|&seconds| seconds > 0) {
+        active.push(format!("slow ({}s)", seconds));
+    }
+    if modes.subs_only == Some(true) {
+        active.push("subs-only".to_owned());
+    }
+    active
+}
+
+// Highlights the matched text in a line, or the whole line when the search only filters by user
+fn highlight_matches<'a>(line: &'a str, search: &ScrollbackSearch, current: bool) -> Line<'a> {
+    let style = if current {
+        Style::default().fg(Color::Black).bg(Color::LightYellow)
+    } else {
+        Style::default().fg(Color::Black).bg(Color::Gray)
+    };
+    let Some(ref pattern) = search.pattern else {
+        return Line::styled(line, style);
+    };
+    let mut spans = Vec::new();
+    let mut end_of_last = 0;
+    for (start, end) in pattern.find_all(line) {
+        spans.push(Span::raw(&line[end_of_last..start]));
+        spans.push(Span::styled(&line[start..end], style));
+        end_of_last = end;
+    }
+    spans.push(Span::raw(&line[end_of_last..]));
+    Line::from(spans)
+}
+
+// How many candidates the completion popup shows at once
+const COMPLETION_POPUP_LEN: usize = 8;
+
+fn render_completion_popup(frame: &mut Frame, completion: &Completion, x: u16, bottom: u16) {
+    let visible = completion.candidates.len().min(COMPLETION_POPUP_LEN);
+    // Scroll the list so the selected candidate stays in view
+    let first = (completion.selected + 1).saturating_sub(visible);
+    let lines: Vec<Line> = completion.candidates[first..first + visible]
+        .iter()
+        .enumerate()
+        .map(|(i, candidate)| {
+            if first + i == completion.selected {
+                Line::from(Span::raw(candidate.as_str()).fg(Color::LightBlue).bold())
+            } else {
+                Line::from(candidate.as_str())
+            }
+        })
+        .collect();
+
+    let count = format!(
+        "[ {}/{} ]",
+        completion.selected + 1,
+        completion.candidates.len()
+    );
+    let content_width = completion
+        .candidates
+        .iter()
+        .map(|candidate| candidate.width())
+        .max()
+        .unwrap_or(0)
+        .max(count.width());
+    let screen = frame.size();
+    let width = (content_width as u16 + 2).min(screen.width);
+    let height = (visible as u16 + 2).min(bottom);
+    // Keep the popup on screen when the word is near the right edge
+    let x = x.min(screen.width - width);
+    let area = Rect::new(x, bottom - height, width, height);
+
+    let block = Block::default()
+        .borders(Borders::ALL)
+        .title_bottom(Line::from(count).right_aligned());
+    frame.render_widget(Clear, area);
+    frame.render_widget(List::new(lines).block(block), area);
+}
+
+// Renders a layout into an area, splitting it up as needed. `pane` counts the panes rendered so
+// far, to find the focused one.
+fn render_panes(
+    frame: &mut Frame,
+    app: &mut App,
+    layout: &PaneLayout<usize>,
+    area: Rect,
+    pane: &mut usize,
+    highlight_focus: bool,
+) {
+    match layout {
+        PaneLayout::Pane(index) => {
+            let mut block = Block::default().borders(Borders::ALL);
+            if highlight_focus {
+                block = block.title_top(Line::from(app.buffers[*index].title()).left_aligned());
+                if *pane == app.focused_pane {
+                    block = block.border_style(Style::default().fg(Color::LightYellow));
+                }
+            }
+            let search = app
+                .scrollback_search
+                .as_ref()
+                .filter(|search| search.buffer == *index);
+            render_chat_buffer(frame, &mut app.buffers[*index], area, block, search);
+            *pane += 1;
+        }
+        PaneLayout::Split {
+            direction,
+            ratio,
+            first,
+            second,
+        } => {
+            let areas = Layout::default()
+                .direction(match direction {
+                    SplitDirection::Horizontal => Direction::Horizontal,
+                    SplitDirection::Vertical => Direction::Vertical,
+                })
+                .constraints([
+                    Constraint::Percentage(*ratio),
+                    Constraint::Percentage(100 - *ratio),
+                ])
+                .split(area);
+            render_panes(frame, app, first, areas[0], pane, highlight_focus);
+            render_panes(frame, app, second, areas[1], pane, highlight_focus);
+        }
+    }
+}
+
+fn render_chat_buffer(
+    frame: &mut Frame,
+    buffer: &mut ChatBuffer,
+    chat_area: Rect,
+    block: Block,
+    search: Option<&ScrollbackSearch>,
+) {
+    let chat_inner_width = chat_area.width.saturating_sub(2);
+    let chat_inner_height = chat_area.height.saturating_sub(2);
+
+    // If the current chat size doesn't match the size in our AppState, update it
+    buffer.refresh_chat_size(chat_inner_width, chat_inner_height);
+
+    // Only the items on screen get wrapped, and the lines are borrowed from them
+    let (first_index, skipped_lines) = buffer.view();
+    let first_item = buffer.first_item + first_index;
+    let chat_lines: Vec<Line> = buffer
+        .chat_items
+        .range(first_index..)
+        .zip(first_item..)
+        .flat_map(|(entry, number)| {
+            let item = &entry.item;
+            let search = search.filter(|search| search.matches(item.username(), item.text()));
+            entry.lines().iter().map(move |line| match search {
+                Some(search) => highlight_matches(line, search, search.current == Some(number)),
+                None => Line::from(line.as_str()),
+            })
+        })
+        .skip(skipped_lines)
+        .take(chat_inner_height.into())
+        .collect();
+
+    let chat_widget = List::new(chat_lines).block(block);
+    frame.render_widget(chat_widget, chat_area);
 }
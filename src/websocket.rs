@@ -0,0 +1,749 @@
+// A minimal WebSocket client (RFC 6455), for reaching Twitch chat over port 443 at
+// wss://irc-ws.chat.twitch.tv. It only does what IRC over WebSocket needs: text frames out, text or
+// binary frames in, and answering pings and closes.
+//
+// The connection is exposed as a plain byte stream, so the client can read lines off it just like
+// it would off a TcpStream. Each frame may carry one or more IRC lines.
+
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+
+use crate::random::random_bytes;
+
+const HANDSHAKE_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+// Generous, as IRC lines are tiny; this just keeps a bad length from allocating gigabytes
+const MAX_FRAME_LEN: u64 = 1 << 20;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+pub struct WebSocketUrl {
+    pub secure: bool,
+    pub host: String,
+    pub port: u16,
+    pub path: String,
+}
+
+impl WebSocketUrl {
+    // Parses ws://host[:port][/path] and wss://host[:port][/path]
+    pub fn parse(url: &str) -> io::Result<Self> {
+        let bad_url = || {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("bad WebSocket URL: {}", url),
+            )
+        };
+
+        let (secure, rest) = if let Some(rest) = url.strip_prefix("wss://") {
+            (true, rest)
+        } else if let Some(rest) = url.strip_prefix("ws://") {
+            (false, rest)
+        } else {
+            return Err(bad_url());
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            // The colons inside a bracketed IPv6 address are not a port separator
+            Some((host, port)) if !port.ends_with(']') => {
+                (host, port.parse::<u16>().map_err(|_| bad_url())?)
+            }
+            _ => (authority, if secure { 443 } else { 80 }),
+        };
+        if host.is_empty() {
+            return Err(bad_url());
+        }
+
+        Ok(WebSocketUrl {
+            secure,
+            host: host
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .to_owned(),
+            port,
+            path: path.to_owned(),
+        })
+    }
+}
+
+// Both halves write frames (the reader answers pings), so they share the underlying writer
+type SharedWriter = Arc<Mutex<Box<dyn Write + Send>>>;
+
+pub struct WebSocketReader {
+    inner: Box<dyn Read + Send>,
+    writer: SharedWriter,
+    // Payload of the current data frame that hasn't been read yet
+    pending: Vec<u8>,
+    pending_pos: usize,
+    closed: bool,
+}
+
+pub struct WebSocketWriter {
+    writer: SharedWriter,
+    // Everything written since the last flush goes out as a single text frame
+    buffer: Vec<u8>,
+}
+
+// Performs the opening handshake over an established (and possibly TLS) connection, and returns
+// the two halves of the WebSocket stream
+pub fn connect(
+    mut reader: Box<dyn Read + Send>,
+    mut writer: Box<dyn Write + Send>,
+    url: &WebSocketUrl,
+) -> io::Result<(WebSocketReader, WebSocketWriter)> {
+    let key = base64(&random_bytes::<16>());
+    let default_port = if url.secure { 443 } else { 80 };
+    let host = if url.port == default_port {
+        url.host.clone()
+    } else {
+        format!("{}:{}", url.host, url.port)
+    };
+
+    write!(
+        writer,
+        "GET {} HTTP/1.1\r\n\
+         Host: {}\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Key: {}\r\n\
+         Sec-WebSocket-Version: 13\r\n\
+         \r\n",
+        url.path, host, key
+    )?;
+    writer.flush()?;
+
+    // Read the response head a byte at a time, so we don't eat into the first frame
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        let mut byte = [0u8; 1];
+        if reader.read(&mut byte)? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection closed during WebSocket handshake",
+            ));
+        }
+        head.push(byte[0]);
+        if head.len() > 8192 {
+            return Err(handshake_error("response head too long"));
+        }
+    }
+    let head = String::from_utf8_lossy(&head);
+    let mut lines = head.split("\r\n");
+
+    let status = lines.next().unwrap_or_default();
+    if status.split(' ').nth(1) != Some("101") {
+        return Err(handshake_error(&format!("server answered \"{}\"", status)));
+    }
+    let expected_accept = accept_key(&key);
+    let accept = lines.find_map(|line| {
+        line.split_once(':')
+            .filter(|(name, _)| name.trim().eq_ignore_ascii_case("sec-websocket-accept"))
+            .map(|(_, value)| value.trim().to_owned())
+    });
+    if accept.as_deref() != Some(expected_accept.as_str()) {
+        return Err(handshake_error("bad Sec-WebSocket-Accept"));
+    }
+
+    let writer: SharedWriter = Arc::new(Mutex::new(writer));
+    Ok((
+        WebSocketReader {
+            inner: reader,
+            writer: writer.clone(),
+            pending: Vec::new(),
+            pending_pos: 0,
+            closed: false,
+        },
+        WebSocketWriter {
+            writer,
+            buffer: Vec::new(),
+        },
+    ))
+}
+
+// The Sec-WebSocket-Accept value a server has to answer the given key with
+fn accept_key(key: &str) -> String {
+    base64(&sha1(format!("{}{}", key, HANDSHAKE_GUID).as_bytes()))
+}
+
+fn handshake_error(reason: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("WebSocket handshake failed: {}", reason),
+    )
+}
+
+fn protocol_error(reason: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("WebSocket protocol error: {}", reason),
+    )
+}
+
+// Writes a single, final frame. Frames from the client must always be masked.
+fn write_frame(writer: &SharedWriter, opcode: u8, payload: &[u8]) -> io::Result<()> {
+    let mut frame = Vec::with_capacity(payload.len() + 14);
+    frame.push(0x80 | opcode);
+    match payload.len() {
+        len @ 0..=125 => frame.push(0x80 | len as u8),
+        len @ 126..=0xFFFF => {
+            frame.push(0x80 | 126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(0x80 | 127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    let mask = random_bytes::<4>();
+    frame.extend_from_slice(&mask);
+    frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+
+    let mut writer = writer.lock().unwrap();
+    writer.write_all(&frame)?;
+    writer.flush()
+}
+
+impl WebSocketReader {
+    // Reads frames until one carries data, answering control frames along the way. Returns false
+    // once the connection is closed.
+    fn next_data_frame(&mut self) -> io::Result<bool> {
+        loop {
+            let mut header = [0u8; 2];
+            if !read_exact_or_eof(&mut self.inner, &mut header)? {
+                return Ok(false);
+            }
+            let fin = header[0] & 0x80 != 0;
+            let opcode = header[0] & 0x0F;
+            if header[1] & 0x80 != 0 {
+                return Err(protocol_error("masked frame from server"));
+            }
+            let len = match header[1] & 0x7F {
+                126 => {
+                    let mut len = [0u8; 2];
+                    self.inner.read_exact(&mut len)?;
+                    u16::from_be_bytes(len) as u64
+                }
+                127 => {
+                    let mut len = [0u8; 8];
+                    self.inner.read_exact(&mut len)?;
+                    u64::from_be_bytes(len)
+                }
+                len => len as u64,
+            };
+            if len > MAX_FRAME_LEN {
+                return Err(protocol_error("frame too long"));
+            }
+            let mut payload = vec![0u8; len as usize];
+            self.inner.read_exact(&mut payload)?;
+
+            match opcode {
+                OPCODE_TEXT | OPCODE_BINARY | OPCODE_CONTINUATION => {
+                    // A message ends at its last frame, so make sure its last line is terminated
+                    // and doesn't run into the next message
+                    if fin && !payload.ends_with(b"\n") {
+                        payload.extend_from_slice(b"\r\n");
+                    }
+                    if payload.is_empty() {
+                        continue;
+                    }
+                    self.pending = payload;
+                    self.pending_pos = 0;
+                    return Ok(true);
+                }
+                OPCODE_PING => write_frame(&self.writer, OPCODE_PONG, &payload)?,
+                OPCODE_PONG => {}
+                OPCODE_CLOSE => {
+                    // Echo the status code back, as the closing handshake asks
+                    let _ =
+                        write_frame(&self.writer, OPCODE_CLOSE, &payload[..payload.len().min(2)]);
+                    return Ok(false);
+                }
+                _ => return Err(protocol_error("unknown opcode")),
+            }
+        }
+    }
+}
+
+// Like read_exact, but a clean EOF before the first byte is not an error
+fn read_exact_or_eof(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<bool> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..])? {
+            0 if filled == 0 => return Ok(false),
+            0 => return Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+            n => filled += n,
+        }
+    }
+    Ok(true)
+}
+
+impl Read for WebSocketReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pending_pos == self.pending.len() && (self.closed || !self.next_data_frame()?) {
+            self.closed = true;
+            return Ok(0);
+        }
+        let n = buf.len().min(self.pending.len() - self.pending_pos);
+        buf[..n].copy_from_slice(&self.pending[self.pending_pos..self.pending_pos + n]);
+        self.pending_pos += n;
+        Ok(n)
+    }
+}
+
+impl Write for WebSocketWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        write_frame(&self.writer, OPCODE_TEXT, &self.buffer)?;
+        self.buffer.clear();
+        Ok(())
+    }
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(n >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+// SHA-1 is only used to check the server's handshake answer, as the protocol requires
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+
+    for block in message.chunks(64) {
+        let mut w = [0u32; 80];
+        for (i, word) in block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, &word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A827999),
+                20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6),
+            };
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        for (h, v) in h.iter_mut().zip([a, b, c, d, e]) {
+            *h = h.wrapping_add(v);
+        }
+    }
+
+    let mut digest = [0u8; 20];
+    for (chunk, word) in digest.chunks_mut(4).zip(h) {
+        chunk.copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Cursor;
+
+    // A writer whose output the test can look at after handing it over
+    #[derive(Clone, Default)]
+    struct Capture(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Capture {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Capture {
+        fn take(&self) -> Vec<u8> {
+            std::mem::take(&mut self.0.lock().unwrap())
+        }
+    }
+
+    // A server frame, which is never masked
+    fn server_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![if fin { 0x80 } else { 0 } | opcode];
+        match payload.len() {
+            len @ 0..=125 => frame.push(len as u8),
+            len @ 126..=0xFFFF => {
+                frame.push(126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    // Splits a client frame into its opcode and unmasked payload, checking the rest of the header
+    fn decode_client_frame(frame: &[u8]) -> (u8, Vec<u8>) {
+        assert_eq!(frame[0] & 0x80, 0x80, "frame is not final");
+        assert_eq!(frame[1] & 0x80, 0x80, "frame is not masked");
+        let (len, header_len) = match frame[1] & 0x7F {
+            126 => (u16::from_be_bytes([frame[2], frame[3]]) as usize, 4),
+            127 => (
+                u64::from_be_bytes(frame[2..10].try_into().unwrap()) as usize,
+                10,
+            ),
+            len => (len as usize, 2),
+        };
+        let mask = &frame[header_len..header_len + 4];
+        let payload = &frame[header_len + 4..];
+        assert_eq!(payload.len(), len);
+        let payload = payload
+            .iter()
+            .enumerate()
+            .map(|(i, b)| b ^ mask[i % 4])
+            .collect();
+        (frame[0] & 0x0F, payload)
+    }
+
+    fn reader_for(frames: Vec<u8>) -> (WebSocketReader, Capture) {
+        let capture = Capture::default();
+        let reader = WebSocketReader {
+            inner: Box::new(Cursor::new(frames)),
+            writer: Arc::new(Mutex::new(Box::new(capture.clone()))),
+            pending: Vec::new(),
+            pending_pos: 0,
+            closed: false,
+        };
+        (reader, capture)
+    }
+
+    fn read_all(reader: &mut WebSocketReader) -> io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    #[test]
+    fn test_sha1() {
+        assert_eq!(hex(&sha1(b"")), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+        assert_eq!(
+            hex(&sha1(b"abc")),
+            "a9993e364706816aba3e25717850c26c9cd0d89d"
+        );
+        // Long enough that the padding needs a second block
+        assert_eq!(
+            hex(&sha1(
+                b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"
+            )),
+            "84983e441c3bd26ebaae4aa1f95129e5e54670f1"
+        );
+        assert_eq!(
+            hex(&sha1(&[b'a'; 1_000_000])),
+            "34aa973cd4c4daa4f61eeb2bdbad27316534016f"
+        );
+    }
+
+    #[test]
+    fn test_base64() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(b"foob"), "Zm9vYg==");
+        assert_eq!(base64(b"fooba"), "Zm9vYmE=");
+        assert_eq!(base64(b"foobar"), "Zm9vYmFy");
+        assert_eq!(base64(&[0xFB, 0xFF, 0xBF]), "+/+/");
+    }
+
+    #[test]
+    fn test_accept_key() {
+        // The example from RFC 6455, section 1.3
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn test_handshake() {
+        let request = Capture::default();
+        let url = WebSocketUrl::parse("ws://localhost:8080/chat").unwrap();
+
+        // The answer depends on the random key in the request, so it's made up on first read
+        struct Server {
+            request: Capture,
+            accept: Option<String>,
+            response: Cursor<Vec<u8>>,
+        }
+        impl Read for Server {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                if self.response.get_ref().is_empty() {
+                    let request = String::from_utf8(self.request.take()).unwrap();
+                    let key = request
+                        .lines()
+                        .find_map(|line| line.strip_prefix("Sec-WebSocket-Key: "))
+                        .unwrap();
+                    let accept = self.accept.clone().unwrap_or_else(|| accept_key(key));
+                    let mut response = format!(
+                        "HTTP/1.1 101 Switching Protocols\r\n\
+                         Upgrade: websocket\r\n\
+                         Connection: Upgrade\r\n\
+                         sec-websocket-accept: {}\r\n\
+                         \r\n",
+                        accept
+                    )
+                    .into_bytes();
+                    response.extend(server_frame(true, OPCODE_TEXT, b"PING :tmi.twitch.tv"));
+                    self.response = Cursor::new(response);
+                }
+                self.response.read(buf)
+            }
+        }
+
+        let server = Server {
+            request: request.clone(),
+            accept: None,
+            response: Cursor::default(),
+        };
+        let (mut reader, _) = connect(Box::new(server), Box::new(request.clone()), &url).unwrap();
+        // Nothing after the response head is lost to the handshake
+        assert_eq!(read_all(&mut reader).unwrap(), b"PING :tmi.twitch.tv\r\n");
+
+        let server = Server {
+            request: request.clone(),
+            accept: Some(accept_key("dGhlIHNhbXBsZSBub25jZQ==")),
+            response: Cursor::default(),
+        };
+        let e = connect(Box::new(server), Box::new(request.clone()), &url)
+            .err()
+            .unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_handshake_request() {
+        let request = Capture::default();
+        let url = WebSocketUrl::parse("wss://irc-ws.chat.twitch.tv").unwrap();
+        let response = b"HTTP/1.1 403 Forbidden\r\n\r\n".to_vec();
+        let e = connect(
+            Box::new(Cursor::new(response)),
+            Box::new(request.clone()),
+            &url,
+        )
+        .err()
+        .unwrap();
+        assert!(e.to_string().contains("403 Forbidden"), "{}", e);
+
+        // The default port is left out of the Host header
+        let request = String::from_utf8(request.take()).unwrap();
+        assert!(request.starts_with("GET / HTTP/1.1\r\n"));
+        assert!(request.contains("\r\nHost: irc-ws.chat.twitch.tv\r\n"));
+        assert!(request.contains("\r\nSec-WebSocket-Version: 13\r\n"));
+        assert!(request.ends_with("\r\n\r\n"));
+    }
+
+    #[test]
+    fn test_write_frame_lengths() {
+        let capture = Capture::default();
+        let writer: SharedWriter = Arc::new(Mutex::new(Box::new(capture.clone())));
+        // Around the edges of the 7-bit, 16-bit and 64-bit length encodings
+        for len in [0, 1, 125, 126, 0xFFFF, 0x10000] {
+            let payload: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            write_frame(&writer, OPCODE_TEXT, &payload).unwrap();
+            let frame = capture.take();
+            let header_len = match len {
+                0..=125 => 2,
+                126..=0xFFFF => 4,
+                _ => 10,
+            };
+            assert_eq!(frame.len(), header_len + 4 + len, "length {}", len);
+            assert_eq!(decode_client_frame(&frame), (OPCODE_TEXT, payload));
+        }
+    }
+
+    #[test]
+    fn test_writer_sends_one_frame_per_flush() {
+        let capture = Capture::default();
+        let mut writer = WebSocketWriter {
+            writer: Arc::new(Mutex::new(Box::new(capture.clone()))),
+            buffer: Vec::new(),
+        };
+        writer.write_all(b"PRIVMSG #forsen ").unwrap();
+        writer.write_all(b":forsenE\r\n").unwrap();
+        assert!(capture.take().is_empty());
+
+        writer.flush().unwrap();
+        assert_eq!(
+            decode_client_frame(&capture.take()),
+            (OPCODE_TEXT, b"PRIVMSG #forsen :forsenE\r\n".to_vec())
+        );
+        // Nothing to send, so no empty frame either
+        writer.flush().unwrap();
+        assert!(capture.take().is_empty());
+    }
+
+    #[test]
+    fn test_read_frames() {
+        let mut frames = server_frame(true, OPCODE_TEXT, b"PING :tmi.twitch.tv\r\n");
+        // Unterminated, so it gets a line ending of its own
+        frames.extend(server_frame(true, OPCODE_BINARY, b"PING :a"));
+        let long_line = vec![b'x'; 0x10000];
+        frames.extend(server_frame(true, OPCODE_TEXT, &long_line));
+        let (mut reader, _) = reader_for(frames);
+
+        let mut expected = b"PING :tmi.twitch.tv\r\nPING :a\r\n".to_vec();
+        expected.extend_from_slice(&long_line);
+        expected.extend_from_slice(b"\r\n");
+        assert_eq!(read_all(&mut reader).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_read_fragmented_message() {
+        let mut frames = server_frame(false, OPCODE_TEXT, b"PRIVMSG #forsen ");
+        // Control frames may come in between the fragments
+        frames.extend(server_frame(true, OPCODE_PING, b"hi"));
+        frames.extend(server_frame(true, OPCODE_CONTINUATION, b":forsenE"));
+        let (mut reader, capture) = reader_for(frames);
+
+        assert_eq!(
+            read_all(&mut reader).unwrap(),
+            b"PRIVMSG #forsen :forsenE\r\n"
+        );
+        assert_eq!(
+            decode_client_frame(&capture.take()),
+            (OPCODE_PONG, b"hi".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_read_close() {
+        let mut frames = server_frame(true, OPCODE_TEXT, b"PING :a\r\n");
+        frames.extend(server_frame(
+            true,
+            OPCODE_CLOSE,
+            &[0x03, 0xE8, b'b', b'y', b'e'],
+        ));
+        frames.extend(server_frame(true, OPCODE_TEXT, b"PING :b\r\n"));
+        let (mut reader, capture) = reader_for(frames);
+
+        // Nothing after the close is read, and the status code is echoed back
+        assert_eq!(read_all(&mut reader).unwrap(), b"PING :a\r\n");
+        assert_eq!(reader.read(&mut [0u8; 16]).unwrap(), 0);
+        assert_eq!(
+            decode_client_frame(&capture.take()),
+            (OPCODE_CLOSE, vec![0x03, 0xE8])
+        );
+    }
+
+    #[test]
+    fn test_read_bad_frames() {
+        let mut masked = server_frame(true, OPCODE_TEXT, b"");
+        masked[1] |= 0x80;
+        masked.extend_from_slice(&[0, 0, 0, 0]);
+        let too_long = vec![0x80 | OPCODE_TEXT, 127, 0, 0, 0, 0, 0, 0x20, 0, 0];
+        let unknown_opcode = server_frame(true, 0x3, b"");
+        let truncated = server_frame(true, OPCODE_TEXT, b"PING :a\r\n")[..5].to_vec();
+
+        for frames in [masked, too_long, unknown_opcode] {
+            let (mut reader, _) = reader_for(frames);
+            let e = read_all(&mut reader).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData, "{}", e);
+        }
+        let (mut reader, _) = reader_for(truncated);
+        let e = read_all(&mut reader).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_parse_url() {
+        let url = WebSocketUrl::parse("wss://irc-ws.chat.twitch.tv").unwrap();
+        assert!(url.secure);
+        assert_eq!(url.host, "irc-ws.chat.twitch.tv");
+        assert_eq!(url.port, 443);
+        assert_eq!(url.path, "/");
+
+        let url = WebSocketUrl::parse("ws://localhost:8080/chat?v=1").unwrap();
+        assert!(!url.secure);
+        assert_eq!(url.host, "localhost");
+        assert_eq!(url.port, 8080);
+        assert_eq!(url.path, "/chat?v=1");
+
+        let url = WebSocketUrl::parse("ws://irc-ws.chat.twitch.tv/").unwrap();
+        assert_eq!(url.port, 80);
+        assert_eq!(url.path, "/");
+
+        let url = WebSocketUrl::parse("wss://[::1]:8443/").unwrap();
+        assert_eq!(url.host, "::1");
+        assert_eq!(url.port, 8443);
+
+        let url = WebSocketUrl::parse("wss://[::1]").unwrap();
+        assert_eq!(url.host, "::1");
+        assert_eq!(url.port, 443);
+    }
+
+    #[test]
+    fn test_parse_bad_url() {
+        for url in [
+            "irc-ws.chat.twitch.tv",
+            "https://irc-ws.chat.twitch.tv",
+            "wss://",
+            "wss:///chat",
+            "wss://:443",
+            "wss://localhost:port",
+            "wss://localhost:65536",
+        ] {
+            let e = WebSocketUrl::parse(url).err().expect(url);
+            assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        }
+    }
+}